@@ -1,15 +1,24 @@
-use crate::cloud::CloudOptions;
-use crate::config::Preset;
-use crate::service::{Service, ServiceTrait};
-use miette::{IntoDiagnostic, Report, Result};
+use crate::nextcloud_log::LogFilter;
+use camino::Utf8PathBuf;
+use haze::cli::ChaosAction;
+use haze::cli::OutputFormat;
+use haze::config::Preset;
+use haze::{Cloud, CloudOptions};
+use haze::{Service, ServiceTrait};
+use miette::{IntoDiagnostic, Report, Result, WrapErr};
 use parse_display::Display;
+use regex::Regex;
 use std::fmt::Display;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum HazeArgs {
     /// List all instances
-    List { filter: Option<String> },
+    List {
+        filter: Option<String>,
+        format: OutputFormat,
+    },
     /// Start a new instance
     Start { options: CloudOptions },
     /// Stop an instance
@@ -23,6 +32,8 @@ pub enum HazeArgs {
     Exec {
         filter: Option<String>,
         service: Option<ExecService>,
+        /// User to run the command as, defaults to `haze` for the cloud container
+        user: Option<String>,
         command: Vec<String>,
     },
     /// Run an occ command in an instance
@@ -44,6 +55,10 @@ pub enum HazeArgs {
         follow: bool,
         service: Option<LogService>,
         count: Option<usize>,
+        /// Show the logs of all containers of the instance
+        all: bool,
+        /// Only show log lines matching this regex
+        grep: Option<String>,
     },
     /// Open an instance in the browser
     Open { filter: Option<String> },
@@ -65,13 +80,69 @@ pub enum HazeArgs {
     Unpin { filter: Option<String> },
     /// Start the proxy
     Proxy,
+    /// Serve the json api, optionally together with the proxy
+    Daemon { proxy: bool },
+    /// Answer dns queries for the instances
+    Dns,
     /// Checkout a branch in all apps
     Checkout { branch: String },
+    /// Show everything known about an instance
+    Info {
+        filter: Option<String>,
+        format: OutputFormat,
+    },
+    /// Check the state of all containers of an instance
+    Status { filter: Option<String> },
+    /// Wait until an instance is fully started
+    Wait {
+        filter: Option<String>,
+        timeout: Duration,
+    },
+    /// Create a copy of an instance
+    Clone {
+        filter: Option<String>,
+        name: Option<String>,
+    },
+    /// Copy files between the host and an instance
+    Cp { source: CopyPath, target: CopyPath },
+    /// Show the nextcloud log of an instance
+    Log {
+        filter: Option<String>,
+        follow: bool,
+        log_filter: LogFilter,
+        count: Option<usize>,
+    },
     Env {
         filter: Option<String>,
         command: String,
         args: Vec<String>,
     },
+    /// Show or export the requests captured by the proxy
+    Requests {
+        filter: Option<String>,
+        action: RequestsAction,
+    },
+    /// Inject network faults between an instance and one of its services
+    Chaos {
+        filter: Option<String>,
+        service: String,
+        /// Show the injected faults if no action is given
+        action: Option<ChaosAction>,
+    },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RequestsAction {
+    /// Show the captured requests as they come in
+    Follow,
+    /// Start capturing requests
+    Start,
+    /// Stop capturing requests
+    Stop,
+    /// Remove all captured requests
+    Clear,
+    /// Export the captured requests as HAR, to stdout if no path is given
+    Har(Option<Utf8PathBuf>),
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -101,6 +172,69 @@ impl LogService {
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ExecService {
     Db,
+    Service(Service),
+}
+
+impl ExecService {
+    pub fn from_type(presets: &[Preset], ty: &str) -> Option<Self> {
+        if ty == "db" {
+            return Some(ExecService::Db);
+        }
+        Some(ExecService::Service(
+            Service::from_type(presets, ty)?.into_iter().next()?,
+        ))
+    }
+
+    pub fn container_name(&self, cloud: &Cloud) -> Option<String> {
+        match self {
+            ExecService::Db => cloud.db().container_name(&cloud.id),
+            ExecService::Service(service) => service.container_name(&cloud.id),
+        }
+    }
+}
+
+/// Source or target of `haze cp`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CopyPath {
+    Host(String),
+    /// A path in an instance, written as `[match]:[service:]path` or `service:path`
+    Instance {
+        filter: Option<String>,
+        service: Option<ExecService>,
+        path: String,
+    },
+}
+
+impl CopyPath {
+    pub fn parse(presets: &[Preset], input: &str) -> Self {
+        let Some((prefix, rest)) = input.split_once(':') else {
+            return CopyPath::Host(input.to_string());
+        };
+        if prefix.contains('/') {
+            return CopyPath::Host(input.to_string());
+        }
+        if let Some((service, path)) = rest.split_once(':') {
+            if let Some(service) = ExecService::from_type(presets, service) {
+                return CopyPath::Instance {
+                    filter: Some(prefix.to_string()).filter(|prefix| !prefix.is_empty()),
+                    service: Some(service),
+                    path: path.to_string(),
+                };
+            }
+        }
+        if let Some(service) = ExecService::from_type(presets, prefix) {
+            return CopyPath::Instance {
+                filter: None,
+                service: Some(service),
+                path: rest.to_string(),
+            };
+        }
+        CopyPath::Instance {
+            filter: Some(prefix.to_string()).filter(|prefix| !prefix.is_empty()),
+            service: None,
+            path: rest.to_string(),
+        }
+    }
 }
 
 impl HazeArgs {
@@ -112,7 +246,12 @@ impl HazeArgs {
         let _bin = args.next();
         let command_or_filter = match args.next() {
             Some(s) => s,
-            None => return Ok(HazeArgs::List { filter: None }),
+            None => {
+                return Ok(HazeArgs::List {
+                    filter: None,
+                    format: OutputFormat::default(),
+                })
+            }
         };
         let (cmd, filter) = match HazeCommand::from_str(command_or_filter.as_ref()) {
             Ok(cmd) => (cmd, None),
@@ -122,6 +261,7 @@ impl HazeArgs {
                     None => {
                         return Ok(HazeArgs::List {
                             filter: Some(command_or_filter.into()),
+                            format: OutputFormat::default(),
                         });
                     }
                 };
@@ -136,9 +276,14 @@ impl HazeArgs {
         };
 
         match cmd {
-            HazeCommand::List => Ok(HazeArgs::List {
-                filter: filter.or_else(|| args.next().map(S::into)),
-            }),
+            HazeCommand::List => {
+                let (filter, format) = parse_filter_and_format(filter, args)?;
+                Ok(HazeArgs::List { filter, format })
+            }
+            HazeCommand::Info => {
+                let (filter, format) = parse_filter_and_format(filter, args)?;
+                Ok(HazeArgs::Info { filter, format })
+            }
             HazeCommand::Start => {
                 let mut args = args.peekable();
                 let options = CloudOptions::parse(presets, &mut args)?;
@@ -163,18 +308,30 @@ impl HazeArgs {
             HazeCommand::Exec => {
                 let mut args = args.peekable();
 
-                let service = match args.peek() {
-                    Some(arg) if arg.as_ref() == "db" => {
-                        args.next();
-                        Some(ExecService::Db)
+                let mut user = None;
+                while let Some(arg) = args.next_if(|arg| arg.as_ref().starts_with("--")) {
+                    match arg.as_ref() {
+                        "--root" => user = Some("root".to_string()),
+                        "--user" => {
+                            user = Some(
+                                args.next()
+                                    .ok_or_else(|| Report::msg("No user provided"))?
+                                    .into(),
+                            )
+                        }
+                        _ => return Err(Report::msg(format!("unrecognized option {}", arg))),
                     }
-                    _ => None,
-                };
+                }
+
+                let service = args
+                    .next_if(|arg| ExecService::from_type(presets, arg.as_ref()).is_some())
+                    .and_then(|arg| ExecService::from_type(presets, arg.as_ref()));
 
                 let command = args.map(S::into).collect();
                 Ok(HazeArgs::Exec {
                     filter,
                     service,
+                    user,
                     command,
                 })
             }
@@ -202,20 +359,51 @@ impl HazeArgs {
             }
             HazeCommand::Clean => Ok(HazeArgs::Clean),
             HazeCommand::Logs => {
-                let mut args = args.peekable();
-                let follow = args.next_if(|arg| arg.as_ref() == "-f").is_some();
-                let service = args
-                    .next_if(|arg| LogService::from_type(presets, arg.as_ref()).is_some())
-                    .and_then(|arg| LogService::from_type(presets, arg.as_ref()));
+                let mut filter = filter;
+                let mut follow = false;
+                let mut all = false;
+                let mut grep = None;
+                let mut service = None;
+                let mut count = None;
+                while let Some(arg) = args.next() {
+                    match arg.as_ref() {
+                        "-f" => follow = true,
+                        "--all" => all = true,
+                        "--grep" => {
+                            let pattern = args
+                                .next()
+                                .ok_or_else(|| Report::msg("No pattern provided"))?;
+                            Regex::new(pattern.as_ref())
+                                .into_diagnostic()
+                                .wrap_err("Invalid log filter")?;
+                            grep = Some(pattern.into());
+                        }
+                        arg if count.is_none() && arg.parse::<usize>().is_ok() => {
+                            count = arg.parse().ok();
+                        }
+                        arg if !all && service.is_none() => {
+                            match LogService::from_type(presets, arg) {
+                                Some(log_service) => service = Some(log_service),
+                                None if filter.is_none() => filter = Some(arg.into()),
+                                None => {
+                                    return Err(Report::msg(format!("unrecognized option {}", arg)))
+                                }
+                            }
+                        }
+                        arg if filter.is_none() => filter = Some(arg.into()),
+                        arg => return Err(Report::msg(format!("unrecognized option {}", arg))),
+                    }
+                }
+                if all && service.is_some() {
+                    return Err(Report::msg("--all can't be combined with a service"));
+                }
                 Ok(HazeArgs::Logs {
                     filter,
                     follow,
                     service,
-                    count: args
-                        .next()
-                        .map(|arg| arg.as_ref().parse())
-                        .transpose()
-                        .into_diagnostic()?,
+                    count,
+                    all,
+                    grep,
                 })
             }
             HazeCommand::Open => Ok(HazeArgs::Open { filter }),
@@ -235,6 +423,17 @@ impl HazeArgs {
             HazeCommand::Pin => Ok(HazeArgs::Pin { filter }),
             HazeCommand::Unpin => Ok(HazeArgs::Unpin { filter }),
             HazeCommand::Proxy => Ok(HazeArgs::Proxy),
+            HazeCommand::Dns => Ok(HazeArgs::Dns),
+            HazeCommand::Daemon => {
+                let mut proxy = false;
+                for arg in args {
+                    match arg.as_ref() {
+                        "--proxy" => proxy = true,
+                        arg => return Err(Report::msg(format!("unrecognized option {}", arg))),
+                    }
+                }
+                Ok(HazeArgs::Daemon { proxy })
+            }
             HazeCommand::Checkout => {
                 let branch = args
                     .next()
@@ -253,10 +452,241 @@ impl HazeArgs {
                     args: args.collect(),
                 })
             }
+            HazeCommand::Requests => {
+                let mut filter = filter;
+                let mut action = RequestsAction::Follow;
+                while let Some(arg) = args.next() {
+                    match arg.as_ref() {
+                        "start" => action = RequestsAction::Start,
+                        "stop" => action = RequestsAction::Stop,
+                        "clear" => action = RequestsAction::Clear,
+                        "har" => {
+                            action =
+                                RequestsAction::Har(args.next().map(S::into).map(Utf8PathBuf::from))
+                        }
+                        arg if filter.is_none() => filter = Some(arg.into()),
+                        arg => return Err(Report::msg(format!("unrecognized option {}", arg))),
+                    }
+                }
+                Ok(HazeArgs::Requests { filter, action })
+            }
+            HazeCommand::Chaos => {
+                let mut args: Vec<String> = args.map(S::into).collect();
+                let action = match args.iter().position(|arg| is_chaos_action(arg)) {
+                    Some(index) => {
+                        let action = parse_chaos_action(&args[index..])?;
+                        args.truncate(index);
+                        Some(action)
+                    }
+                    None => None,
+                };
+                let (filter, service) = match (filter, args.as_slice()) {
+                    (filter, [service]) => (filter, service.clone()),
+                    (None, [filter, service]) => (Some(filter.clone()), service.clone()),
+                    (_, []) => return Err(Report::msg("No service provided")),
+                    (_, [.., arg]) => {
+                        return Err(Report::msg(format!("unrecognized option {}", arg)))
+                    }
+                };
+                Ok(HazeArgs::Chaos {
+                    filter,
+                    service,
+                    action,
+                })
+            }
+            HazeCommand::Status => Ok(HazeArgs::Status {
+                filter: filter.or_else(|| args.next().map(S::into)),
+            }),
+            HazeCommand::Wait => {
+                let mut filter = filter;
+                let mut timeout = Duration::from_secs(120);
+                while let Some(arg) = args.next() {
+                    if arg.as_ref() == "--timeout" {
+                        timeout = parse_duration(
+                            args.next()
+                                .ok_or_else(|| Report::msg("No timeout provided"))?
+                                .as_ref(),
+                        )?;
+                    } else if filter.is_none() {
+                        filter = Some(arg.into());
+                    } else {
+                        return Err(Report::msg(format!("unrecognized option {}", arg)));
+                    }
+                }
+                Ok(HazeArgs::Wait { filter, timeout })
+            }
+            HazeCommand::Clone => {
+                let mut args = args.peekable();
+                let filter = filter.or_else(|| {
+                    args.next_if(|arg| !arg.as_ref().starts_with("--"))
+                        .map(S::into)
+                });
+                let name = match args.next() {
+                    Some(arg) if arg.as_ref() == "--name" => Some(
+                        args.next()
+                            .map(S::into)
+                            .ok_or_else(|| Report::msg("No name provided"))?,
+                    ),
+                    Some(arg) => return Err(Report::msg(format!("unrecognized option {}", arg))),
+                    None => None,
+                };
+                Ok(HazeArgs::Clone { filter, name })
+            }
+            HazeCommand::Cp => {
+                let (Some(source), Some(target), None) = (args.next(), args.next(), args.next())
+                else {
+                    return Err(Report::msg("Expected a source and target path"));
+                };
+                let source = CopyPath::parse(presets, source.as_ref());
+                let target = CopyPath::parse(presets, target.as_ref());
+                match (&source, &target) {
+                    (CopyPath::Host(_), CopyPath::Instance { .. })
+                    | (CopyPath::Instance { .. }, CopyPath::Host(_)) => {
+                        Ok(HazeArgs::Cp { source, target })
+                    }
+                    _ => Err(Report::msg(
+                        "Exactly one of the paths needs to be a path in an instance",
+                    )),
+                }
+            }
+            HazeCommand::Log => {
+                let mut filter = filter;
+                let mut follow = false;
+                let mut log_filter = LogFilter::default();
+                let mut count = None;
+                while let Some(arg) = args.next() {
+                    match arg.as_ref() {
+                        "-f" => follow = true,
+                        "--level" => {
+                            log_filter.level = Some(
+                                args.next()
+                                    .ok_or_else(|| Report::msg("No level provided"))?
+                                    .as_ref()
+                                    .parse()?,
+                            )
+                        }
+                        "--app" => {
+                            log_filter.app = Some(
+                                args.next()
+                                    .ok_or_else(|| Report::msg("No app provided"))?
+                                    .into(),
+                            )
+                        }
+                        "--req" => {
+                            log_filter.req = Some(
+                                args.next()
+                                    .ok_or_else(|| Report::msg("No request id provided"))?
+                                    .into(),
+                            )
+                        }
+                        arg if count.is_none() && arg.parse::<usize>().is_ok() => {
+                            count = arg.parse().ok();
+                        }
+                        arg if filter.is_none() => filter = Some(arg.into()),
+                        arg => return Err(Report::msg(format!("unrecognized option {}", arg))),
+                    }
+                }
+                Ok(HazeArgs::Log {
+                    filter,
+                    follow,
+                    log_filter,
+                    count,
+                })
+            }
         }
     }
 }
 
+/// Parse a duration like `90`, `90s`, `500ms`, `2m` or `1h`, plain numbers are seconds
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: u64 = number
+        .parse()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Invalid duration {}", input))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 60 * 60)),
+        _ => Err(Report::msg(format!("Invalid duration {}", input))),
+    }
+}
+
+fn is_chaos_action(arg: &str) -> bool {
+    matches!(
+        arg,
+        "latency" | "bandwidth" | "stall" | "reset" | "clear" | "off"
+    )
+}
+
+/// Parse a chaos action like `latency 500ms`, `bandwidth 64`, `stall 10%` or `reset`
+fn parse_chaos_action(args: &[String]) -> Result<ChaosAction> {
+    let value = || {
+        args.get(1)
+            .map(String::as_str)
+            .ok_or_else(|| Report::msg(format!("No value provided for {}", args[0])))
+    };
+    let action = match args[0].as_str() {
+        "latency" => ChaosAction::Latency(parse_duration(value()?)?),
+        "bandwidth" => ChaosAction::Bandwidth(
+            value()?
+                .trim_end_matches("kb")
+                .parse()
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Invalid bandwidth {}", args[1]))?,
+        ),
+        "stall" => ChaosAction::Stall(
+            value()?
+                .trim_end_matches('%')
+                .parse()
+                .ok()
+                .filter(|percentage| *percentage <= 100)
+                .ok_or_else(|| Report::msg(format!("Invalid stall percentage {}", args[1])))?,
+        ),
+        "reset" => ChaosAction::Reset,
+        "clear" => ChaosAction::Clear,
+        _ => ChaosAction::Off,
+    };
+    let expected = if matches!(args[0].as_str(), "latency" | "bandwidth" | "stall") {
+        2
+    } else {
+        1
+    };
+    if let Some(arg) = args.get(expected) {
+        return Err(Report::msg(format!("unrecognized option {}", arg)));
+    }
+    Ok(action)
+}
+
+fn parse_filter_and_format<I, S>(
+    mut filter: Option<String>,
+    mut args: I,
+) -> Result<(Option<String>, OutputFormat)>
+where
+    S: AsRef<str> + Into<String> + Display,
+    I: Iterator<Item = S>,
+{
+    let mut format = OutputFormat::default();
+    while let Some(arg) = args.next() {
+        if arg.as_ref() == "--format" {
+            format = args
+                .next()
+                .ok_or_else(|| Report::msg("No format provided"))?
+                .as_ref()
+                .parse()?;
+        } else if filter.is_none() {
+            filter = Some(arg.into());
+        } else {
+            return Err(Report::msg(format!("unrecognized option {}", arg)));
+        }
+    }
+    Ok((filter, format))
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Display)]
 pub enum HazeCommand {
     List,
@@ -275,8 +705,18 @@ pub enum HazeCommand {
     Pin,
     Unpin,
     Proxy,
+    Daemon,
+    Dns,
     Checkout,
     Env,
+    Info,
+    Status,
+    Wait,
+    Clone,
+    Cp,
+    Log,
+    Requests,
+    Chaos,
 }
 
 impl FromStr for HazeCommand {
@@ -301,8 +741,18 @@ impl FromStr for HazeCommand {
             "pin" => Ok(HazeCommand::Pin),
             "unpin" => Ok(HazeCommand::Unpin),
             "proxy" => Ok(HazeCommand::Proxy),
+            "daemon" => Ok(HazeCommand::Daemon),
+            "dns" => Ok(HazeCommand::Dns),
             "checkout" => Ok(HazeCommand::Checkout),
             "env" => Ok(HazeCommand::Env),
+            "info" => Ok(HazeCommand::Info),
+            "status" => Ok(HazeCommand::Status),
+            "wait" => Ok(HazeCommand::Wait),
+            "clone" => Ok(HazeCommand::Clone),
+            "cp" => Ok(HazeCommand::Cp),
+            "log" => Ok(HazeCommand::Log),
+            "requests" => Ok(HazeCommand::Requests),
+            "chaos" => Ok(HazeCommand::Chaos),
             _ => Err(Report::msg(format!("Unknown command: {}", s))),
         }
     }
@@ -327,8 +777,18 @@ impl HazeCommand {
             HazeCommand::Pin => true,
             HazeCommand::Unpin => true,
             HazeCommand::Proxy => false,
+            HazeCommand::Daemon => false,
+            HazeCommand::Dns => false,
             HazeCommand::Checkout => false,
             HazeCommand::Env => true,
+            HazeCommand::Info => true,
+            HazeCommand::Status => true,
+            HazeCommand::Wait => true,
+            HazeCommand::Clone => true,
+            HazeCommand::Cp => false,
+            HazeCommand::Log => true,
+            HazeCommand::Requests => true,
+            HazeCommand::Chaos => true,
         }
     }
 }
@@ -337,7 +797,10 @@ impl HazeCommand {
 fn test_arg_parse() {
     assert_eq!(
         HazeArgs::parse(&[], vec!["haze"].into_iter()).unwrap(),
-        HazeArgs::List { filter: None }
+        HazeArgs::List {
+            filter: None,
+            format: OutputFormat::Text
+        }
     );
     assert_eq!(
         HazeArgs::parse(&[], vec!["haze", "test"].into_iter()).unwrap(),
@@ -349,7 +812,26 @@ fn test_arg_parse() {
     assert_eq!(
         HazeArgs::parse(&[], vec!["haze", "asdasd"].into_iter()).unwrap(),
         HazeArgs::List {
-            filter: Some("asdasd".to_string())
+            filter: Some("asdasd".to_string()),
+            format: OutputFormat::Text
+        }
+    );
+    assert_eq!(
+        HazeArgs::parse(
+            &[],
+            vec!["haze", "list", "asdasd", "--format", "json"].into_iter()
+        )
+        .unwrap(),
+        HazeArgs::List {
+            filter: Some("asdasd".to_string()),
+            format: OutputFormat::Json
+        }
+    );
+    assert_eq!(
+        HazeArgs::parse(&[], vec!["haze", "asdasd", "info"].into_iter()).unwrap(),
+        HazeArgs::Info {
+            filter: Some("asdasd".to_string()),
+            format: OutputFormat::Text
         }
     );
     assert_eq!(
@@ -393,6 +875,7 @@ fn test_arg_parse() {
         HazeArgs::Exec {
             filter: None,
             service: None,
+            user: None,
             command: vec!["foo".to_string(), "bar".to_string()],
         }
     );
@@ -405,6 +888,7 @@ fn test_arg_parse() {
         HazeArgs::Exec {
             filter: Some("asdasd".to_string()),
             service: None,
+            user: None,
             command: vec!["foo".to_string(), "bar".to_string()],
         }
     );
@@ -417,9 +901,32 @@ fn test_arg_parse() {
         HazeArgs::Exec {
             filter: Some("asdasd".to_string()),
             service: Some(ExecService::Db),
+            user: None,
             command: vec!["foo".to_string(), "bar".to_string()],
         }
     );
+    assert_eq!(
+        HazeArgs::parse(&[], vec!["haze", "exec", "--root", "ls"].into_iter()).unwrap(),
+        HazeArgs::Exec {
+            filter: None,
+            service: None,
+            user: Some("root".to_string()),
+            command: vec!["ls".to_string()],
+        }
+    );
+    assert_eq!(
+        HazeArgs::parse(
+            &[],
+            vec!["haze", "exec", "--user", "www-data", "smb", "ls"].into_iter()
+        )
+        .unwrap(),
+        HazeArgs::Exec {
+            filter: None,
+            service: ExecService::from_type(&[], "smb"),
+            user: Some("www-data".to_string()),
+            command: vec!["ls".to_string()],
+        }
+    );
     assert_eq!(
         HazeArgs::parse(&[], vec!["haze", "test", "foo", "bar"].into_iter()).unwrap(),
         HazeArgs::Test {
@@ -434,6 +941,8 @@ fn test_arg_parse() {
             follow: true,
             service: Some(LogService::from_type(&[], "smb").unwrap()),
             count: None,
+            all: false,
+            grep: None,
         }
     );
     assert_eq!(
@@ -447,6 +956,174 @@ fn test_arg_parse() {
             follow: false,
             service: Some(LogService::from_type(&[], "smb").unwrap()),
             count: Some(123),
+            all: false,
+            grep: None,
+        }
+    );
+    assert_eq!(
+        HazeArgs::parse(
+            &[],
+            vec![
+                "haze",
+                "logs",
+                "--all",
+                "asdasd",
+                "-f",
+                "--grep",
+                "push|redis"
+            ]
+            .into_iter()
+        )
+        .unwrap(),
+        HazeArgs::Logs {
+            filter: Some("asdasd".to_string()),
+            follow: true,
+            service: None,
+            count: None,
+            all: true,
+            grep: Some("push|redis".to_string()),
+        }
+    );
+    assert!(HazeArgs::parse(&[], vec!["haze", "logs", "--grep", "("].into_iter()).is_err());
+    assert_eq!(
+        HazeArgs::parse(
+            &[],
+            vec!["haze", "asdasd", "wait", "--timeout", "2m"].into_iter()
+        )
+        .unwrap(),
+        HazeArgs::Wait {
+            filter: Some("asdasd".to_string()),
+            timeout: Duration::from_secs(120),
+        }
+    );
+    assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(parse_duration("15").unwrap(), Duration::from_secs(15));
+    assert!(parse_duration("15 minutes").is_err());
+    assert_eq!(
+        HazeArgs::parse(
+            &[],
+            vec!["haze", "clone", "asdasd", "--name", "foo"].into_iter()
+        )
+        .unwrap(),
+        HazeArgs::Clone {
+            filter: Some("asdasd".to_string()),
+            name: Some("foo".to_string()),
+        }
+    );
+    assert_eq!(
+        HazeArgs::parse(&[], vec!["haze", "asdasd", "clone"].into_iter()).unwrap(),
+        HazeArgs::Clone {
+            filter: Some("asdasd".to_string()),
+            name: None,
+        }
+    );
+    assert_eq!(
+        HazeArgs::parse(
+            &[],
+            vec!["haze", "log", "asdasd", "--level", "warn", "--app", "dav", "-f"].into_iter()
+        )
+        .unwrap(),
+        HazeArgs::Log {
+            filter: Some("asdasd".to_string()),
+            follow: true,
+            log_filter: LogFilter {
+                level: Some(crate::nextcloud_log::LogLevel::Warn),
+                app: Some("dav".to_string()),
+                req: None,
+            },
+            count: None,
+        }
+    );
+    assert!(HazeArgs::parse(&[], vec!["haze", "log", "--level", "loud"].into_iter()).is_err());
+    assert_eq!(
+        HazeArgs::parse(
+            &[],
+            vec!["haze", "cp", "./foo.txt", "asdasd:data/foo.txt"].into_iter()
+        )
+        .unwrap(),
+        HazeArgs::Cp {
+            source: CopyPath::Host("./foo.txt".to_string()),
+            target: CopyPath::Instance {
+                filter: Some("asdasd".to_string()),
+                service: None,
+                path: "data/foo.txt".to_string(),
+            },
+        }
+    );
+    assert_eq!(
+        CopyPath::parse(&[], "smb:/share/test"),
+        CopyPath::Instance {
+            filter: None,
+            service: ExecService::from_type(&[], "smb"),
+            path: "/share/test".to_string(),
+        }
+    );
+    assert_eq!(
+        CopyPath::parse(&[], "asdasd:db:/tmp/dump.sql"),
+        CopyPath::Instance {
+            filter: Some("asdasd".to_string()),
+            service: Some(ExecService::Db),
+            path: "/tmp/dump.sql".to_string(),
+        }
+    );
+    assert_eq!(
+        CopyPath::parse(&[], ":/tmp/foo"),
+        CopyPath::Instance {
+            filter: None,
+            service: None,
+            path: "/tmp/foo".to_string(),
+        }
+    );
+    assert!(HazeArgs::parse(&[], vec!["haze", "cp", "foo", "bar"].into_iter()).is_err());
+    assert_eq!(
+        HazeArgs::parse(&[], vec!["haze", "daemon", "--proxy"].into_iter()).unwrap(),
+        HazeArgs::Daemon { proxy: true }
+    );
+    assert_eq!(
+        HazeArgs::parse(
+            &[],
+            vec!["haze", "foo", "requests", "har", "out.har"].into_iter()
+        )
+        .unwrap(),
+        HazeArgs::Requests {
+            filter: Some("foo".into()),
+            action: RequestsAction::Har(Some("out.har".into())),
+        }
+    );
+    assert_eq!(
+        HazeArgs::parse(&[], vec!["haze", "requests", "clear"].into_iter()).unwrap(),
+        HazeArgs::Requests {
+            filter: None,
+            action: RequestsAction::Clear,
+        }
+    );
+    assert_eq!(
+        HazeArgs::parse(
+            &[],
+            vec!["haze", "chaos", "foo", "s3", "latency", "500ms"].into_iter()
+        )
+        .unwrap(),
+        HazeArgs::Chaos {
+            filter: Some("foo".into()),
+            service: "s3".into(),
+            action: Some(ChaosAction::Latency(Duration::from_millis(500))),
+        }
+    );
+    assert_eq!(
+        HazeArgs::parse(&[], vec!["haze", "chaos", "db", "reset"].into_iter()).unwrap(),
+        HazeArgs::Chaos {
+            filter: None,
+            service: "db".into(),
+            action: Some(ChaosAction::Reset),
+        }
+    );
+    assert_eq!(
+        HazeArgs::parse(&[], vec!["haze", "chaos", "db", "stall", "10%"].into_iter()).unwrap(),
+        HazeArgs::Chaos {
+            filter: None,
+            service: "db".into(),
+            action: Some(ChaosAction::Stall(10)),
         }
     );
+    assert!(HazeArgs::parse(&[], vec!["haze", "chaos", "db", "stall", "150"].into_iter()).is_err());
 }
@@ -0,0 +1,607 @@
+use crate::Cloud;
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{HeaderMap, Version};
+use axum::response::Response;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local, Utc};
+use futures_util::StreamExt;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{remove_file, write, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem::take;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs::{metadata, rename, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::spawn;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::error;
+
+/// File in the instance work directory that enables capturing requests when it exists
+pub const CAPTURE_FLAG: &str = "capture-requests";
+
+/// File in the instance work directory the captured requests are written to, one json object per line
+pub const CAPTURE_FILE: &str = "requests.jsonl";
+
+/// File the previous captures are moved to once the capture file reaches [`MAX_CAPTURE_FILE_SIZE`]
+pub const ROTATED_CAPTURE_FILE: &str = "requests.1.jsonl";
+
+/// Size after which the capture file is rotated, this keeps at most twice this size of captures per instance
+const MAX_CAPTURE_FILE_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Headers containing credentials, their values are not stored
+const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+/// Request and response bodies are only captured up to this size
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// A request proxied to an instance together with its response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedExchange {
+    pub started: DateTime<Utc>,
+    pub request: CapturedRequest,
+    /// `None` if no response was received from the instance
+    pub response: Option<CapturedResponse>,
+    /// Time until the response headers were received, in milliseconds
+    pub wait: f64,
+    /// Time spent sending the response body to the client, in milliseconds
+    pub receive: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    pub method: String,
+    pub url: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: CapturedBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: CapturedBody,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CapturedBody {
+    /// Size of the full body in bytes
+    pub size: usize,
+    /// The start of the body, `None` for binary bodies
+    pub text: Option<String>,
+    /// Whether the body was larger than the captured text
+    pub truncated: bool,
+}
+
+#[derive(Default)]
+struct BodyBuffer {
+    data: Vec<u8>,
+    size: usize,
+}
+
+impl BodyBuffer {
+    fn push(&mut self, chunk: &[u8]) {
+        self.size += chunk.len();
+        let remaining = MAX_BODY_SIZE.saturating_sub(self.data.len());
+        self.data
+            .extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+    }
+
+    fn captured(&self) -> CapturedBody {
+        let text = match std::str::from_utf8(&self.data) {
+            Ok(text) => Some(text),
+            // a truncated body can end halfway through a character
+            Err(e) if self.size > self.data.len() && e.error_len().is_none() => {
+                Some(std::str::from_utf8(&self.data[..e.valid_up_to()]).unwrap())
+            }
+            Err(_) => None,
+        };
+        CapturedBody {
+            size: self.size,
+            text: text.map(String::from),
+            truncated: self.size > self.data.len(),
+        }
+    }
+}
+
+/// Copy the body into a buffer while it is being read
+fn tee_body(body: Body, mut on_chunk: impl FnMut(&[u8]) + Send + 'static) -> Body {
+    Body::from_stream(body.into_data_stream().map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            on_chunk(chunk);
+        }
+        chunk
+    }))
+}
+
+fn header_list(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                "[redacted]".into()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn version_name(version: Version) -> String {
+    format!("{:?}", version)
+}
+
+/// Writes the captured exchanges of all instances from a single task
+#[derive(Clone)]
+pub struct CaptureWriter {
+    sender: UnboundedSender<(Utf8PathBuf, CapturedExchange)>,
+}
+
+impl CaptureWriter {
+    /// Spawn the task writing the captures, it stops once all writers and captures are dropped
+    pub fn spawn() -> (CaptureWriter, JoinHandle<()>) {
+        let (sender, receiver) = unbounded_channel();
+        let task = spawn(write_captures(receiver, MAX_CAPTURE_FILE_SIZE));
+        (CaptureWriter { sender }, task)
+    }
+}
+
+async fn write_captures(
+    mut receiver: UnboundedReceiver<(Utf8PathBuf, CapturedExchange)>,
+    max_size: u64,
+) {
+    while let Some((workdir, exchange)) = receiver.recv().await {
+        let result = serde_json::to_vec(&exchange).into_diagnostic();
+        let result = match result {
+            Ok(line) => append_capture(&workdir, line, max_size).await,
+            Err(e) => Err(e),
+        };
+        if let Err(error) = result {
+            error!(?error, %workdir, "failed to save captured request");
+        }
+    }
+}
+
+async fn append_capture(workdir: &Utf8Path, mut line: Vec<u8>, max_size: u64) -> Result<()> {
+    line.push(b'\n');
+    let path = workdir.join(CAPTURE_FILE);
+    if let Ok(meta) = metadata(&path).await {
+        if meta.len() > 0 && meta.len() + line.len() as u64 > max_size {
+            rename(&path, workdir.join(ROTATED_CAPTURE_FILE))
+                .await
+                .into_diagnostic()?;
+        }
+    }
+    // the captures can contain credentials in urls and bodies
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(&path)
+        .await
+        .into_diagnostic()?;
+    file.write_all(&line).await.into_diagnostic()?;
+    // tokio only finishes the write in the background unless flushed
+    file.flush().await.into_diagnostic()
+}
+
+/// Capture of a single request that is being proxied
+pub struct Capture {
+    writer: CaptureWriter,
+    workdir: Utf8PathBuf,
+    started: DateTime<Utc>,
+    start: Instant,
+    request: CapturedRequest,
+    request_body: Arc<Mutex<BodyBuffer>>,
+}
+
+impl Capture {
+    /// Start capturing a request to the instance with the provided work directory
+    ///
+    /// The body of the request is captured while it is being forwarded.
+    pub fn start(
+        writer: &CaptureWriter,
+        workdir: &Utf8Path,
+        url: String,
+        req: &mut Request,
+    ) -> Capture {
+        let request_body = Arc::<Mutex<BodyBuffer>>::default();
+        let buffer = request_body.clone();
+        let body = take(req.body_mut());
+        *req.body_mut() = tee_body(body, move |chunk| buffer.lock().unwrap().push(chunk));
+
+        Capture {
+            writer: writer.clone(),
+            workdir: workdir.into(),
+            started: Utc::now(),
+            start: Instant::now(),
+            request: CapturedRequest {
+                method: req.method().to_string(),
+                url,
+                version: version_name(req.version()),
+                headers: header_list(req.headers()),
+                body: CapturedBody::default(),
+            },
+            request_body,
+        }
+    }
+
+    /// Capture the response, the exchange is saved once the response body is sent
+    pub fn finish(self, response: Response) -> Response {
+        let wait = self.start.elapsed();
+        let (parts, body) = response.into_parts();
+        let mut pending = PendingExchange {
+            capture: Some(self),
+            response: CapturedResponse {
+                status: parts.status.as_u16(),
+                version: version_name(parts.version),
+                headers: header_list(&parts.headers),
+                body: CapturedBody::default(),
+            },
+            wait,
+            body: BodyBuffer::default(),
+        };
+        let body = tee_body(body, move |chunk| pending.body.push(chunk));
+        Response::from_parts(parts, body)
+    }
+
+    /// Save the exchange for a request that didn't get a response
+    pub fn failed(self) {
+        let wait = self.start.elapsed();
+        self.save(None, wait, Duration::ZERO);
+    }
+
+    fn save(mut self, response: Option<CapturedResponse>, wait: Duration, receive: Duration) {
+        self.request.body = self.request_body.lock().unwrap().captured();
+        let exchange = CapturedExchange {
+            started: self.started,
+            request: self.request,
+            response,
+            wait: wait.as_secs_f64() * 1000.0,
+            receive: receive.as_secs_f64() * 1000.0,
+        };
+        // the writer only stops when the proxy shuts down
+        let _ = self.writer.sender.send((self.workdir, exchange));
+    }
+}
+
+/// A captured exchange waiting for the response body to be sent
+struct PendingExchange {
+    capture: Option<Capture>,
+    response: CapturedResponse,
+    wait: Duration,
+    body: BodyBuffer,
+}
+
+impl Drop for PendingExchange {
+    // the body stream is dropped once it is fully sent or the client disconnects
+    fn drop(&mut self) {
+        if let Some(capture) = self.capture.take() {
+            let receive = capture.start.elapsed().saturating_sub(self.wait);
+            let mut response = self.response.clone();
+            response.body = self.body.captured();
+            capture.save(Some(response), self.wait, receive);
+        }
+    }
+}
+
+impl Cloud {
+    pub fn capture_enabled(&self) -> bool {
+        self.workdir.join(CAPTURE_FLAG).exists()
+    }
+
+    /// Enable or disable capturing requests to the instance by the proxy
+    pub fn set_capture(&self, enabled: bool) -> Result<()> {
+        let flag = self.workdir.join(CAPTURE_FLAG);
+        if enabled {
+            write(&flag, "").into_diagnostic()
+        } else if flag.exists() {
+            remove_file(&flag).into_diagnostic()
+        } else {
+            Ok(())
+        }
+        .wrap_err("Failed to change request capture")
+    }
+
+    /// Load all captured requests for the instance
+    pub fn captured_requests(&self) -> Result<Vec<CapturedExchange>> {
+        let mut exchanges = Vec::new();
+        for file in [ROTATED_CAPTURE_FILE, CAPTURE_FILE] {
+            let path = self.workdir.join(file);
+            if !path.exists() {
+                continue;
+            }
+            let content = std::fs::read_to_string(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to read {}", path))?;
+            exchanges.extend(
+                content
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<CapturedExchange>(line).ok()),
+            );
+        }
+        Ok(exchanges)
+    }
+
+    /// Remove all captured requests for the instance
+    pub fn clear_captured_requests(&self) -> Result<()> {
+        for file in [ROTATED_CAPTURE_FILE, CAPTURE_FILE] {
+            let path = self.workdir.join(file);
+            if path.exists() {
+                remove_file(&path)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to remove {}", path))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert captured exchanges to a HAR 1.2 document
+pub fn to_har(exchanges: &[CapturedExchange]) -> Value {
+    fn headers(headers: &[(String, String)]) -> Vec<Value> {
+        headers
+            .iter()
+            .map(|(name, value)| json!({"name": name, "value": value}))
+            .collect()
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn query_string(url: &str) -> Vec<Value> {
+        let Some((_, query)) = url.split_once('?') else {
+            return Vec::new();
+        };
+        query
+            .split('&')
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let (name, value) = part.split_once('=').unwrap_or((part, ""));
+                json!({"name": name, "value": value})
+            })
+            .collect()
+    }
+
+    fn body_comment(body: &CapturedBody) -> &'static str {
+        match (body.truncated, &body.text) {
+            (_, None) if body.size > 0 => "binary body not captured",
+            (true, _) => "body truncated",
+            _ => "",
+        }
+    }
+
+    let entries: Vec<Value> = exchanges
+        .iter()
+        .map(|exchange| {
+            let request = &exchange.request;
+            let mut har_request = json!({
+                "method": request.method,
+                "url": request.url,
+                "httpVersion": request.version,
+                "cookies": [],
+                "headers": headers(&request.headers),
+                "queryString": query_string(&request.url),
+                "headersSize": -1,
+                "bodySize": request.body.size,
+            });
+            if request.body.size > 0 {
+                har_request["postData"] = json!({
+                    "mimeType": header(&request.headers, "content-type").unwrap_or_default(),
+                    "text": request.body.text.as_deref().unwrap_or_default(),
+                    "comment": body_comment(&request.body),
+                });
+            }
+
+            let har_response = match &exchange.response {
+                Some(response) => json!({
+                    "status": response.status,
+                    "statusText": "",
+                    "httpVersion": response.version,
+                    "cookies": [],
+                    "headers": headers(&response.headers),
+                    "content": {
+                        "size": response.body.size,
+                        "mimeType": header(&response.headers, "content-type").unwrap_or_default(),
+                        "text": response.body.text.as_deref().unwrap_or_default(),
+                        "comment": body_comment(&response.body),
+                    },
+                    "redirectURL": header(&response.headers, "location").unwrap_or_default(),
+                    "headersSize": -1,
+                    "bodySize": response.body.size,
+                }),
+                // requests without a response are represented with status 0, like browsers do
+                None => json!({
+                    "status": 0,
+                    "statusText": "",
+                    "httpVersion": "",
+                    "cookies": [],
+                    "headers": [],
+                    "content": {"size": 0, "mimeType": ""},
+                    "redirectURL": "",
+                    "headersSize": -1,
+                    "bodySize": -1,
+                }),
+            };
+
+            json!({
+                "startedDateTime": exchange.started.to_rfc3339(),
+                "time": exchange.wait + exchange.receive,
+                "request": har_request,
+                "response": har_response,
+                "cache": {},
+                "timings": {
+                    "send": 0,
+                    "wait": exchange.wait,
+                    "receive": exchange.receive,
+                },
+            })
+        })
+        .collect();
+
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": {
+                "name": "haze",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "entries": entries,
+        }
+    })
+}
+
+fn print_exchange(mut out: impl Write, exchange: &CapturedExchange) -> Result<()> {
+    let time = exchange.started.with_timezone(&Local).format("%H:%M:%S");
+    let (status, size) = match &exchange.response {
+        Some(response) => (response.status.to_string(), response.body.size),
+        None => ("failed".to_string(), 0),
+    };
+    writeln!(
+        out,
+        "{time} {} {} {status} {:.0}ms {size}B",
+        exchange.request.method,
+        exchange.request.url,
+        exchange.wait + exchange.receive
+    )
+    .into_diagnostic()
+}
+
+/// Print the last captured requests of an instance and follow new requests as they are captured
+pub async fn follow_requests(cloud: &Cloud, mut out: impl Write, count: usize) -> Result<()> {
+    let path = cloud.workdir.join(CAPTURE_FILE);
+    let captured = cloud.captured_requests()?;
+    for exchange in &captured[captured.len().saturating_sub(count)..] {
+        print_exchange(&mut out, exchange)?;
+    }
+
+    let mut file = None;
+    let mut offset = path.metadata().map(|meta| meta.len()).unwrap_or(0);
+    let mut partial = Vec::new();
+    loop {
+        out.flush().into_diagnostic()?;
+        sleep(Duration::from_millis(250)).await;
+
+        let len = path.metadata().map(|meta| meta.len()).unwrap_or(0);
+        if len < offset {
+            // captures were cleared
+            file = None;
+            offset = 0;
+            partial.clear();
+        }
+        if len == offset {
+            continue;
+        }
+        let file = match &mut file {
+            Some(file) => file,
+            None => file.insert(File::open(&path).into_diagnostic()?),
+        };
+        file.seek(SeekFrom::Start(offset)).into_diagnostic()?;
+        offset += file.read_to_end(&mut partial).into_diagnostic()? as u64;
+
+        while let Some(end) = partial.iter().position(|c| *c == b'\n') {
+            let line: Vec<u8> = partial.drain(..=end).collect();
+            if let Ok(exchange) = serde_json::from_slice::<CapturedExchange>(&line) {
+                print_exchange(&mut out, &exchange)?;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_capture() {
+    use axum::body::to_bytes;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let workdir = Utf8Path::from_path(dir.path()).unwrap();
+
+    let request = || {
+        Request::builder()
+            .method("PROPFIND")
+            .uri("/remote.php/dav/files/admin")
+            .header("content-type", "application/xml")
+            .header("authorization", "Basic YWRtaW46YWRtaW4=")
+            .body(Body::from("<d:propfind/>"))
+            .unwrap()
+    };
+    let url = "https://foo.haze.example.com/remote.php/dav/files/admin?depth=1";
+    let (writer, task) = CaptureWriter::spawn();
+
+    let mut req = request();
+    let capture = Capture::start(&writer, workdir, url.into(), &mut req);
+    // the forwarded body is unchanged
+    let forwarded = to_bytes(req.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&forwarded[..], b"<d:propfind/>");
+
+    let large = "a".repeat(MAX_BODY_SIZE + 10);
+    let response = capture.finish(
+        Response::builder()
+            .status(207)
+            .body(Body::from(large.clone()))
+            .unwrap(),
+    );
+    let sent = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(sent.len(), large.len());
+
+    // wait for the writer to save the exchange
+    drop(writer);
+    task.await.unwrap();
+    let path = workdir.join(CAPTURE_FILE);
+    assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+    let content = std::fs::read_to_string(path).unwrap();
+    let exchange: CapturedExchange = serde_json::from_str(content.trim()).unwrap();
+    assert_eq!(exchange.request.method, "PROPFIND");
+    assert_eq!(exchange.request.body.text.as_deref(), Some("<d:propfind/>"));
+    assert!(exchange
+        .request
+        .headers
+        .contains(&("authorization".into(), "[redacted]".into())));
+    let response = exchange.response.as_ref().unwrap();
+    assert_eq!(response.status, 207);
+    assert_eq!(response.body.size, large.len());
+    assert!(response.body.truncated);
+    assert_eq!(response.body.text.as_ref().unwrap().len(), MAX_BODY_SIZE);
+
+    let har = to_har(&[exchange]);
+    let entry = &har["log"]["entries"][0];
+    assert_eq!(entry["request"]["queryString"][0]["name"], "depth");
+    assert_eq!(entry["request"]["postData"]["mimeType"], "application/xml");
+    assert_eq!(entry["response"]["status"], 207);
+}
+
+#[tokio::test]
+async fn test_capture_rotation() {
+    let dir = tempfile::tempdir().unwrap();
+    let workdir = Utf8Path::from_path(dir.path()).unwrap();
+
+    append_capture(workdir, b"first".to_vec(), 16)
+        .await
+        .unwrap();
+    append_capture(workdir, b"second".to_vec(), 16)
+        .await
+        .unwrap();
+    append_capture(workdir, b"third".to_vec(), 16)
+        .await
+        .unwrap();
+    let read = |file| std::fs::read_to_string(workdir.join(file)).unwrap();
+    assert_eq!(read(ROTATED_CAPTURE_FILE), "first\nsecond\n");
+    assert_eq!(read(CAPTURE_FILE), "third\n");
+}
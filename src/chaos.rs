@@ -0,0 +1,539 @@
+use crate::docker::{owned_config, Docker};
+use crate::exec::exec;
+use crate::image::pull_image;
+use crate::{Cloud, Result, ServiceTrait};
+use bollard::container::{
+    Config, CreateContainerOptions, NetworkingConfig, RemoveContainerOptions,
+};
+use bollard::models::{EndpointSettings, HostConfig};
+use bollard::network::{ConnectNetworkOptions, DisconnectNetworkOptions};
+use maplit::hashmap;
+use miette::{miette, IntoDiagnostic, Report, WrapErr};
+use std::io::stdout;
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::info;
+
+/// Image of the tcp proxy that is put in front of services
+pub const CHAOS_IMAGE: &str = "ghcr.io/shopify/toxiproxy:2.9.0";
+
+const CLI: &str = "/toxiproxy-cli";
+
+/// A fault to inject into the connections to a service, or a change to the injected faults
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ChaosAction {
+    /// Delay all data by a fixed duration
+    Latency(Duration),
+    /// Limit the bandwidth in KB/s
+    Bandwidth(u64),
+    /// Stall a percentage of the connections, they stop passing data without being closed
+    Stall(u8),
+    /// Close all open connections
+    Reset,
+    /// Remove all injected faults
+    Clear,
+    /// Remove the proxy in front of the service
+    Off,
+}
+
+/// The container of a service of an instance that can have a proxy put in front of it
+#[derive(Debug)]
+struct Target {
+    /// Name used for the proxies
+    name: String,
+    container: String,
+}
+
+impl Target {
+    fn find(cloud: &Cloud, service: &str) -> Result<Target> {
+        let db = cloud.db();
+        let container = if service == "db" || service == db.name() {
+            db.container_name(&cloud.id)
+                .map(|container| ("db".to_string(), container))
+        } else {
+            cloud
+                .services()
+                .filter(|found| found.name() == service)
+                .find_map(|found| {
+                    Some((found.name().to_string(), found.container_name(&cloud.id)?))
+                })
+        };
+        let (name, container) = container
+            .ok_or_else(|| miette!("{} has no {} service with a container", cloud.id, service))?;
+        Ok(Target { name, container })
+    }
+
+    fn sidecar(&self) -> String {
+        format!("{}-chaos", self.container)
+    }
+}
+
+/// The proxy in front of a service
+#[derive(Debug)]
+struct Sidecar {
+    name: String,
+    /// Names of the proxies, one for each port of the service
+    proxies: Vec<String>,
+}
+
+impl Sidecar {
+    async fn get(docker: &Docker, target: &Target) -> Option<Sidecar> {
+        let name = target.sidecar();
+        let labels = docker
+            .inspect_container(&name, None)
+            .await
+            .ok()?
+            .config?
+            .labels?;
+        let proxies = labels
+            .get("haze-chaos-ports")?
+            .split(',')
+            .filter(|port| !port.is_empty())
+            .map(|port| format!("{}-{}", target.name, port))
+            .collect();
+        Some(Sidecar { name, proxies })
+    }
+
+    async fn cli(&self, docker: &Docker, args: Vec<String>, output: bool) -> Result<()> {
+        let mut cmd = vec![CLI.to_string()];
+        cmd.extend(args);
+        let out = output.then(stdout);
+        exec(docker, &self.name, "root", cmd, Vec::<String>::new(), out)
+            .await?
+            .to_result()
+    }
+
+    async fn remove_toxic(&self, docker: &Docker, proxy: &str, toxic: &str) -> Result<()> {
+        let args = vec!["toxic", "remove", "-n", toxic, proxy];
+        // the toxic not existing is fine, so the exit code is ignored
+        exec(
+            docker,
+            &self.name,
+            "root",
+            [CLI].into_iter().chain(args).collect(),
+            Vec::<String>::new(),
+            Option::<Vec<u8>>::None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_toxic(
+        &self,
+        docker: &Docker,
+        toxic: &str,
+        attribute: String,
+        toxicity: Option<u8>,
+    ) -> Result<()> {
+        for proxy in &self.proxies {
+            self.remove_toxic(docker, proxy, toxic).await?;
+            let mut args = vec![
+                "toxic".to_string(),
+                "add".into(),
+                "-t".into(),
+                toxic.into(),
+                "-n".into(),
+                toxic.into(),
+                "-a".into(),
+                attribute.clone(),
+            ];
+            if let Some(toxicity) = toxicity {
+                args.push("--toxicity".into());
+                args.push(format!("{:.2}", toxicity as f32 / 100.0));
+            }
+            args.push(proxy.clone());
+            self.cli(docker, args, false)
+                .await
+                .wrap_err_with(|| format!("Failed to add {} to {}", toxic, proxy))?;
+        }
+        Ok(())
+    }
+}
+
+/// Put a proxy in front of a service of an instance, if there isn't one yet
+///
+/// The proxy takes over the network aliases of the service, so connections from the instance made by alias go through it.
+async fn enable(docker: &Docker, cloud: &Cloud, target: &Target) -> Result<Sidecar> {
+    if let Some(sidecar) = Sidecar::get(docker, target).await {
+        return Ok(sidecar);
+    }
+
+    let info = docker
+        .inspect_container(&target.container, None)
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to inspect {}", target.container))?;
+    let mut ports: Vec<u16> = info
+        .config
+        .and_then(|config| config.exposed_ports)
+        .unwrap_or_default()
+        .keys()
+        .filter_map(|port| port.strip_suffix("/tcp")?.parse().ok())
+        .collect();
+    ports.sort_unstable();
+    if ports.is_empty() {
+        return Err(miette!("{} doesn't expose any tcp ports", target.container));
+    }
+    let aliases: Vec<String> = info
+        .network_settings
+        .and_then(|settings| settings.networks)
+        // services are only connected to the network of the instance
+        .and_then(|networks| networks.into_values().next())
+        .and_then(|endpoint| endpoint.aliases)
+        .unwrap_or_default()
+        .into_iter()
+        // docker adds the container name and short id itself
+        .filter(|alias| {
+            let id = info.id.as_deref().unwrap_or_default();
+            *alias != target.container && !(alias.len() == 12 && id.starts_with(alias.as_str()))
+        })
+        .collect();
+
+    pull_image(docker, CHAOS_IMAGE)
+        .await
+        .wrap_err("Failed to pull chaos proxy image")?;
+
+    let name = target.sidecar();
+    let sidecar = match start_sidecar(docker, cloud, target, &name, &ports, &aliases).await {
+        Ok(sidecar) => sidecar,
+        Err(e) => {
+            remove_sidecar(docker, &name).await.ok();
+            return Err(e);
+        }
+    };
+
+    // only take the aliases from the service once the proxy works, until then both answer to them
+    if let Err(e) = move_aliases(docker, cloud, target, Vec::new()).await {
+        remove_sidecar(docker, &name).await.ok();
+        // the service might have been disconnected already
+        connect(docker, &cloud.network, &target.container, aliases)
+            .await
+            .ok();
+        return Err(e);
+    }
+    info!(
+        service = target.name,
+        aliases = aliases.join(", "),
+        sidecar = sidecar.name,
+        "Proxying service"
+    );
+    Ok(sidecar)
+}
+
+/// Create the proxy container with the aliases of the service and a proxy for each port
+async fn start_sidecar(
+    docker: &Docker,
+    cloud: &Cloud,
+    target: &Target,
+    name: &str,
+    ports: &[u16],
+    aliases: &[String],
+) -> Result<Sidecar> {
+    let ports_label = ports
+        .iter()
+        .map(u16::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let aliases_label = aliases.join(",");
+    let options = Some(CreateContainerOptions {
+        name: name.to_string(),
+        ..CreateContainerOptions::default()
+    });
+    let config = Config {
+        image: Some(CHAOS_IMAGE),
+        host_config: Some(HostConfig {
+            network_mode: Some(cloud.network.clone()),
+            ..Default::default()
+        }),
+        labels: Some(hashmap! {
+            "haze-type" => "chaos",
+            "haze-cloud-id" => cloud.id.as_str(),
+            "haze-chaos-ports" => ports_label.as_str(),
+            "haze-chaos-aliases" => aliases_label.as_str(),
+        }),
+        networking_config: Some(NetworkingConfig {
+            endpoints_config: hashmap! {
+                cloud.network.as_str() => EndpointSettings {
+                    aliases: Some(aliases.to_vec()),
+                    ..Default::default()
+                }
+            },
+        }),
+        ..Default::default()
+    };
+    let id = docker
+        .create_container(options, owned_config(config))
+        .await
+        .into_diagnostic()
+        .wrap_err("Failed to create chaos proxy container")?
+        .id;
+    docker.start_container(&id).await.into_diagnostic()?;
+
+    let sidecar = Sidecar {
+        name: name.to_string(),
+        proxies: Vec::new(),
+    };
+    wait_for_api(docker, &sidecar).await?;
+    let mut proxies = Vec::with_capacity(ports.len());
+    for &port in ports {
+        let proxy = format!("{}-{}", target.name, port);
+        sidecar
+            .cli(
+                docker,
+                vec![
+                    "create".into(),
+                    "-l".into(),
+                    format!("0.0.0.0:{}", port),
+                    "-u".into(),
+                    format!("{}:{}", target.container, port),
+                    proxy.clone(),
+                ],
+                false,
+            )
+            .await
+            .wrap_err_with(|| format!("Failed to create proxy for port {}", port))?;
+        proxies.push(proxy);
+    }
+    Ok(Sidecar { proxies, ..sidecar })
+}
+
+async fn remove_sidecar(docker: &Docker, name: &str) -> Result<()> {
+    docker
+        .remove_container(
+            name,
+            Some(RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            }),
+        )
+        .await
+        .into_diagnostic()
+        .wrap_err("Failed to remove chaos proxy container")
+}
+
+/// Reconnect the service to the instance network with the provided aliases
+async fn move_aliases(
+    docker: &Docker,
+    cloud: &Cloud,
+    target: &Target,
+    aliases: Vec<String>,
+) -> Result<()> {
+    docker
+        .disconnect_network(
+            &cloud.network,
+            DisconnectNetworkOptions {
+                container: target.container.clone(),
+                force: false,
+            },
+        )
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to disconnect {}", target.container))?;
+    connect(docker, &cloud.network, &target.container, aliases).await
+}
+
+async fn wait_for_api(docker: &Docker, sidecar: &Sidecar) -> Result<()> {
+    for _ in 0..50 {
+        if sidecar
+            .cli(docker, vec!["list".into()], false)
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    Err(Report::msg(
+        "Timeout while waiting for the chaos proxy to start",
+    ))
+}
+
+async fn connect(
+    docker: &Docker,
+    network: &str,
+    container: &str,
+    aliases: Vec<String>,
+) -> Result<()> {
+    docker
+        .connect_network(
+            network,
+            ConnectNetworkOptions {
+                container: container.to_string(),
+                endpoint_config: EndpointSettings {
+                    aliases: Some(aliases),
+                    ..Default::default()
+                },
+            },
+        )
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to connect {} to {}", container, network))
+}
+
+/// Remove the proxy in front of a service and give the aliases back to the service
+async fn disable(docker: &Docker, cloud: &Cloud, target: &Target) -> Result<()> {
+    let name = target.sidecar();
+    let Ok(info) = docker.inspect_container(&name, None).await else {
+        info!(service = target.name, "Service isn't proxied");
+        return Ok(());
+    };
+    let aliases = info
+        .config
+        .and_then(|config| config.labels)
+        .and_then(|mut labels| labels.remove("haze-chaos-aliases"))
+        .unwrap_or_default()
+        .split(',')
+        .filter(|alias| !alias.is_empty())
+        .map(String::from)
+        .collect();
+    remove_sidecar(docker, &name).await?;
+    move_aliases(docker, cloud, target, aliases).await
+}
+
+/// Inject a fault into the connections from an instance to one of its services
+///
+/// A proxy is put in front of the service the first time, without an action the current faults are shown.
+pub async fn chaos(
+    docker: &Docker,
+    cloud: &Cloud,
+    service: &str,
+    action: Option<ChaosAction>,
+) -> Result<()> {
+    let target = Target::find(cloud, service)?;
+    let sidecar = || enable(docker, cloud, &target);
+    match action {
+        None => {
+            let sidecar = sidecar().await?;
+            for proxy in &sidecar.proxies {
+                sidecar
+                    .cli(docker, vec!["inspect".into(), proxy.clone()], true)
+                    .await?;
+            }
+        }
+        Some(ChaosAction::Latency(latency)) => {
+            sidecar()
+                .await?
+                .set_toxic(
+                    docker,
+                    "latency",
+                    format!("latency={}", latency.as_millis()),
+                    None,
+                )
+                .await?;
+        }
+        Some(ChaosAction::Bandwidth(rate)) => {
+            sidecar()
+                .await?
+                .set_toxic(docker, "bandwidth", format!("rate={}", rate), None)
+                .await?;
+        }
+        Some(ChaosAction::Stall(percentage)) => {
+            // a timeout toxic without a timeout holds the data of the connection until the toxic is removed
+            sidecar()
+                .await?
+                .set_toxic(docker, "timeout", "timeout=0".into(), Some(percentage))
+                .await?;
+        }
+        Some(ChaosAction::Reset) => {
+            // disabling a proxy closes all its connections
+            let sidecar = sidecar().await?;
+            for proxy in &sidecar.proxies {
+                for _ in 0..2 {
+                    sidecar
+                        .cli(docker, vec!["toggle".into(), proxy.clone()], false)
+                        .await?;
+                }
+            }
+        }
+        Some(ChaosAction::Clear) => {
+            let sidecar = sidecar().await?;
+            for proxy in &sidecar.proxies {
+                for toxic in ["latency", "bandwidth", "timeout"] {
+                    sidecar.remove_toxic(docker, proxy, toxic).await?;
+                }
+            }
+        }
+        Some(ChaosAction::Off) => disable(docker, cloud, &target).await?,
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chaos() {
+    use crate::cloud::{test_env, test_options};
+    use crate::Database;
+
+    let (_dir, config, fake, docker) = test_env();
+    let cloud = Cloud::create(&docker, test_options("test", Database::MariaDB), &config)
+        .await
+        .unwrap();
+    fake.expose("haze-test-db", "3306/tcp");
+    let aliases = |container: &str| {
+        fake.container(container)
+            .map(|container| container.networks["haze-test"].aliases.clone())
+    };
+
+    chaos(
+        &docker,
+        &cloud,
+        "db",
+        Some(ChaosAction::Latency(Duration::from_millis(500))),
+    )
+    .await
+    .unwrap();
+    assert_eq!(aliases("haze-test-db"), Some(vec![]));
+    assert_eq!(
+        aliases("haze-test-db-chaos"),
+        Some(vec!["mariadb".to_string(), "db".to_string()])
+    );
+    let commands: Vec<_> = fake
+        .execs()
+        .into_iter()
+        .filter(|exec| exec.container == "haze-test-db-chaos")
+        .map(|exec| exec.cmd[1..].join(" "))
+        .collect();
+    assert!(commands.contains(&"create -l 0.0.0.0:3306 -u haze-test-db:3306 db-3306".to_string()));
+    assert!(
+        commands.contains(&"toxic add -t latency -n latency -a latency=500 db-3306".to_string())
+    );
+
+    // the proxy is part of the instance
+    let cloud = Cloud::get_by_filter(&docker, None, &config).await.unwrap();
+    assert!(cloud
+        .containers
+        .iter()
+        .any(|container| container.ends_with("haze-test-db-chaos")));
+
+    chaos(&docker, &cloud, "db", Some(ChaosAction::Off))
+        .await
+        .unwrap();
+    assert_eq!(aliases("haze-test-db-chaos"), None);
+    assert_eq!(
+        aliases("haze-test-db"),
+        Some(vec!["mariadb".to_string(), "db".to_string()])
+    );
+
+    assert!(chaos(&docker, &cloud, "s3", None).await.is_err());
+}
+
+#[tokio::test]
+async fn test_chaos_failure_keeps_aliases() {
+    use crate::cloud::{test_env, test_options};
+    use crate::Database;
+
+    let (_dir, config, fake, docker) = test_env();
+    let cloud = Cloud::create(&docker, test_options("test", Database::MariaDB), &config)
+        .await
+        .unwrap();
+    fake.expose("haze-test-db", "3306/tcp");
+    fake.fail_start("haze-test-db-chaos");
+
+    assert!(chaos(&docker, &cloud, "db", Some(ChaosAction::Reset))
+        .await
+        .is_err());
+    assert!(fake.container("haze-test-db-chaos").is_none());
+    assert_eq!(
+        fake.container("haze-test-db").unwrap().networks["haze-test"].aliases,
+        vec!["mariadb".to_string(), "db".to_string()]
+    );
+}
@@ -1,24 +1,29 @@
-use crate::config::{HazeConfig, HazeVolumeConfig, Preset};
+use crate::config::{HazeConfig, HazeVolumeConfig, HooksConfig, Preset, TimeoutConfig};
+use crate::copy::{upload_data, Owner};
 use crate::database::Database;
-use crate::exec::{exec, exec_io, exec_tty, ExitCode};
-use crate::mapping::{default_mappings, Mapping};
+use crate::docker::Docker;
+use crate::exec::{exec, exec_tty, ExitCode};
+use crate::hooks::{options_env, run_hooks, Hook};
+use crate::mapping::{ca_mapping, default_mappings, Mapping};
 use crate::php::{PhpVersion, PHP_MEMORY_LIMIT};
 use crate::service::Service;
 use crate::service::ServiceTrait;
+use crate::status::{ComponentStatus, ContainerStatus};
+use crate::tls::{LocalCa, CA_DIR};
 use bollard::container::{ListContainersOptions, RemoveContainerOptions, UpdateContainerOptions};
 use bollard::models::ContainerState;
 use bollard::network::CreateNetworkOptions;
-use bollard::Docker;
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
 use flate2::read::GzDecoder;
-use futures_util::future::try_join_all;
+use futures_util::future::{join_all, try_join_all};
 use miette::{IntoDiagnostic, Report, Result, WrapErr};
 use petname::petname;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs;
-use std::io::{stdout, Cursor, Read, Stdout, Write};
+use std::io::{stdout, Write};
 use std::iter::Peekable;
 use std::net::IpAddr;
 use std::os::unix::fs::MetadataExt;
@@ -26,19 +31,23 @@ use std::str::FromStr;
 use std::time::Duration;
 use tokio::fs::create_dir_all;
 use tokio::fs::remove_dir_all;
-use tokio::task::spawn;
 use tokio::time::sleep;
+use tracing::{info, warn};
 
+/// Options for creating a new instance
 #[derive(Clone, Default, Debug, Eq, PartialEq)]
 pub struct CloudOptions {
+    /// Name of the instance, a random name is generated when not set
     pub name: Option<String>,
     pub db: Database,
     pub php: PhpVersion,
     pub services: Vec<Service>,
+    /// App packages to extract into the apps directory of the instance
     pub app_packages: Vec<Utf8PathBuf>,
 }
 
 impl CloudOptions {
+    /// Parse options from the command line arguments used by `haze start`
     pub fn parse<I, S>(presets: &[Preset], args: &mut Peekable<I>) -> Result<CloudOptions>
     where
         S: AsRef<str> + Into<String> + Display,
@@ -154,6 +163,7 @@ fn test_option_parse() {
                 commands: Vec::new(),
                 apps: Vec::new(),
                 config: HashMap::default(),
+                hooks: Default::default(),
             }],
             &mut args
         )
@@ -171,20 +181,35 @@ fn test_option_parse() {
     );
 }
 
+/// A running or stopped instance
 #[derive(Debug)]
 pub struct Cloud {
+    /// Id of the instance, also the name of the php container
     pub id: String,
+    /// Name of the docker network of the instance
     pub network: String,
+    /// Names of all containers of the instance
     pub containers: Vec<String>,
+    /// Ip of the php container, `None` if the instance is stopped
     pub ip: Option<IpAddr>,
+    /// Directory on the host containing the data and config of the instance
     pub workdir: Utf8PathBuf,
     pub options: CloudOptions,
+    /// Pinned instances are not removed by `haze clean`
     pub pinned: bool,
+    /// Url the instance can be reached on
     pub address: String,
+    /// Nextcloud config provided by the presets of the instance
     pub preset_config: HashMap<String, Value>,
+    /// Host commands to run on lifecycle events, from the config and the presets of the instance
+    pub hooks: HooksConfig,
+    pub created: DateTime<Utc>,
 }
 
 impl Cloud {
+    /// Create and start the containers for a new instance
+    ///
+    /// This doesn't wait for the containers to be ready or install nextcloud, see [`crate::setup`] for that.
     pub async fn create(
         docker: &Docker,
         options: CloudOptions,
@@ -199,6 +224,14 @@ impl Cloud {
         let workdir = config.work_dir.join(&id);
         let app_package_dir = workdir.join("app_package");
 
+        let hooks = HooksConfig::for_services(config, &options.services);
+        run_hooks(
+            Hook::PreCreate,
+            hooks.commands(Hook::PreCreate),
+            &options_env(&id, &workdir, &options),
+        )
+        .await?;
+
         if !options.app_packages.is_empty() {
             create_dir_all(&app_package_dir)
                 .await
@@ -217,7 +250,7 @@ impl Cloud {
                     .into_diagnostic()
                     .wrap_err_with(|| format!("Failed to open app bundle {}", app_package))?;
                 if app_package.metadata().into_diagnostic()?.len() > 1024 * 1024 {
-                    println!("Extracting app archive for {}...", app_name);
+                    info!(app = app_name, "Extracting app archive");
                 }
                 let gz = GzDecoder::new(app_package_file);
                 tar::Archive::new(gz)
@@ -233,11 +266,18 @@ impl Cloud {
                 })
             })
             .collect::<Result<Vec<_>>>()?;
+        let proxy_ca = if config.proxy.tls_enabled() {
+            LocalCa::load_or_create(&config.work_dir.join(CA_DIR), &config.proxy.address)?;
+            Some(ca_mapping())
+        } else {
+            None
+        };
         let mappings = config
             .volume
             .iter()
             .map(Mapping::from)
             .chain(default_mappings())
+            .chain(proxy_ca)
             .chain(app_volumes.iter().map(Mapping::from))
             .collect::<Vec<_>>();
         for mapping in &mappings {
@@ -247,34 +287,6 @@ impl Cloud {
                 .wrap_err_with(|| format!("Failed to setup work directory {}", mapping.source))?;
         }
 
-        let network = docker
-            .create_network(CreateNetworkOptions {
-                name: id.as_str(),
-                ..Default::default()
-            })
-            .await
-            .into_diagnostic()?
-            .id;
-
-        let network_info = docker
-            .inspect_network::<String>(&network, None)
-            .await
-            .into_diagnostic()?;
-        let gateway = network_info
-            .ipam
-            .as_ref()
-            .ok_or_else(|| Report::msg("Network has no ip info"))?
-            .config
-            .as_deref()
-            .ok_or_else(|| Report::msg("Network has no ip info"))?
-            .first()
-            .ok_or_else(|| Report::msg("Network has no ip info"))?
-            .gateway
-            .as_deref()
-            .ok_or_else(|| Report::msg("Network has no ip info"))?;
-
-        let mut containers = Vec::new();
-
         let sources_meta = fs::metadata(&config.sources_root).into_diagnostic()?;
         let uid = sources_meta.uid();
         let gid = sources_meta.gid();
@@ -291,16 +303,6 @@ impl Cloud {
             .filter_map(|mapping| mapping.get_volume_arg(&id, config))
             .collect();
 
-        if let Some(db_name) = options
-            .db
-            .spawn(docker, &id, &network, "")
-            .await
-            .wrap_err("Failed to start database")?
-        {
-            containers.push(db_name);
-            env.push(format!("SQL={}", options.db.name()));
-        }
-
         if let Some(blackfire) = config.blackfire.as_ref() {
             env.push(format!("BLACKFIRE_SERVER_ID={}", blackfire.server_id));
             env.push(format!("BLACKFIRE_SERVER_TOKEN={}", blackfire.server_token));
@@ -308,28 +310,73 @@ impl Cloud {
             env.push(format!("BLACKFIRE_CLIENT_TOKEN={}", blackfire.client_token));
         }
 
-        let service_containers: Vec<Vec<String>> = try_join_all(
+        env.extend(
             options
                 .services
                 .iter()
-                .map(|service| service.spawn(docker, &id, &network, config, &options)),
-        )
-        .await?;
-        containers.extend(service_containers.iter().flatten().cloned());
+                .flat_map(Service::env)
+                .copied()
+                .map(String::from),
+        );
 
-        let mut preset_config = HashMap::new();
-        for service in &options.services {
-            preset_config.extend(service.config(docker, &id, config)?);
+        let network = docker
+            .create_network(CreateNetworkOptions {
+                name: id.clone(),
+                ..Default::default()
+            })
+            .await
+            .into_diagnostic()?
+            .id;
+
+        // from here on, everything that was created is removed again if a later step fails
+        let mut containers = Vec::new();
+
+        let gateway = match network_gateway(docker, &network).await {
+            Ok(gateway) => gateway,
+            Err(e) => return Err(rollback(docker, &containers, &network, &workdir, e).await),
+        };
+
+        match options
+            .db
+            .spawn(docker, &id, &network, "")
+            .await
+            .wrap_err("Failed to start database")
+        {
+            Ok(Some(db_name)) => {
+                containers.push(db_name);
+                env.push(format!("SQL={}", options.db.name()));
+            }
+            Ok(None) => {}
+            Err(e) => return Err(rollback(docker, &containers, &network, &workdir, e).await),
         }
 
-        env.extend(
+        let mut service_error = None;
+        for spawned in join_all(
             options
                 .services
                 .iter()
-                .flat_map(Service::env)
-                .copied()
-                .map(String::from),
-        );
+                .map(|service| service.spawn(docker, &id, &network, config, &options)),
+        )
+        .await
+        {
+            match spawned {
+                Ok(service_containers) => containers.extend(service_containers),
+                Err(e) => {
+                    service_error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = service_error {
+            return Err(rollback(docker, &containers, &network, &workdir, e).await);
+        }
+
+        let mut preset_config = HashMap::new();
+        for service in &options.services {
+            match service.config(docker, &id, config) {
+                Ok(service_config) => preset_config.extend(service_config),
+                Err(e) => return Err(rollback(docker, &containers, &network, &workdir, e).await),
+            }
+        }
 
         let container = match options
             .php
@@ -340,96 +387,25 @@ impl Cloud {
                 &options.db,
                 &network,
                 volumes,
-                gateway,
+                &gateway,
                 &options.services,
             )
             .await
             .wrap_err("Failed to start php container")
         {
             Ok(container) => container,
-            Err(e) => {
-                for container in service_containers.iter().flatten() {
-                    docker
-                        .remove_container(
-                            container,
-                            Some(RemoveContainerOptions {
-                                force: true,
-                                ..RemoveContainerOptions::default()
-                            }),
-                        )
-                        .await
-                        .ok();
-                }
-                return Err(e);
-            }
-        };
-
-        let mut tries = 0;
-        let ip = loop {
-            let info = docker
-                .inspect_container(&container, None)
-                .await
-                .into_diagnostic()?;
-            if matches!(
-                info.state,
-                Some(ContainerState {
-                    running: Some(true),
-                    ..
-                })
-            ) {
-                break info
-                    .network_settings
-                    .unwrap()
-                    .networks
-                    .unwrap()
-                    .iter()
-                    .filter_map(|(name, network)| name.eq("haze").then_some(network))
-                    .next()
-                    .unwrap()
-                    .ip_address
-                    .as_ref()
-                    .unwrap()
-                    .parse()
-                    .unwrap();
-            } else if tries > 100 {
-                return Err(Report::msg("starting container timed out"));
-            } else {
-                tries += 1;
-                sleep(Duration::from_millis(100)).await;
-            }
+            Err(e) => return Err(rollback(docker, &containers, &network, &workdir, e).await),
         };
-
         containers.push(container);
 
-        let options_clone = options.clone();
-        let cloud_id = id.clone();
-        let docker_clone = docker.clone();
-        spawn(async move {
-            if let Err(e) =
-                try_join_all(options_clone.services.iter().map(|service| {
-                    service.wait_for_start(&docker_clone, &cloud_id, &options_clone)
-                }))
-                .await
-            {
-                println!("{:#}", e);
-                return;
-            }
-            for service in options_clone.services {
-                match service.start_message(&docker_clone, &cloud_id).await {
-                    Ok(Some(msg)) => {
-                        println!("{}", msg);
-                    }
-                    Err(e) => {
-                        println!("{:#}", e);
-                    }
-                    _ => {}
-                }
-            }
-        });
+        let ip = match wait_for_ip(docker, &id).await {
+            Ok(ip) => ip,
+            Err(e) => return Err(rollback(docker, &containers, &network, &workdir, e).await),
+        };
 
         let address = config.proxy.addr(&id, ip);
 
-        Ok(Cloud {
+        let cloud = Cloud {
             id,
             network,
             containers,
@@ -439,10 +415,20 @@ impl Cloud {
             pinned: false,
             address,
             preset_config,
-        })
+            hooks,
+            created: Utc::now(),
+        };
+        if let Err(e) = cloud.run_hook(docker, Hook::PostCreate).await {
+            warn!("{}", e);
+        }
+        Ok(cloud)
     }
 
+    /// Remove all containers, the network and the work directory of the instance
     pub async fn destroy(self, docker: &Docker) -> Result<()> {
+        if let Err(e) = self.run_hook(docker, Hook::PreDestroy).await {
+            warn!("{}", e);
+        }
         for container in self.containers {
             docker
                 .remove_container(
@@ -467,55 +453,121 @@ impl Cloud {
                 .into_diagnostic()
                 .wrap_err("Failed to remove work directory")
             {
-                eprintln!("{}", e);
+                warn!("{:#}", e);
             }
         }
 
         Ok(())
     }
 
+    /// Copy the data and config directories and the database contents of another instance into this one
+    pub async fn copy_from(&self, docker: &Docker, source: &Cloud) -> Result<()> {
+        if source.db().family() != self.db().family() {
+            return Err(Report::msg(
+                "Can't copy between instances with different databases",
+            ));
+        }
+        for dir in ["data", "config"] {
+            copy_dir(&source.workdir.join(dir), &self.workdir.join(dir))
+                .wrap_err_with(|| format!("Failed to copy {} directory", dir))?;
+        }
+
+        // the extra databases of services like sharding are copied along with the main database
+        let postfixes = [""].into_iter().chain(
+            source
+                .services()
+                .flat_map(|service| service.databases())
+                .copied(),
+        );
+        for postfix in postfixes {
+            let mut dump = Vec::new();
+            source
+                .db()
+                .dump(docker, &source.id, postfix, &mut dump)
+                .await?;
+            self.db()
+                .restore(docker, &self.id, postfix, dump.as_slice())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Run a command in the php container as the `haze` user
     pub async fn exec<S: Into<String>, Env: Into<String>>(
         &self,
         docker: &Docker,
         cmd: Vec<S>,
         tty: bool,
         env: Vec<Env>,
+    ) -> Result<ExitCode> {
+        self.exec_as(docker, "haze", cmd, tty, env).await
+    }
+
+    pub async fn exec_as<S: Into<String>, Env: Into<String>>(
+        &self,
+        docker: &Docker,
+        user: &str,
+        cmd: Vec<S>,
+        tty: bool,
+        env: Vec<Env>,
     ) -> Result<ExitCode> {
         if tty {
-            exec_tty(docker, &self.id, "haze", cmd, env).await
+            exec_tty(docker, &self.id, user, cmd, env).await
         } else {
-            exec(docker, &self.id, "haze", cmd, env, Some(stdout())).await
+            exec(docker, &self.id, user, cmd, env, Some(stdout())).await
         }
     }
 
+    /// Write a file in the instance, relative paths are relative to the nextcloud root
     pub async fn write_file<C: AsRef<[u8]>>(
         &self,
         docker: &Docker,
         path: &str,
         contents: C,
     ) -> Result<()> {
-        self.exec_io(
+        let owner = self.owner(docker).await?;
+        upload_data(
             docker,
-            vec!["tee", path],
-            Vec::<String>::default(),
-            Option::<Stdout>::None,
-            Some(Cursor::new(contents.as_ref())),
+            &self.id,
+            &self.container_path(path),
+            contents.as_ref(),
+            owner,
         )
-        .await?;
-        Ok(())
+        .await
     }
 
-    pub async fn exec_io<S: Into<String>, Env: Into<String>>(
-        &self,
-        docker: &Docker,
-        cmd: Vec<S>,
-        env: Vec<Env>,
-        std_out: Option<impl Write>,
-        std_in: Option<impl Read>,
-    ) -> Result<ExitCode> {
-        exec_io(docker, &self.id, "haze", cmd, env, std_out, std_in).await
+    /// Resolve a path in the php container, relative paths are relative to the nextcloud root
+    pub fn container_path(&self, path: &str) -> String {
+        if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/var/www/html/{}", path)
+        }
+    }
+
+    /// The uid and gid used by the instance
+    pub async fn owner(&self, docker: &Docker) -> Result<Owner> {
+        let info = docker
+            .inspect_container(&self.id, None)
+            .await
+            .into_diagnostic()?;
+        let env = info
+            .config
+            .and_then(|config| config.env)
+            .unwrap_or_default();
+        let get = |name: &str| {
+            env.iter()
+                .find_map(|var| var.strip_prefix(name)?.strip_prefix('='))
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| Report::msg(format!("Can't determine {} of {}", name, self.id)))
+        };
+        Ok(Owner {
+            uid: get("UID")?,
+            gid: get("GID")?,
+        })
     }
 
+    /// Run an occ command, optionally capturing the output
     pub async fn occ<'a, S: Into<String> + From<&'a str>, Env: Into<String>>(
         &self,
         docker: &Docker,
@@ -537,13 +589,14 @@ impl Cloud {
         exec(docker, &self.id, "haze", cmd, env, output).await
     }
 
+    /// List all instances, optionally filtered by name
     pub async fn list(
         docker: &Docker,
         filter: Option<String>,
         config: &HazeConfig,
     ) -> Result<Vec<Cloud>> {
         let containers = docker
-            .list_containers::<String>(Some(ListContainersOptions {
+            .list_containers(Some(ListContainersOptions {
                 all: true,
                 ..Default::default()
             }))
@@ -581,7 +634,7 @@ impl Cloud {
                 let db = labels.get("haze-db")?.parse().ok()?;
                 let php = labels.get("haze-php")?.parse().ok()?;
 
-                let found_services = labels
+                let found_services: Vec<_> = labels
                     .get("haze-services")?
                     .split(',')
                     .flat_map(|service| {
@@ -610,9 +663,12 @@ impl Cloud {
                     "Not running".into()
                 };
 
+                let hooks = HooksConfig::for_services(config, &found_services);
                 service_ids.push(id.clone());
+                let created = DateTime::from_timestamp(cloud.created.unwrap_or_default(), 0)
+                    .unwrap_or_default();
                 Some((
-                    cloud.created.unwrap_or_default(),
+                    created,
                     Cloud {
                         id,
                         network,
@@ -629,6 +685,8 @@ impl Cloud {
                         pinned,
                         address,
                         preset_config: HashMap::default(),
+                        hooks,
+                        created,
                     },
                 ))
             })
@@ -642,6 +700,7 @@ impl Cloud {
             .collect())
     }
 
+    /// Get the most recently created instance matching the filter
     pub async fn get_by_filter(
         docker: &Docker,
         filter: Option<String>,
@@ -654,26 +713,117 @@ impl Cloud {
             .ok_or_else(|| Report::msg("No clouds running matching filter"))
     }
 
-    pub async fn wait_for_start(&self, docker: &Docker) -> Result<()> {
+    /// Wait until the php container, database and all services are healthy
+    pub async fn wait_for_start(&self, docker: &Docker, timeouts: &TimeoutConfig) -> Result<()> {
         self.options
             .php
-            .wait_for_start(self.ip)
+            .wait_for_start(docker, &self.id, self.ip, timeouts)
             .await
             .wrap_err("Failed to wait for php container")?;
         self.options
             .db
-            .wait_for_start(docker, &self.id)
+            .wait_for_start(docker, &self.id, timeouts)
             .await
             .wrap_err("Failed to wait for database container")?;
         try_join_all(
             self.services()
-                .map(|service| service.wait_for_start(docker, &self.id, &self.options)),
+                .map(|service| service.wait_for_start(docker, &self.id, &self.options, timeouts)),
         )
         .await
         .wrap_err("Failed to wait for service containers")?;
         Ok(())
     }
 
+    /// The messages the services show once they are started, like the credentials to use for them
+    pub async fn service_messages(&self, docker: &Docker) -> Result<Vec<String>> {
+        let mut messages = Vec::new();
+        for service in self.services() {
+            if let Some(message) = service.start_message(docker, &self.id).await? {
+                messages.push(message);
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Check if nextcloud has been installed in the instance
+    pub async fn is_installed(&self, docker: &Docker) -> Result<bool> {
+        let mut output = Vec::new();
+        self.occ(
+            docker,
+            vec!["status", "--output=json"],
+            Some(&mut output),
+            Vec::<String>::default(),
+        )
+        .await?;
+        let output = String::from_utf8_lossy(&output);
+        let Some(start) = output.find('{') else {
+            return Ok(false);
+        };
+        let status: Value = serde_json::from_str(output[start..].trim()).into_diagnostic()?;
+        Ok(status["installed"] == Value::Bool(true))
+    }
+
+    /// Check the state and health of every container of the instance once, without waiting
+    pub async fn status(&self, docker: &Docker) -> Vec<ComponentStatus> {
+        let mut status = Vec::new();
+
+        let state = ContainerStatus::get(docker, &self.id).await;
+        let state = if state == ContainerStatus::Running {
+            state.with_health(self.php().is_healthy(self.ip).await.unwrap_or(false))
+        } else {
+            state
+        };
+        status.push(ComponentStatus {
+            name: "cloud".into(),
+            container: Some(self.id.clone()),
+            state,
+        });
+
+        if let Some(container) = self.db().container_name(&self.id) {
+            let state = ContainerStatus::get(docker, &container).await;
+            let state = if state == ContainerStatus::Running {
+                state.with_health(
+                    self.db()
+                        .is_healthy(docker, &self.id, "")
+                        .await
+                        .unwrap_or(false),
+                )
+            } else {
+                state
+            };
+            status.push(ComponentStatus {
+                name: "db".into(),
+                container: Some(container),
+                state,
+            });
+        }
+
+        for service in self.services() {
+            let container = service.container_name(&self.id);
+            let state = match &container {
+                Some(container) => ContainerStatus::get(docker, container).await,
+                None => ContainerStatus::Running,
+            };
+            let state = if state == ContainerStatus::Running {
+                state.with_health(
+                    service
+                        .is_healthy(docker, &self.id, &self.options)
+                        .await
+                        .unwrap_or(false),
+                )
+            } else {
+                state
+            };
+            status.push(ComponentStatus {
+                name: service.name().into(),
+                container,
+                state,
+            });
+        }
+
+        status
+    }
+
     pub async fn enable_app<S: Into<String>>(&self, docker: &Docker, app: S) -> Result<()> {
         self.exec(
             docker,
@@ -730,3 +880,353 @@ impl Cloud {
         &self.options.php
     }
 }
+
+pub(crate) async fn network_gateway(docker: &Docker, network: &str) -> Result<String> {
+    let network_info = docker
+        .inspect_network(network, None)
+        .await
+        .into_diagnostic()?;
+    network_info
+        .ipam
+        .and_then(|ipam| ipam.config)
+        .and_then(|config| config.into_iter().next())
+        .and_then(|config| config.gateway)
+        .ok_or_else(|| Report::msg("Network has no ip info"))
+}
+
+/// Wait for the php container to be running and get its ip in the `haze` network
+async fn wait_for_ip(docker: &Docker, container: &str) -> Result<IpAddr> {
+    let mut tries = 0;
+    loop {
+        let info = docker
+            .inspect_container(container, None)
+            .await
+            .into_diagnostic()?;
+        if matches!(
+            info.state,
+            Some(ContainerState {
+                running: Some(true),
+                ..
+            })
+        ) {
+            return Ok(info
+                .network_settings
+                .unwrap()
+                .networks
+                .unwrap()
+                .iter()
+                .filter_map(|(name, network)| name.eq("haze").then_some(network))
+                .next()
+                .unwrap()
+                .ip_address
+                .as_ref()
+                .unwrap()
+                .parse()
+                .unwrap());
+        } else if tries > 100 {
+            return Err(Report::msg("starting container timed out"));
+        } else {
+            tries += 1;
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// Remove the containers and network of a partially created instance, returning the error that caused the rollback
+async fn rollback(
+    docker: &Docker,
+    containers: &[String],
+    network: &str,
+    workdir: &Utf8Path,
+    error: Report,
+) -> Report {
+    for container in containers {
+        docker
+            .remove_container(
+                container,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+            .ok();
+    }
+    docker.remove_network(network).await.ok();
+    remove_dir_all(workdir).await.ok();
+    error
+}
+
+fn copy_dir(source: &Utf8Path, target: &Utf8Path) -> Result<()> {
+    fs::create_dir_all(target).into_diagnostic()?;
+    for entry in source.read_dir_utf8().into_diagnostic()? {
+        let entry = entry.into_diagnostic()?;
+        let target = target.join(entry.file_name());
+        if entry.file_type().into_diagnostic()?.is_dir() {
+            copy_dir(entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to copy {}", entry.path()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn test_config(dir: &tempfile::TempDir) -> HazeConfig {
+    let root = Utf8Path::from_path(dir.path()).unwrap();
+    let sources_root = root.join("sources");
+    fs::create_dir_all(&sources_root).unwrap();
+    HazeConfig {
+        sources_root,
+        work_dir: root.join("work"),
+        auto_setup: Default::default(),
+        volume: Vec::new(),
+        blackfire: None,
+        proxy: Default::default(),
+        daemon: Default::default(),
+        dns: Default::default(),
+        hooks: Default::default(),
+        preset: Vec::new(),
+        timeouts: Default::default(),
+    }
+}
+
+/// Temporary directory, config and fake docker backend for tests, the directory is removed when dropped
+#[cfg(test)]
+pub(crate) fn test_env() -> (
+    tempfile::TempDir,
+    HazeConfig,
+    std::sync::Arc<crate::docker::fake::FakeDocker>,
+    Docker,
+) {
+    let dir = tempfile::tempdir().unwrap();
+    let config = test_config(&dir);
+    let fake = std::sync::Arc::new(crate::docker::fake::FakeDocker::new());
+    let docker: Docker = fake.clone();
+    (dir, config, fake, docker)
+}
+
+#[cfg(test)]
+pub(crate) fn test_options(name: &str, db: Database) -> CloudOptions {
+    CloudOptions {
+        name: Some(name.into()),
+        db,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_create() {
+    let (_dir, config, fake, docker) = test_env();
+
+    let cloud = Cloud::create(&docker, test_options("test", Database::MariaDB), &config)
+        .await
+        .unwrap();
+    assert_eq!(cloud.id, "haze-test");
+    assert_eq!(cloud.workdir, config.work_dir.join("haze-test"));
+    assert!(cloud.workdir.join("data").is_dir());
+    assert_eq!(fake.networks(), vec!["haze-test", "haze"]);
+
+    let containers = fake.containers();
+    let names: Vec<_> = containers.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, vec!["haze-test-db", "haze-test"]);
+    assert!(containers.iter().all(|container| container.running));
+    assert_eq!(
+        cloud.ip.map(|ip| ip.to_string()),
+        Some(containers[1].networks["haze"].ip.clone())
+    );
+    assert_eq!(
+        cloud.containers,
+        vec![containers[0].id.clone(), containers[1].id.clone()]
+    );
+}
+
+#[tokio::test]
+async fn test_create_labels() {
+    let (_dir, config, fake, docker) = test_env();
+
+    Cloud::create(&docker, test_options("test", Database::Postgres), &config)
+        .await
+        .unwrap();
+
+    let db_labels = fake
+        .container("haze-test-db")
+        .unwrap()
+        .config
+        .labels
+        .unwrap();
+    assert_eq!(db_labels["haze-type"], "db");
+    assert_eq!(db_labels["haze-cloud-id"], "haze-test");
+
+    let labels = fake.container("haze-test").unwrap().config.labels.unwrap();
+    assert_eq!(labels["haze-type"], "cloud");
+    assert_eq!(labels["haze-cloud-id"], "haze-test");
+    assert_eq!(labels["haze-db"], Database::Postgres.name());
+    assert_eq!(labels["haze-php"], PhpVersion::default().name());
+    assert_eq!(labels["haze-services"], "");
+}
+
+#[tokio::test]
+async fn test_create_rollback() {
+    let (_dir, config, fake, docker) = test_env();
+
+    fake.fail_start("haze-test");
+    let result = Cloud::create(&docker, test_options("test", Database::MariaDB), &config).await;
+    assert!(result.is_err());
+    assert!(fake.containers().is_empty());
+    assert_eq!(fake.networks(), vec!["haze"]);
+
+    assert!(!config.work_dir.join("haze-test").exists());
+
+    // the failed attempt doesn't block creating the instance again
+    fake.allow_start("haze-test");
+    Cloud::create(&docker, test_options("test", Database::MariaDB), &config)
+        .await
+        .unwrap();
+    assert_eq!(fake.containers().len(), 2);
+}
+
+#[tokio::test]
+async fn test_list() {
+    let (_dir, config, _, docker) = test_env();
+
+    let first = Cloud::create(&docker, test_options("first", Database::MariaDB), &config)
+        .await
+        .unwrap();
+    let second = Cloud::create(&docker, test_options("second", Database::Sqlite), &config)
+        .await
+        .unwrap();
+
+    let list = Cloud::list(&docker, None, &config).await.unwrap();
+    let ids: Vec<_> = list.iter().map(|cloud| cloud.id.as_str()).collect();
+    assert_eq!(ids, vec!["haze-second", "haze-first"]);
+    assert_eq!(list[0].ip, second.ip);
+    assert_eq!(list[0].db(), &Database::Sqlite);
+    assert_eq!(list[1].ip, first.ip);
+    assert_eq!(list[1].db(), &Database::MariaDB);
+    assert_eq!(list[1].containers, vec!["/haze-first-db", "haze-first"]);
+    assert!(!list[1].pinned);
+
+    let filtered = Cloud::list(&docker, Some("fir".into()), &config)
+        .await
+        .unwrap();
+    assert_eq!(filtered.len(), 1);
+    assert_eq!(filtered[0].id, "haze-first");
+
+    let last = Cloud::get_by_filter(&docker, None, &config).await.unwrap();
+    assert_eq!(last.id, "haze-second");
+
+    second.destroy(&docker).await.unwrap();
+    let list = Cloud::list(&docker, None, &config).await.unwrap();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].id, "haze-first");
+}
+
+#[tokio::test]
+async fn test_pin() {
+    let (_dir, config, _, docker) = test_env();
+
+    let cloud = Cloud::create(&docker, test_options("test", Database::Sqlite), &config)
+        .await
+        .unwrap();
+    assert!(!cloud.pinned);
+
+    cloud.pin(&docker).await.unwrap();
+    let listed = Cloud::get_by_filter(&docker, None, &config).await.unwrap();
+    assert!(listed.pinned);
+
+    cloud.unpin(&docker).await.unwrap();
+    let listed = Cloud::get_by_filter(&docker, None, &config).await.unwrap();
+    assert!(!listed.pinned);
+}
+
+#[tokio::test]
+async fn test_destroy() {
+    let (_dir, config, fake, docker) = test_env();
+
+    let cloud = Cloud::create(&docker, test_options("test", Database::MariaDB), &config)
+        .await
+        .unwrap();
+    let workdir = cloud.workdir.clone();
+    cloud.destroy(&docker).await.unwrap();
+
+    assert!(fake.containers().is_empty());
+    assert_eq!(fake.networks(), vec!["haze"]);
+    assert!(!workdir.exists());
+}
+
+#[tokio::test]
+async fn test_copy_from() {
+    let (_dir, config, fake, docker) = test_env();
+
+    let options = CloudOptions {
+        services: Service::from_type(&[], "sharding").unwrap(),
+        ..test_options("source", Database::MariaDB)
+    };
+    let source = Cloud::create(&docker, options, &config).await.unwrap();
+    let options = CloudOptions {
+        name: Some("target".into()),
+        ..source.options.clone()
+    };
+    let target = Cloud::create(&docker, options, &config).await.unwrap();
+    let previous = fake.execs().len();
+    target.copy_from(&docker, &source).await.unwrap();
+
+    let containers: Vec<_> = fake
+        .execs()
+        .into_iter()
+        .skip(previous)
+        .map(|exec| exec.container)
+        .filter(|container| container.contains("-db"))
+        .collect();
+    assert_eq!(
+        containers,
+        [
+            "haze-source-db",
+            "haze-target-db",
+            "haze-source-db-1",
+            "haze-target-db-1",
+            "haze-source-db-2",
+            "haze-target-db-2",
+            "haze-source-db-3",
+            "haze-target-db-3",
+            "haze-source-db-4",
+            "haze-target-db-4",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_set_trusted_proxy() {
+    let (_dir, config, fake, docker) = test_env();
+    let cloud = Cloud::create(&docker, test_options("test", Database::Sqlite), &config)
+        .await
+        .unwrap();
+    let previous = fake.execs().len();
+    cloud.set_trusted_proxy(&docker).await.unwrap();
+
+    let gateway = network_gateway(&docker, "haze").await.unwrap();
+    assert_ne!(
+        gateway,
+        network_gateway(&docker, &cloud.network).await.unwrap()
+    );
+    let commands: Vec<_> = fake
+        .execs()
+        .into_iter()
+        .skip(previous)
+        .map(|exec| exec.cmd)
+        .collect();
+    assert_eq!(
+        commands,
+        vec![vec![
+            "occ".to_string(),
+            "config:system:set".into(),
+            "trusted_proxies".into(),
+            "0".into(),
+            "--value".into(),
+            gateway,
+        ]]
+    );
+}
@@ -6,10 +6,12 @@ use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::env::var;
 use std::fs::read_to_string;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 use toml::Value;
 
-#[derive(Debug, Deserialize)]
+/// The haze configuration, loaded from `~/.config/haze/haze.toml`
+#[derive(Debug, Clone, Deserialize)]
 #[serde(from = "RawHazeConfig")]
 pub struct HazeConfig {
     pub sources_root: Utf8PathBuf,
@@ -18,7 +20,11 @@ pub struct HazeConfig {
     pub volume: Vec<HazeVolumeConfig>,
     pub blackfire: Option<HazeBlackfireConfig>,
     pub proxy: ProxyConfig,
+    pub daemon: DaemonConfig,
+    pub dns: DnsConfig,
+    pub hooks: HooksConfig,
     pub preset: Vec<Preset>,
+    pub timeouts: TimeoutConfig,
 }
 
 #[derive(Debug, Deserialize)]
@@ -35,7 +41,15 @@ pub struct RawHazeConfig {
     #[serde(default)]
     pub proxy: ProxyConfig,
     #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub dns: DnsConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
     pub preset: Vec<Preset>,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
 }
 
 impl From<RawHazeConfig> for HazeConfig {
@@ -49,19 +63,53 @@ impl From<RawHazeConfig> for HazeConfig {
             }
         }
 
+        let work_dir = normalize_path(raw.work_dir);
+        let daemon = DaemonConfig {
+            listen: if raw.daemon.listen.is_empty() {
+                format!("{}/daemon.sock", work_dir)
+            } else {
+                raw.daemon.listen
+            },
+            token: raw.daemon.token,
+        };
+
+        let mut dns = raw.dns;
+        if dns.domain.is_empty() {
+            dns.domain = "haze.local".into();
+        }
+
+        let mut proxy = raw.proxy;
+        // instances behind the builtin tls termination are reached over https
+        proxy.https |= proxy.tls_enabled();
+        // a notify_push running on the host was always reachable as `host-push`
+        if !proxy
+            .forward
+            .iter()
+            .any(|forward| forward.name == "host-push")
+        {
+            proxy.forward.push(ProxyForward {
+                name: "host-push".into(),
+                target: SocketAddr::from(([127, 0, 0, 1], 7867)),
+            });
+        }
+
         HazeConfig {
             sources_root: normalize_path(raw.sources_root),
-            work_dir: normalize_path(raw.work_dir),
+            work_dir,
             auto_setup: raw.auto_setup,
             volume: raw.volume,
             blackfire: raw.blackfire,
-            proxy: raw.proxy,
+            proxy,
+            daemon,
+            dns,
+            hooks: raw.hooks,
             preset: raw.preset,
+            timeouts: raw.timeouts,
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct HazeAutoSetupConfig {
     pub enabled: bool,
     #[serde(default = "default_auto_setup_username")]
@@ -83,6 +131,41 @@ impl Default for HazeAutoSetupConfig {
     }
 }
 
+/// Timeouts in seconds for waiting on containers to become healthy
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct TimeoutConfig {
+    /// Used for all checks that don't have a more specific timeout configured
+    #[serde(default)]
+    pub default: Option<u64>,
+    #[serde(default)]
+    pub php: Option<u64>,
+    #[serde(default)]
+    pub db: Option<u64>,
+    /// Timeouts by service name
+    #[serde(default)]
+    pub services: HashMap<String, u64>,
+}
+
+impl TimeoutConfig {
+    pub fn php(&self) -> Duration {
+        Duration::from_secs(self.php.or(self.default).unwrap_or(15))
+    }
+
+    pub fn db(&self, builtin: u64) -> Duration {
+        Duration::from_secs(self.db.or(self.default).unwrap_or(builtin))
+    }
+
+    pub fn service(&self, name: &str) -> Duration {
+        Duration::from_secs(
+            self.services
+                .get(name)
+                .copied()
+                .or(self.default)
+                .unwrap_or(30),
+        )
+    }
+}
+
 fn default_work_dir() -> Utf8PathBuf {
     "/tmp/haze".into()
 }
@@ -95,7 +178,7 @@ fn default_auto_setup_password() -> String {
     "admin".to_string()
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct HazeVolumeConfig {
     pub source: Utf8PathBuf,
     pub target: Utf8PathBuf,
@@ -105,7 +188,7 @@ pub struct HazeVolumeConfig {
     pub create: bool,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(try_from = "RawHazeBlackfireConfig")]
 pub struct HazeBlackfireConfig {
     pub server_id: String,
@@ -158,33 +241,116 @@ fn load_secret(name: &str, path: Option<String>, raw: Option<String>) -> Result<
     }
 }
 
-#[derive(Default, Deserialize, Debug)]
+#[derive(Default, Clone, Deserialize, Debug)]
 pub struct ProxyConfig {
     pub listen: String,
     #[serde(default)]
     pub address: String,
     #[serde(default)]
     pub https: bool,
+    /// Socket address to serve https on, using certificates from a local CA
+    #[serde(default)]
+    pub tls_listen: String,
+    #[serde(default)]
+    pub routing: ProxyRouting,
+    /// Services running on the host that are made available through the proxy
+    #[serde(default)]
+    pub forward: Vec<ProxyForward>,
+}
+
+/// A service running on the host, like a frontend dev server, that is made available through the proxy
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct ProxyForward {
+    /// Subdomain, or path name with path based routing, to serve the service on
+    pub name: String,
+    /// Address and port on the host the requests are forwarded to
+    pub target: SocketAddr,
+}
+
+/// How the proxy determines which instance a request is for
+#[derive(Default, Clone, Copy, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyRouting {
+    /// Instances are served on a subdomain of the proxy address, requires wildcard DNS
+    #[default]
+    Subdomain,
+    /// Instances are served under `/i/<name>/` on the proxy address
+    Path,
+}
+
+/// Path prefix used to route to instances with [`ProxyRouting::Path`]
+pub const PROXY_PATH_PREFIX: &str = "/i/";
+
+/// The name an instance or service container is reachable by through the proxy, the container name without `haze-`
+pub fn route_name(container: &str) -> &str {
+    container.strip_prefix("haze-").unwrap_or(container)
+}
+
+#[derive(Default, Clone, Deserialize, Debug)]
+pub struct DaemonConfig {
+    /// Unix socket path or socket address to serve the json api on, defaults to `daemon.sock` in the work directory
+    #[serde(default)]
+    pub listen: String,
+    /// Bearer token required for all api requests, mandatory when listening on a socket address
+    #[serde(default)]
+    pub token: String,
+}
+
+#[derive(Default, Clone, Deserialize, Debug)]
+pub struct DnsConfig {
+    /// Socket address to answer dns queries for the instances on, disabled when empty
+    #[serde(default)]
+    pub listen: String,
+    /// Domain the instances are resolvable under, defaults to `haze.local`
+    #[serde(default)]
+    pub domain: String,
+    /// Dns server to forward other queries to, defaults to the first nameserver from `/etc/resolv.conf`
+    #[serde(default)]
+    pub upstream: String,
+}
+
+/// Host commands to run on instance lifecycle events
+///
+/// Commands are run with `sh -c` and have the instance details set as environment variables.
+#[derive(Default, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct HooksConfig {
+    #[serde(default)]
+    pub pre_create: Vec<String>,
+    #[serde(default)]
+    pub post_create: Vec<String>,
+    #[serde(default)]
+    pub post_install: Vec<String>,
+    #[serde(default)]
+    pub pre_destroy: Vec<String>,
 }
 
 impl ProxyConfig {
+    pub fn tls_enabled(&self) -> bool {
+        !self.tls_listen.is_empty()
+    }
+
     /// Get a public address for a service, either with direct ip or through the proxy
     pub fn addr(&self, id: &str, ip: IpAddr) -> String {
-        let clean_id = id.strip_prefix("haze-").unwrap_or(id);
-        match (&self.address, self.https) {
-            (public, true) if !public.is_empty() => format!("https://{clean_id}.{public}"),
-            (public, false) if !public.is_empty() => format!("http://{clean_id}.{public}"),
-            _ => format!("http://{ip}"),
-        }
+        self.proxied_addr(id)
+            .unwrap_or_else(|| format!("http://{ip}"))
     }
 
     pub fn addr_with_port(&self, id: &str, ip: IpAddr, port: u16) -> String {
-        let clean_id = id.strip_prefix("haze-").unwrap_or(id);
-        match (&self.address, self.https) {
-            (public, true) if !public.is_empty() => format!("https://{clean_id}.{public}"),
-            (public, false) if !public.is_empty() => format!("http://{clean_id}.{public}"),
-            _ => format!("http://{ip}:{port}"),
+        self.proxied_addr(id)
+            .unwrap_or_else(|| format!("http://{ip}:{port}"))
+    }
+
+    fn proxied_addr(&self, id: &str) -> Option<String> {
+        if self.address.is_empty() {
+            return None;
         }
+        let clean_id = route_name(id);
+        let scheme = if self.https { "https" } else { "http" };
+        let public = &self.address;
+        Some(match self.routing {
+            ProxyRouting::Subdomain => format!("{scheme}://{clean_id}.{public}"),
+            ProxyRouting::Path => format!("{scheme}://{public}{PROXY_PATH_PREFIX}{clean_id}"),
+        })
     }
 }
 
@@ -207,7 +373,7 @@ impl HazeConfig {
     }
 }
 
-#[derive(Default, Deserialize, Debug)]
+#[derive(Default, Clone, Deserialize, Debug)]
 pub struct Preset {
     pub name: String,
     #[serde(default)]
@@ -216,4 +382,60 @@ pub struct Preset {
     pub apps: Vec<String>,
     #[serde(default)]
     pub commands: Vec<String>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+}
+
+#[test]
+fn test_proxy_addr() {
+    let ip = IpAddr::from([172, 18, 0, 2]);
+    let mut proxy = ProxyConfig::default();
+    assert_eq!("http://172.18.0.2", proxy.addr("haze-foo", ip));
+    assert_eq!(
+        "http://172.18.0.2:8080",
+        proxy.addr_with_port("haze-foo-office", ip, 8080)
+    );
+
+    proxy.address = "haze.example.com".into();
+    proxy.https = true;
+    assert_eq!("https://foo.haze.example.com", proxy.addr("haze-foo", ip));
+
+    proxy.routing = ProxyRouting::Path;
+    assert_eq!("https://haze.example.com/i/foo", proxy.addr("haze-foo", ip));
+    assert_eq!(
+        "https://haze.example.com/i/foo-office",
+        proxy.addr_with_port("haze-foo-office", ip, 8080)
+    );
+}
+
+#[test]
+fn test_default_forward() {
+    let host_push = |config: &str| {
+        let config: HazeConfig = toml::from_str(config).unwrap();
+        config
+            .proxy
+            .forward
+            .into_iter()
+            .filter(|forward| forward.name == "host-push")
+            .map(|forward| forward.target)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        host_push(r#"sources_root = "/srv/sources""#),
+        vec![SocketAddr::from(([127, 0, 0, 1], 7867))]
+    );
+    assert_eq!(
+        host_push(
+            r#"
+            sources_root = "/srv/sources"
+            [proxy]
+            listen = "127.0.0.1:8080"
+            [[proxy.forward]]
+            name = "host-push"
+            target = "127.0.0.1:7868"
+            "#
+        ),
+        vec![SocketAddr::from(([127, 0, 0, 1], 7868))]
+    );
 }
@@ -0,0 +1,213 @@
+use crate::docker::Docker;
+use crate::exec::exec;
+use bollard::container::{DownloadFromContainerOptions, UploadToContainerOptions};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
+use futures_util::StreamExt;
+use miette::{IntoDiagnostic, Report, Result, WrapErr};
+use std::fs::{self, File};
+use std::io;
+use std::path::Component;
+use tar::{Archive, Builder, EntryType, Header};
+
+/// Uid and gid to assign to uploaded files
+#[derive(Debug, Clone, Copy)]
+pub struct Owner {
+    pub uid: u64,
+    pub gid: u64,
+}
+
+/// Copy a file or directory from the host into a container
+///
+/// If `target` ends with a `/` or is an existing directory the source is copied into that directory, otherwise
+/// the source is copied to `target`.
+pub async fn upload(
+    docker: &Docker,
+    container: &str,
+    source: &Utf8Path,
+    target: &str,
+    owner: Owner,
+) -> Result<()> {
+    let source_name = source
+        .file_name()
+        .ok_or_else(|| Report::msg(format!("Invalid source path {}", source)))?;
+    let target = if !target.ends_with('/') && is_dir(docker, container, target).await {
+        format!("{}/", target)
+    } else {
+        target.to_string()
+    };
+    let (parent, name) = split_target(&target, source_name);
+    let mut builder = Builder::new(Vec::new());
+    append_path(&mut builder, source, name, owner)
+        .wrap_err_with(|| format!("Failed to read {}", source))?;
+    let archive = builder.into_inner().into_diagnostic()?;
+    upload_archive(docker, container, parent, archive).await
+}
+
+/// Write data to a file in a container
+pub async fn upload_data(
+    docker: &Docker,
+    container: &str,
+    target: &str,
+    data: &[u8],
+    owner: Owner,
+) -> Result<()> {
+    let (parent, name) = split_target(target, "");
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_uid(owner.uid);
+    header.set_gid(owner.gid);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    let mut builder = Builder::new(Vec::new());
+    builder
+        .append_data(&mut header, name, data)
+        .into_diagnostic()?;
+    let archive = builder.into_inner().into_diagnostic()?;
+    upload_archive(docker, container, parent, archive).await
+}
+
+/// Copy a file or directory from a container to the host
+///
+/// If `target` is an existing directory the source is copied into that directory, otherwise the source is copied to `target`.
+pub async fn download(
+    docker: &Docker,
+    container: &str,
+    source: &str,
+    target: &Utf8Path,
+) -> Result<()> {
+    let mut stream = docker.download_from_container(
+        container,
+        Some(DownloadFromContainerOptions {
+            path: source.to_string(),
+        }),
+    );
+    let mut archive = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to download {} from {}", source, container))?;
+        archive.extend_from_slice(&chunk);
+    }
+
+    let into_dir = target.is_dir();
+    let mut archive = Archive::new(archive.as_slice());
+    for entry in archive.entries().into_diagnostic()? {
+        let mut entry = entry.into_diagnostic()?;
+        let path = entry.path().into_diagnostic()?.into_owned();
+        let mut components = path.components();
+        let Some(Component::Normal(root_name)) = components.next() else {
+            continue;
+        };
+        if components
+            .clone()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            continue;
+        }
+
+        let root = if into_dir {
+            target.as_std_path().join(root_name)
+        } else {
+            target.as_std_path().to_path_buf()
+        };
+        let destination = root.join(components.as_path());
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).into_diagnostic()?;
+        }
+        entry
+            .unpack(&destination)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {}", destination.display()))?;
+    }
+    Ok(())
+}
+
+async fn upload_archive(
+    docker: &Docker,
+    container: &str,
+    path: &str,
+    archive: Vec<u8>,
+) -> Result<()> {
+    docker
+        .upload_to_container(
+            container,
+            Some(UploadToContainerOptions {
+                path: path.to_string(),
+                ..Default::default()
+            }),
+            archive.into(),
+        )
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to upload to {} in {}", path, container))
+}
+
+/// Check if a path in a container is an existing directory
+async fn is_dir(docker: &Docker, container: &str, path: &str) -> bool {
+    let mut output = Vec::new();
+    let result = exec(
+        docker,
+        container,
+        "root",
+        vec!["sh", "-c", "[ -d \"$1\" ] && echo dir", "sh", path],
+        Vec::<String>::new(),
+        Some(&mut output),
+    )
+    .await;
+    result.is_ok() && output == b"dir\n"
+}
+
+/// Split the target path into the directory to upload the archive to and the name of the uploaded file
+fn split_target<'a>(target: &'a str, source_name: &'a str) -> (&'a str, &'a str) {
+    if target.ends_with('/') {
+        (target, source_name)
+    } else {
+        match target.rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((parent, name)) => (parent, name),
+            None => ("/", target),
+        }
+    }
+}
+
+fn append_path(
+    builder: &mut Builder<Vec<u8>>,
+    path: &Utf8Path,
+    archive_path: &str,
+    owner: Owner,
+) -> Result<()> {
+    let meta = fs::symlink_metadata(path).into_diagnostic()?;
+    let mut header = Header::new_gnu();
+    header.set_metadata(&meta);
+    header.set_uid(owner.uid);
+    header.set_gid(owner.gid);
+
+    if meta.is_symlink() {
+        let link = fs::read_link(path).into_diagnostic()?;
+        builder
+            .append_link(&mut header, archive_path, link)
+            .into_diagnostic()?;
+    } else if meta.is_dir() {
+        header.set_entry_type(EntryType::Directory);
+        builder
+            .append_data(&mut header, archive_path, io::empty())
+            .into_diagnostic()?;
+        for child in path.read_dir_utf8().into_diagnostic()? {
+            let child = child.into_diagnostic()?;
+            let child_path: Utf8PathBuf = child.path().into();
+            append_path(
+                builder,
+                &child_path,
+                &format!("{}/{}", archive_path, child.file_name()),
+                owner,
+            )?;
+        }
+    } else {
+        let file = File::open(path).into_diagnostic()?;
+        builder
+            .append_data(&mut header, archive_path, file)
+            .into_diagnostic()?;
+    }
+    Ok(())
+}
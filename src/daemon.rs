@@ -0,0 +1,568 @@
+use crate::docker::Docker;
+use crate::exec::exec_streamed;
+use crate::info::CloudInfo;
+use crate::proxy::{is_socket_address, listen_and_serve};
+use crate::service::{Service, ServiceTrait};
+use crate::status::ComponentStatus;
+use crate::{setup, Cloud, CloudOptions, Database, HazeConfig, PhpVersion, Result};
+use axum::body::Body;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::{from_fn_with_state, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use bollard::container::{LogOutput, LogsOptions};
+use camino::Utf8PathBuf;
+use futures_util::stream::{unfold, BoxStream};
+use futures_util::StreamExt;
+use itertools::Itertools;
+use miette::Report;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::spawn;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+struct DaemonState {
+    docker: Docker,
+    config: HazeConfig,
+}
+
+type AppState = State<Arc<DaemonState>>;
+
+impl DaemonState {
+    async fn cloud(&self, filter: &str) -> Result<Cloud, ApiError> {
+        Cloud::list(&self.docker, Some(filter.into()), &self.config)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ApiError::not_found(format!("No clouds running matching {filter}")))
+    }
+}
+
+/// Serve the json api on the configured socket until ctrl-c is pressed
+pub async fn daemon(docker: Docker, config: HazeConfig) -> Result<()> {
+    let listen = config.daemon.listen.clone();
+    // anyone that can connect to a tcp socket, including other users on the same machine, could run commands in the instances
+    if is_socket_address(&listen) && config.daemon.token.is_empty() {
+        return Err(Report::msg(format!(
+            "Refusing to serve the daemon api on {listen} without a token, set `daemon.token` in the config"
+        )));
+    }
+    let app = router(Arc::new(DaemonState { docker, config }));
+
+    // unix sockets are only accessible by the current user, tcp sockets are protected by the token
+    listen_and_serve(app, &listen, 0o600).await
+}
+
+fn router(state: Arc<DaemonState>) -> Router {
+    Router::new()
+        .route("/instances", get(list).post(start))
+        .route("/instances/{filter}", get(info).delete(stop))
+        .route("/instances/{filter}/pin", post(pin).delete(unpin))
+        .route("/instances/{filter}/status", get(status))
+        .route("/instances/{filter}/logs", get(logs))
+        .route("/instances/{filter}/exec", post(exec))
+        .route("/instances/{filter}/occ", post(occ))
+        .layer(from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+/// Reject requests without the configured bearer token, if one is set
+async fn authorize(State(state): AppState, request: Request, next: Next) -> Response {
+    let token = &state.config.daemon.token;
+    if !token.is_empty() && !is_authorized(request.headers(), token) {
+        return ApiError {
+            status: StatusCode::UNAUTHORIZED,
+            message: "Missing or invalid bearer token".into(),
+        }
+        .into_response();
+    }
+    next.run(request).await
+}
+
+fn is_authorized(headers: &HeaderMap, token: &str) -> bool {
+    let Some(provided) = headers
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // compare in constant time to not leak the token through response timings
+    provided.len() == token.len()
+        && provided
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn not_found(message: String) -> Self {
+        ApiError {
+            status: StatusCode::NOT_FOUND,
+            message,
+        }
+    }
+
+    fn bad_request(message: String) -> Self {
+        ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message,
+        }
+    }
+}
+
+impl From<Report> for ApiError {
+    fn from(error: Report) -> Self {
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: error.chain().join(": "),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct ErrorBody {
+            error: String,
+        }
+
+        (
+            self.status,
+            Json(ErrorBody {
+                error: self.message,
+            }),
+        )
+            .into_response()
+    }
+}
+
+/// A single line of streamed output
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum OutputEvent {
+    Stdout(String),
+    Stderr(String),
+    ExitCode(i64),
+    Error(String),
+}
+
+impl From<LogOutput> for OutputEvent {
+    fn from(output: LogOutput) -> Self {
+        match output {
+            LogOutput::StdErr { message } => {
+                OutputEvent::Stderr(String::from_utf8_lossy(&message).into())
+            }
+            output => OutputEvent::Stdout(output.to_string()),
+        }
+    }
+}
+
+/// Stream events as newline delimited json
+fn ndjson(events: BoxStream<'static, OutputEvent>) -> Response {
+    let lines = events.map(|event| {
+        let mut line = serde_json::to_vec(&event).unwrap_or_default();
+        line.push(b'\n');
+        Ok::<_, Infallible>(line)
+    });
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+fn receiver_stream(receiver: UnboundedReceiver<OutputEvent>) -> BoxStream<'static, OutputEvent> {
+    unfold(receiver, |mut receiver| async move {
+        let event = receiver.recv().await?;
+        Some((event, receiver))
+    })
+    .boxed()
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    filter: Option<String>,
+}
+
+async fn list(
+    State(state): AppState,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Vec<CloudInfo>>, ApiError> {
+    let clouds = Cloud::list(&state.docker, query.filter, &state.config).await?;
+    let mut infos = Vec::with_capacity(clouds.len());
+    for cloud in &clouds {
+        let mut info = CloudInfo::new(cloud);
+        info.check_health(&state.docker, cloud).await;
+        infos.push(info);
+    }
+    Ok(Json(infos))
+}
+
+/// Options for a new instance, same as the arguments for `haze start`
+#[derive(Deserialize)]
+struct StartRequest {
+    name: Option<String>,
+    db: Option<String>,
+    php: Option<String>,
+    #[serde(default)]
+    services: Vec<String>,
+    #[serde(default)]
+    app_packages: Vec<Utf8PathBuf>,
+}
+
+impl StartRequest {
+    fn into_options(self, config: &HazeConfig) -> Result<CloudOptions, ApiError> {
+        let db = self
+            .db
+            .map(|db| Database::from_str(&db))
+            .transpose()
+            .map_err(|_| ApiError::bad_request("Unknown database".into()))?;
+        let php = self
+            .php
+            .map(|php| PhpVersion::from_str(&php))
+            .transpose()
+            .map_err(|_| ApiError::bad_request("Unknown php version".into()))?;
+        let mut services = Vec::new();
+        for service in &self.services {
+            services.extend(
+                Service::from_type(&config.preset, service)
+                    .ok_or_else(|| ApiError::bad_request(format!("Unknown service {service}")))?,
+            );
+        }
+        Ok(CloudOptions {
+            name: self.name,
+            db: db.unwrap_or_default(),
+            php: php.unwrap_or_default(),
+            services,
+            app_packages: self.app_packages,
+        })
+    }
+}
+
+async fn start(
+    State(state): AppState,
+    Json(request): Json<StartRequest>,
+) -> Result<(StatusCode, Json<CloudInfo>), ApiError> {
+    let options = request.into_options(&state.config)?;
+    let cloud = setup(&state.docker, options, &state.config).await?;
+    Ok((StatusCode::CREATED, Json(CloudInfo::new(&cloud))))
+}
+
+async fn info(
+    State(state): AppState,
+    Path(filter): Path<String>,
+) -> Result<Json<CloudInfo>, ApiError> {
+    let cloud = state.cloud(&filter).await?;
+    let mut info = CloudInfo::new(&cloud);
+    info.check_health(&state.docker, &cloud).await;
+    info.add_details(&state.docker, &cloud, &state.config).await;
+    Ok(Json(info))
+}
+
+async fn stop(State(state): AppState, Path(filter): Path<String>) -> Result<StatusCode, ApiError> {
+    state.cloud(&filter).await?.destroy(&state.docker).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn pin(State(state): AppState, Path(filter): Path<String>) -> Result<StatusCode, ApiError> {
+    state.cloud(&filter).await?.pin(&state.docker).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unpin(State(state): AppState, Path(filter): Path<String>) -> Result<StatusCode, ApiError> {
+    state.cloud(&filter).await?.unpin(&state.docker).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn status(
+    State(state): AppState,
+    Path(filter): Path<String>,
+) -> Result<Json<Vec<ComponentStatus>>, ApiError> {
+    let cloud = state.cloud(&filter).await?;
+    Ok(Json(cloud.status(&state.docker).await))
+}
+
+/// Get the container for a service name, or the instance itself when no service is given
+fn service_container(cloud: &Cloud, service: Option<&str>) -> Result<String, ApiError> {
+    match service {
+        None => Ok(cloud.id.clone()),
+        Some("db") => cloud
+            .db()
+            .container_name(&cloud.id)
+            .ok_or_else(|| ApiError::bad_request("Database doesn't have a container".into())),
+        Some(name) => cloud
+            .services()
+            .find(|service| service.name() == name)
+            .ok_or_else(|| ApiError::not_found(format!("Instance has no service {name}")))?
+            .container_name(&cloud.id)
+            .ok_or_else(|| ApiError::bad_request(format!("{name} doesn't have a container"))),
+    }
+}
+
+#[derive(Deserialize)]
+struct LogsQuery {
+    count: Option<usize>,
+    #[serde(default)]
+    follow: bool,
+    service: Option<String>,
+}
+
+async fn logs(
+    State(state): AppState,
+    Path(filter): Path<String>,
+    Query(query): Query<LogsQuery>,
+) -> Result<Response, ApiError> {
+    let cloud = state.cloud(&filter).await?;
+    let container = service_container(&cloud, query.service.as_deref())?;
+    let logs = state.docker.logs(
+        &container,
+        Some(LogsOptions {
+            stdout: true,
+            stderr: true,
+            follow: query.follow,
+            tail: format!("{}", query.count.unwrap_or(20)),
+            ..Default::default()
+        }),
+    );
+    Ok(ndjson(
+        logs.map(|chunk| match chunk {
+            Ok(chunk) => chunk.into(),
+            Err(e) => OutputEvent::Error(e.to_string()),
+        })
+        .boxed(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ExecRequest {
+    cmd: Vec<String>,
+    service: Option<String>,
+    user: Option<String>,
+    #[serde(default)]
+    env: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct OccRequest {
+    args: Vec<String>,
+}
+
+async fn exec(
+    State(state): AppState,
+    Path(filter): Path<String>,
+    Json(request): Json<ExecRequest>,
+) -> Result<Response, ApiError> {
+    let cloud = state.cloud(&filter).await?;
+    let container = service_container(&cloud, request.service.as_deref())?;
+    // same default users as `haze exec`
+    let user = request.user.unwrap_or_else(|| {
+        match request.service.as_deref() {
+            None => "haze",
+            Some("db") => "root",
+            Some(_) => "",
+        }
+        .into()
+    });
+    Ok(streamed_exec(
+        state.docker.clone(),
+        container,
+        user,
+        request.cmd,
+        request.env,
+    ))
+}
+
+async fn occ(
+    State(state): AppState,
+    Path(filter): Path<String>,
+    Json(request): Json<OccRequest>,
+) -> Result<Response, ApiError> {
+    let cloud = state.cloud(&filter).await?;
+    let mut cmd = request.args;
+    cmd.insert(0, "occ".into());
+    Ok(streamed_exec(
+        state.docker.clone(),
+        cloud.id,
+        "haze".into(),
+        cmd,
+        Vec::new(),
+    ))
+}
+
+/// Run the command in the background and stream its output and exit code
+fn streamed_exec(
+    docker: Docker,
+    container: String,
+    user: String,
+    cmd: Vec<String>,
+    env: Vec<String>,
+) -> Response {
+    let (sender, receiver) = unbounded_channel();
+    spawn(async move {
+        let result = exec_streamed(&docker, container, &user, cmd, env, |chunk| {
+            sender.send(chunk.into()).ok();
+        })
+        .await;
+        let event = match result {
+            Ok(code) => OutputEvent::ExitCode(code.code()),
+            Err(e) => OutputEvent::Error(e.chain().join(": ")),
+        };
+        sender.send(event).ok();
+    });
+    ndjson(receiver_stream(receiver))
+}
+
+#[test]
+fn test_is_authorized() {
+    let headers = |value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, value.parse().unwrap());
+        headers
+    };
+
+    assert!(is_authorized(&headers("Bearer secret"), "secret"));
+    assert!(!is_authorized(&headers("Bearer secreT"), "secret"));
+    assert!(!is_authorized(&headers("Bearer secret2"), "secret"));
+    assert!(!is_authorized(&headers("secret"), "secret"));
+    assert!(!is_authorized(&HeaderMap::new(), "secret"));
+}
+
+#[cfg(test)]
+async fn send(
+    app: &Router,
+    method: &str,
+    uri: &str,
+    body: Option<serde_json::Value>,
+) -> (StatusCode, Vec<u8>) {
+    use tower::ServiceExt;
+
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(AUTHORIZATION, "Bearer secret")
+        .header(CONTENT_TYPE, "application/json");
+    let request = match body {
+        Some(body) => request.body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    };
+    let response = app.clone().oneshot(request.unwrap()).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, body.to_vec())
+}
+
+#[cfg(test)]
+fn json(body: &[u8]) -> serde_json::Value {
+    serde_json::from_slice(body).unwrap()
+}
+
+#[tokio::test]
+async fn test_api() {
+    use serde_json::json;
+
+    let (_dir, mut config, fake, docker) = crate::cloud::test_env();
+    config.daemon.token = "secret".into();
+    let app = router(Arc::new(DaemonState { docker, config }));
+
+    let (status, body) = send(
+        &app,
+        "POST",
+        "/instances",
+        Some(json!({"name": "test", "db": "mariadb"})),
+    )
+    .await;
+    assert_eq!(StatusCode::CREATED, status);
+    assert_eq!("haze-test", json(&body)["id"]);
+
+    let (status, body) = send(&app, "GET", "/instances", None).await;
+    assert_eq!(StatusCode::OK, status);
+    let list = json(&body);
+    assert_eq!(1, list.as_array().unwrap().len());
+    assert_eq!("haze-test", list[0]["id"]);
+    assert_eq!(false, list[0]["pinned"]);
+
+    let (status, _) = send(&app, "POST", "/instances/test/pin", None).await;
+    assert_eq!(StatusCode::NO_CONTENT, status);
+    let (_, body) = send(&app, "GET", "/instances", None).await;
+    assert_eq!(true, json(&body)[0]["pinned"]);
+
+    let (status, body) = send(&app, "GET", "/instances/test/status", None).await;
+    assert_eq!(StatusCode::OK, status);
+    assert!(json(&body).is_array());
+
+    // the exit code is the last event of the stream
+    let (status, body) = send(
+        &app,
+        "POST",
+        "/instances/test/occ",
+        Some(json!({"args": ["status"]})),
+    )
+    .await;
+    assert_eq!(StatusCode::OK, status);
+    let events: Vec<serde_json::Value> = body
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(json)
+        .collect();
+    assert_eq!(Some(&json!({"exit_code": 0})), events.last());
+    let exec = fake.execs().pop().unwrap();
+    assert_eq!(
+        ("haze-test", "haze"),
+        (exec.container.as_str(), exec.user.as_str())
+    );
+    assert_eq!(vec!["occ", "status"], exec.cmd);
+
+    let (status, body) = send(
+        &app,
+        "POST",
+        "/instances/test/exec",
+        Some(json!({"cmd": ["ls", "-l"], "service": "db"})),
+    )
+    .await;
+    assert_eq!(StatusCode::OK, status);
+    assert!(body.ends_with(b"{\"exit_code\":0}\n"));
+    let exec = fake.execs().pop().unwrap();
+    assert_eq!("root", exec.user);
+    assert_eq!(vec!["ls", "-l"], exec.cmd);
+
+    let (status, _) = send(&app, "DELETE", "/instances/test", None).await;
+    assert_eq!(StatusCode::NO_CONTENT, status);
+    let (_, body) = send(&app, "GET", "/instances", None).await;
+    assert_eq!(json!([]), json(&body));
+
+    let (status, _) = send(&app, "GET", "/instances/test/status", None).await;
+    assert_eq!(StatusCode::NOT_FOUND, status);
+}
+
+#[tokio::test]
+async fn test_api_requires_token() {
+    use tower::ServiceExt;
+
+    let (_dir, mut config, _fake, docker) = crate::cloud::test_env();
+    config.daemon.token = "secret".into();
+    let app = router(Arc::new(DaemonState { docker, config }));
+
+    let request = Request::builder()
+        .uri("/instances")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+    let (status, _) = send(&app, "GET", "/instances", None).await;
+    assert_eq!(StatusCode::OK, status);
+}
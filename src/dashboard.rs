@@ -0,0 +1,249 @@
+use crate::docker::Docker;
+use crate::info::CloudInfo;
+use crate::{Cloud, HazeConfig, Result};
+use axum::extract::Request;
+use axum::http::header::{HOST, ORIGIN};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use itertools::Itertools;
+use std::fmt::Write;
+use tracing::error;
+
+/// Subdomain of the proxy address the dashboard is served on
+pub const DASHBOARD_SUBDOMAIN: &str = "dashboard";
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 60em; color: #222; }
+.instance { border: 1px solid #ccc; border-radius: 4px; padding: 0.5em 1em; margin-bottom: 1em; }
+.instance h2 { font-size: 1.2em; }
+.healthy { color: #2a7d2a; }
+.unhealthy { color: #b52a2a; }
+.stopped, .unknown { color: #777; }
+form { display: inline; }
+pre { white-space: pre-wrap; margin: 0; font-size: 0.9em; }
+";
+
+/// Handle a request to the dashboard
+///
+/// `GET /` lists the instances, `POST /instances/<id>/<action>` stops, pins or unpins an instance.
+pub async fn dashboard(docker: &Docker, config: &HazeConfig, req: Request) -> Response {
+    let path = req.uri().path().trim_matches('/').to_string();
+    let parts: Vec<&str> = path.split('/').collect();
+    let result = match (req.method(), parts.as_slice()) {
+        (&Method::GET, [""]) => overview(docker, config)
+            .await
+            .map(IntoResponse::into_response),
+        (&Method::POST, ["instances", id, action]) => {
+            if !is_same_origin(req.headers()) {
+                return StatusCode::FORBIDDEN.into_response();
+            }
+            instance_action(docker, config, id, action).await
+        }
+        _ => Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    result.unwrap_or_else(|e| {
+        error!(error = ?e, "dashboard request failed");
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)).into_response()
+    })
+}
+
+/// Check that a request was submitted from the dashboard itself and not from a form on another site
+///
+/// Requests without `Sec-Fetch-Site` or `Origin` header are not sent by browsers and can't be forged by other sites.
+fn is_same_origin(headers: &HeaderMap) -> bool {
+    if let Some(site) = headers.get("sec-fetch-site") {
+        return matches!(site.as_bytes(), b"same-origin" | b"none");
+    }
+    match headers.get(ORIGIN) {
+        Some(origin) => {
+            let origin_host = origin
+                .to_str()
+                .ok()
+                .and_then(|origin| origin.split_once("://"))
+                .map(|(_, host)| host);
+            origin_host.is_some()
+                && origin_host == headers.get(HOST).and_then(|host| host.to_str().ok())
+        }
+        None => true,
+    }
+}
+
+async fn instance_action(
+    docker: &Docker,
+    config: &HazeConfig,
+    id: &str,
+    action: &str,
+) -> Result<Response> {
+    let Some(cloud) = Cloud::list(docker, Some(id.into()), config)
+        .await?
+        .into_iter()
+        .find(|cloud| cloud.id == id)
+    else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    match action {
+        "stop" => cloud.destroy(docker).await?,
+        "pin" => cloud.pin(docker).await?,
+        "unpin" => cloud.unpin(docker).await?,
+        _ => return Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+    Ok(Redirect::to("/").into_response())
+}
+
+async fn overview(docker: &Docker, config: &HazeConfig) -> Result<Html<String>> {
+    let clouds = Cloud::list(docker, None, config).await?;
+    let mut body = String::new();
+    if clouds.is_empty() {
+        body.push_str("<p>No instances running</p>");
+    }
+    for cloud in &clouds {
+        let mut info = CloudInfo::new(cloud);
+        info.check_health(docker, cloud).await;
+        if cloud.ip.is_some() {
+            info.add_details(docker, cloud, config).await;
+        }
+        render_instance(&mut body, &info);
+    }
+
+    Ok(Html(format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>haze</title><style>{STYLE}</style></head>\
+        <body><h1>haze</h1>{body}</body></html>"
+    )))
+}
+
+fn render_instance(out: &mut String, info: &CloudInfo) {
+    let id = escape(&info.id);
+    let status = info.status();
+    let title = if info.ip.is_some() {
+        format!("<a href=\"{}\">{id}</a>", escape(&info.address))
+    } else {
+        id.clone()
+    };
+    let pin = if info.pinned { "unpin" } else { "pin" };
+    let _ = write!(
+        out,
+        "<div class=\"instance\"><h2>{title}{} <span class=\"{status}\">{status}</span></h2>\
+        <p>php {}, {}{}</p><ul>",
+        if info.pinned { " (pinned)" } else { "" },
+        escape(info.php),
+        escape(info.db.ty),
+        render_health(info.db.healthy),
+    );
+    if let Some(url) = info.db.credentials.as_ref().and_then(|db| db.url.as_ref()) {
+        let _ = write!(out, "<li>database: <code>{}</code></li>", escape(url));
+    }
+    for service in &info.services {
+        let name = match &service.endpoint {
+            Some(endpoint) => format!(
+                "<a href=\"{}\">{}</a>",
+                escape(endpoint),
+                escape(&service.name)
+            ),
+            None => escape(&service.name),
+        };
+        let message = service
+            .message
+            .as_deref()
+            .map(|message| format!("<pre>{}</pre>", escape(message)))
+            .unwrap_or_default();
+        let _ = write!(
+            out,
+            "<li>{name}{}{message}</li>",
+            render_health(service.healthy)
+        );
+    }
+    let _ = write!(
+        out,
+        "</ul>\
+        <form method=\"post\" action=\"/instances/{id}/{pin}\"><button>{pin}</button></form> \
+        <form method=\"post\" action=\"/instances/{id}/stop\" onsubmit=\"return confirm('Stop {id}?')\"><button>stop</button></form>\
+        </div>",
+    );
+}
+
+fn render_health(healthy: Option<bool>) -> &'static str {
+    match healthy {
+        Some(true) => " <span class=\"healthy\">healthy</span>",
+        Some(false) => " <span class=\"unhealthy\">unhealthy</span>",
+        None => "",
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".into(),
+            '<' => "&lt;".into(),
+            '>' => "&gt;".into(),
+            '"' => "&quot;".into(),
+            '\'' => "&#39;".into(),
+            c => c.to_string(),
+        })
+        .join("")
+}
+
+#[tokio::test]
+async fn test_dashboard() {
+    use crate::cloud::{test_env, test_options};
+    use crate::Database;
+    use axum::body::{to_bytes, Body};
+
+    let (_dir, config, _, docker) = test_env();
+    Cloud::create(&docker, test_options("test", Database::MariaDB), &config)
+        .await
+        .unwrap();
+
+    let request = |method: &str, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = dashboard(&docker, &config, request("GET", "/")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("haze-test"));
+    assert!(body.contains("/instances/haze-test/pin"));
+
+    let mut cross_site = request("POST", "/instances/haze-test/pin");
+    cross_site
+        .headers_mut()
+        .insert("sec-fetch-site", "cross-site".parse().unwrap());
+    let response = dashboard(&docker, &config, cross_site).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let mut other_origin = request("POST", "/instances/haze-test/pin");
+    other_origin
+        .headers_mut()
+        .insert(HOST, "dashboard.haze.example.com".parse().unwrap());
+    other_origin
+        .headers_mut()
+        .insert(ORIGIN, "https://evil.example.com".parse().unwrap());
+    let response = dashboard(&docker, &config, other_origin).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let cloud = Cloud::get_by_filter(&docker, None, &config).await.unwrap();
+    assert!(!cloud.pinned);
+
+    let mut same_origin = request("POST", "/instances/haze-test/pin");
+    same_origin
+        .headers_mut()
+        .insert(HOST, "dashboard.haze.example.com".parse().unwrap());
+    same_origin.headers_mut().insert(
+        ORIGIN,
+        "https://dashboard.haze.example.com".parse().unwrap(),
+    );
+    let response = dashboard(&docker, &config, same_origin).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let cloud = Cloud::get_by_filter(&docker, None, &config).await.unwrap();
+    assert!(cloud.pinned);
+
+    let response = dashboard(
+        &docker,
+        &config,
+        request("POST", "/instances/haze-other/stop"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
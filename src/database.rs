@@ -1,15 +1,16 @@
-use crate::exec::{exec, exec_tty, ExitCode};
+use crate::config::TimeoutConfig;
+use crate::docker::{container_ip, owned_config, Docker};
+use crate::exec::{exec, exec_io, exec_tty, ExitCode};
 use crate::image::pull_image;
+use crate::status::wait_for_healthy;
 use bollard::container::{Config, CreateContainerOptions, NetworkingConfig};
 use bollard::models::{EndpointSettings, HostConfig};
-use bollard::Docker;
 use maplit::hashmap;
 use miette::{IntoDiagnostic, Report, Result, WrapErr};
-use std::io::{stdout, Stdout};
+use std::io::{stdout, Read, Stdout, Write};
 use std::net::IpAddr;
 use std::str::FromStr;
 use std::time::Duration;
-use tokio::time::{sleep, timeout};
 
 #[derive(Eq, PartialEq)]
 pub enum DatabaseFamily {
@@ -32,6 +33,7 @@ impl DatabaseFamily {
     }
 }
 
+/// Database server used by an instance
 #[derive(Clone, Debug, Eq, PartialEq, Default)]
 #[allow(dead_code)]
 pub enum Database {
@@ -154,6 +156,33 @@ impl Database {
         }
     }
 
+    pub fn container_name(&self, cloud_id: &str) -> Option<String> {
+        match self.family() {
+            DatabaseFamily::Sqlite => None,
+            _ => Some(format!("{}-db", cloud_id)),
+        }
+    }
+
+    /// Username, password and database name used by the instance
+    pub fn credentials(&self) -> (&'static str, &'static str, &'static str) {
+        match self.family() {
+            DatabaseFamily::Oracle => ("system", "haze", "xe"),
+            _ => ("haze", "haze", "haze"),
+        }
+    }
+
+    /// Connection url for the database running on `ip`, sqlite databases don't have one
+    pub fn url(&self, ip: IpAddr) -> Option<String> {
+        let scheme = match self.family() {
+            DatabaseFamily::Mysql | DatabaseFamily::MariaDB => "mysql",
+            DatabaseFamily::Postgres => "postgresql",
+            DatabaseFamily::Oracle => "oracle",
+            DatabaseFamily::Sqlite => return None,
+        };
+        let (username, password, database) = self.credentials();
+        Some(format!("{scheme}://{username}:{password}@{ip}/{database}"))
+    }
+
     pub fn env(&self) -> Vec<&'static str> {
         match self.family() {
             DatabaseFamily::Sqlite => Vec::new(),
@@ -228,14 +257,11 @@ impl Database {
             ..Default::default()
         };
         let id = docker
-            .create_container(options, config)
+            .create_container(options, owned_config(config))
             .await
             .into_diagnostic()?
             .id;
-        docker
-            .start_container::<String>(&id, None)
-            .await
-            .into_diagnostic()?;
+        docker.start_container(&id).await.into_diagnostic()?;
         Ok(Some(id))
     }
 
@@ -243,6 +269,7 @@ impl Database {
         &self,
         docker: &Docker,
         cloud_id: &str,
+        user: &str,
         cmd: Vec<S>,
         tty: bool,
     ) -> Result<ExitCode> {
@@ -251,12 +278,12 @@ impl Database {
             _ => format!("{}-db", cloud_id),
         };
         if tty {
-            exec_tty(docker, &container, "root", cmd, Vec::<String>::default()).await
+            exec_tty(docker, &container, user, cmd, Vec::<String>::default()).await
         } else {
             exec(
                 docker,
                 &container,
-                "root",
+                user,
                 cmd,
                 Vec::<String>::default(),
                 Some(stdout()),
@@ -381,39 +408,98 @@ impl Database {
         }
     }
 
-    pub async fn wait_for_start(&self, docker: &Docker, cloud_id: &str) -> Result<()> {
-        let time = if self.family() == DatabaseFamily::Oracle {
+    /// Write an sql dump of the database contents to `output`
+    ///
+    /// For sqlite this is a no-op, since the database is stored in the data directory
+    pub async fn dump(
+        &self,
+        docker: &Docker,
+        cloud_id: &str,
+        postfix: &str,
+        output: impl Write,
+    ) -> Result<()> {
+        let cmd = match self.family() {
+            DatabaseFamily::Sqlite => return Ok(()),
+            DatabaseFamily::Mysql | DatabaseFamily::MariaDB => {
+                "mysqldump -u root -phaze haze 2>/dev/null"
+            }
+            DatabaseFamily::Postgres => "pg_dump -U haze haze 2>/dev/null",
+            DatabaseFamily::Oracle => {
+                return Err(Report::msg("Dumping oracle databases is not supported"));
+            }
+        };
+        exec(
+            docker,
+            format!("{}-db{}", cloud_id, postfix),
+            "root",
+            vec!["sh", "-c", cmd],
+            Vec::<String>::default(),
+            Some(output),
+        )
+        .await?
+        .to_result()
+        .wrap_err("Failed to dump database")
+    }
+
+    /// Load an sql dump created by [`Database::dump`] into the database
+    pub async fn restore(
+        &self,
+        docker: &Docker,
+        cloud_id: &str,
+        postfix: &str,
+        input: impl Read,
+    ) -> Result<()> {
+        let cmd = match self.family() {
+            DatabaseFamily::Sqlite => return Ok(()),
+            DatabaseFamily::Mysql | DatabaseFamily::MariaDB => {
+                vec!["mysql", "-u", "root", "-phaze", "haze"]
+            }
+            DatabaseFamily::Postgres => vec!["psql", "-U", "haze", "-q", "haze"],
+            DatabaseFamily::Oracle => {
+                return Err(Report::msg("Restoring oracle databases is not supported"));
+            }
+        };
+        exec_io(
+            docker,
+            format!("{}-db{}", cloud_id, postfix),
+            "root",
+            cmd,
+            Vec::<String>::default(),
+            Option::<Stdout>::None,
+            Some(input),
+        )
+        .await?
+        .to_result()
+        .wrap_err("Failed to restore database")
+    }
+
+    pub async fn wait_for_start(
+        &self,
+        docker: &Docker,
+        cloud_id: &str,
+        timeouts: &TimeoutConfig,
+    ) -> Result<()> {
+        let default_timeout = if self.family() == DatabaseFamily::Oracle {
             45
         } else {
             15
         };
 
-        timeout(Duration::from_secs(time), async {
-            while !self.is_healthy(docker, cloud_id, "").await? {
-                sleep(Duration::from_millis(250)).await
-            }
-            Result::<(), Report>::Ok(())
-        })
+        wait_for_healthy(
+            docker,
+            "the database to accept connections",
+            self.container_name(cloud_id).as_deref(),
+            timeouts.db(default_timeout),
+            Duration::from_millis(250),
+            || self.is_healthy(docker, cloud_id, ""),
+        )
         .await
-        .into_diagnostic()
-        .wrap_err(format!("Timeout after {time} seconds"))?
     }
 
     pub async fn ip(&self, docker: &Docker, cloud_id: &str) -> Option<IpAddr> {
         match self.family() {
             DatabaseFamily::Sqlite => None,
-            _ => docker
-                .inspect_container(&format!("{}-db", cloud_id), None)
-                .await
-                .ok()?
-                .network_settings?
-                .networks?
-                .values()
-                .next()?
-                .ip_address
-                .clone()?
-                .parse()
-                .ok(),
+            _ => container_ip(docker, &format!("{}-db", cloud_id)).await,
         }
     }
 
@@ -426,7 +512,7 @@ impl Database {
                     docker,
                     format!("{}-db{}", cloud_id, postfix),
                     "root",
-                    vec!["mysql", "-u", "haze", "-phaze", "-e", "SELECT 1"],
+                    vec!["sh", "-c", "mysql -u haze -phaze -e 'SELECT 1' 2>&1"],
                     Vec::<String>::default(),
                     Some(&mut output),
                 )
@@ -0,0 +1,347 @@
+use crate::docker::Docker;
+use crate::proxy::{route_names, ActiveInstances};
+use crate::{Cloud, HazeConfig, Result};
+use miette::{miette, IntoDiagnostic, WrapErr};
+use std::fs::read_to_string;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::signal::ctrl_c;
+use tokio::time::timeout;
+use tokio::{pin, select, spawn};
+use tracing::{debug, error, info};
+
+/// Time to live of the answers in seconds, kept short since instances come and go
+const TTL: u32 = 5;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// The first question of a dns query
+#[derive(Debug, PartialEq)]
+struct Question {
+    name: String,
+    ty: u16,
+    /// Offset of the end of the question in the query
+    end: usize,
+}
+
+fn parse_question(query: &[u8]) -> Option<Question> {
+    // only queries with at least one question are handled
+    if query.len() < 12 || query[2] & 0x80 != 0 || query[4..6] == [0, 0] {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // names in questions are never compressed
+        if len > 63 {
+            return None;
+        }
+        let label = std::str::from_utf8(query.get(pos..pos + len)?).ok()?;
+        labels.push(label.to_ascii_lowercase());
+        pos += len;
+    }
+    let ty = u16::from_be_bytes([*query.get(pos)?, *query.get(pos + 1)?]);
+    // the class is ignored, but needs to be present
+    query.get(pos + 3)?;
+    Some(Question {
+        name: labels.join("."),
+        ty,
+        end: pos + 4,
+    })
+}
+
+/// Where the addresses of instances and services are looked up
+pub(crate) enum Names {
+    /// List the containers for every query
+    Docker(Docker),
+    /// Use the routing table of the proxy, which follows the docker events
+    Proxy(Arc<ActiveInstances>),
+}
+
+/// Build the response to a query, answering with the provided address if it matches the requested type
+fn build_response(query: &[u8], question: &Question, rcode: u8, ip: Option<IpAddr>) -> Vec<u8> {
+    let record = match ip {
+        Some(IpAddr::V4(ip)) if matches!(question.ty, TYPE_A | TYPE_ANY) => {
+            Some((TYPE_A, ip.octets().to_vec()))
+        }
+        Some(IpAddr::V6(ip)) if matches!(question.ty, TYPE_AAAA | TYPE_ANY) => {
+            Some((TYPE_AAAA, ip.octets().to_vec()))
+        }
+        _ => None,
+    };
+
+    let mut response = Vec::with_capacity(question.end + 32);
+    response.extend_from_slice(&query[..2]);
+    // response flag, copy the opcode and "recursion desired" from the query and mark the answer as authoritative,
+    // unless it is a failure
+    let authoritative = if rcode == RCODE_SERVFAIL { 0 } else { 0x04 };
+    response.push(0x80 | (query[2] & 0x79) | authoritative);
+    // recursion available
+    response.push(0x80 | rcode);
+    // one question, the answer if any, no authority or additional records
+    response.extend_from_slice(&[0, 1, 0, record.is_some() as u8, 0, 0, 0, 0]);
+    response.extend_from_slice(&query[12..question.end]);
+    if let Some((ty, data)) = record {
+        // pointer to the name in the question
+        response.extend_from_slice(&[0xc0, 12]);
+        response.extend_from_slice(&ty.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&TTL.to_be_bytes());
+        response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        response.extend_from_slice(&data);
+    }
+    response
+}
+
+/// Find the ip of an instance or one of its services by its name without the domain
+///
+/// The names are the same as the subdomains used by the proxy.
+async fn lookup(names: &Names, config: &HazeConfig, name: &str) -> Result<Option<IpAddr>> {
+    let docker = match names {
+        Names::Docker(docker) => docker,
+        Names::Proxy(instances) => return Ok(instances.routed_ip(name)),
+    };
+    let clouds = Cloud::list(docker, None, config).await?;
+    let Some(route) = route_names(&clouds)
+        .into_iter()
+        .find(|route| route.name == name)
+    else {
+        return Ok(None);
+    };
+    Ok(route.addr(docker).await.map(|addr| addr.ip()))
+}
+
+/// Get the part of a name before the dns domain, `None` if the name isn't in the domain
+fn name_in_domain<'a>(name: &'a str, domain: &str) -> Option<&'a str> {
+    if name == domain {
+        return Some("");
+    }
+    name.strip_suffix(domain)?.strip_suffix('.')
+}
+
+/// Answer queries for the instances and forward everything else to the upstream server
+async fn answer(
+    names: &Names,
+    config: &HazeConfig,
+    upstream: SocketAddr,
+    query: &[u8],
+) -> Option<Vec<u8>> {
+    let question = parse_question(query)?;
+    let Some(name) = name_in_domain(&question.name, &config.dns.domain) else {
+        let response = forward(upstream, query).await;
+        // let the client know right away instead of having it wait for its own timeout
+        return Some(
+            response.unwrap_or_else(|| build_response(query, &question, RCODE_SERVFAIL, None)),
+        );
+    };
+    debug!(
+        name = question.name,
+        ty = question.ty,
+        "answering dns query"
+    );
+    Some(match lookup(names, config, name).await {
+        Ok(Some(ip)) => build_response(query, &question, 0, Some(ip)),
+        Ok(None) if name.is_empty() => build_response(query, &question, 0, None),
+        Ok(None) => build_response(query, &question, RCODE_NXDOMAIN, None),
+        Err(error) => {
+            error!(?error, "failed to load instances");
+            build_response(query, &question, RCODE_SERVFAIL, None)
+        }
+    })
+}
+
+async fn forward(upstream: SocketAddr, query: &[u8]) -> Option<Vec<u8>> {
+    let bind: SocketAddr = if upstream.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await.ok()?;
+    socket.connect(upstream).await.ok()?;
+    socket.send(query).await.ok()?;
+    let mut buffer = vec![0; 4096];
+    let len = match timeout(Duration::from_secs(5), socket.recv(&mut buffer)).await {
+        Ok(Ok(len)) => len,
+        Ok(Err(error)) => {
+            debug!(%error, %upstream, "failed to forward dns query");
+            return None;
+        }
+        Err(_) => {
+            debug!(%upstream, "forwarded dns query timed out");
+            return None;
+        }
+    };
+    buffer.truncate(len);
+    Some(buffer)
+}
+
+/// Get the first nameserver from `/etc/resolv.conf`
+fn system_nameserver() -> Option<SocketAddr> {
+    read_to_string("/etc/resolv.conf")
+        .ok()?
+        .lines()
+        .find_map(|line| {
+            let ip: IpAddr = line
+                .trim()
+                .strip_prefix("nameserver")?
+                .trim()
+                .parse()
+                .ok()?;
+            Some(SocketAddr::new(ip, 53))
+        })
+}
+
+fn upstream(config: &HazeConfig) -> Result<SocketAddr> {
+    let upstream = &config.dns.upstream;
+    if upstream.is_empty() {
+        return system_nameserver().ok_or_else(|| {
+            miette!("No upstream dns server configured or found in /etc/resolv.conf")
+        });
+    }
+    upstream
+        .parse()
+        .or_else(|_| upstream.parse().map(|ip: IpAddr| SocketAddr::new(ip, 53)))
+        .map_err(|_| miette!("Invalid upstream dns server {}", upstream))
+}
+
+/// Answer dns queries for the instances on the configured address, until ctrl-c is pressed
+pub async fn dns(docker: Docker, config: HazeConfig) -> Result<()> {
+    serve_dns(Names::Docker(docker), config).await
+}
+
+/// Answer dns queries with the addresses from the provided names, until ctrl-c is pressed
+pub(crate) async fn serve_dns(names: Names, config: HazeConfig) -> Result<()> {
+    if config.dns.listen.is_empty() {
+        return Err(miette!("Dns server not configured"));
+    }
+    let upstream = upstream(&config)?;
+    let socket = UdpSocket::bind(&config.dns.listen)
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to listen on {}", config.dns.listen))?;
+    let socket = Arc::new(socket);
+    info!(
+        domain = config.dns.domain,
+        listen = %socket.local_addr().into_diagnostic()?,
+        %upstream,
+        "Answering dns queries"
+    );
+
+    let names = Arc::new(names);
+    let config = Arc::new(config);
+    let shutdown = ctrl_c();
+    pin!(shutdown);
+    let mut buffer = vec![0; 4096];
+    loop {
+        let (len, client) = select! {
+            received = socket.recv_from(&mut buffer) => match received {
+                Ok(received) => received,
+                Err(error) => {
+                    error!(%error, "failed to receive dns query");
+                    continue;
+                }
+            },
+            _ = &mut shutdown => return Ok(()),
+        };
+        let query = buffer[..len].to_vec();
+        let (names, config, socket) = (names.clone(), config.clone(), socket.clone());
+        spawn(async move {
+            if let Some(response) = answer(&names, &config, upstream, &query).await {
+                if let Err(error) = socket.send_to(&response, client).await {
+                    debug!(%error, %client, "failed to send dns response");
+                }
+            }
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_dns_answer() {
+    use crate::cloud::{test_env, test_options};
+    use crate::Database;
+
+    fn query(name: &str, ty: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&ty.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    let (_dir, mut config, _, docker) = test_env();
+    config.dns.domain = "haze.local".into();
+    let cloud = Cloud::create(&docker, test_options("test", Database::MariaDB), &config)
+        .await
+        .unwrap();
+    let names = Names::Docker(docker.clone());
+    // queries can't be sent to the broadcast address without SO_BROADCAST, so forwarding fails right away
+    let upstream = SocketAddr::from(([255, 255, 255, 255], 53));
+
+    let request = query("Test.haze.local", TYPE_A);
+    assert_eq!(
+        parse_question(&request),
+        Some(Question {
+            name: "test.haze.local".into(),
+            ty: TYPE_A,
+            end: request.len()
+        })
+    );
+    let response = answer(&names, &config, upstream, &request).await.unwrap();
+    assert_eq!(&response[..2], &[0x12, 0x34]);
+    assert_eq!(response[3] & 0x0f, 0);
+    assert_eq!(&response[6..8], &[0, 1]);
+    let IpAddr::V4(ip) = cloud.ip.unwrap() else {
+        panic!("expected an ipv4 address");
+    };
+    assert_eq!(&response[response.len() - 4..], &ip.octets());
+
+    // known names without an address of the requested type get an empty answer
+    let response = answer(
+        &names,
+        &config,
+        upstream,
+        &query("test.haze.local", TYPE_AAAA),
+    )
+    .await
+    .unwrap();
+    assert_eq!((response[3] & 0x0f, response[7]), (0, 0));
+
+    let response = answer(
+        &names,
+        &config,
+        upstream,
+        &query("other.haze.local", TYPE_A),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response[3] & 0x0f, RCODE_NXDOMAIN);
+
+    let response = answer(&names, &config, upstream, &query("example.com", TYPE_A))
+        .await
+        .unwrap();
+    assert_eq!(response[3] & 0x0f, RCODE_SERVFAIL);
+
+    // inside the proxy, the names are resolved from its routing table
+    let instances = Arc::new(ActiveInstances::new(docker.clone(), config.clone()));
+    instances.refresh().await.unwrap();
+    let names = Names::Proxy(instances);
+    let response = answer(&names, &config, upstream, &request).await.unwrap();
+    assert_eq!(&response[response.len() - 4..], &ip.octets());
+}
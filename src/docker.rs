@@ -0,0 +1,383 @@
+use async_trait::async_trait;
+use bollard::container::{
+    Config, CreateContainerOptions, DownloadFromContainerOptions, InspectContainerOptions,
+    ListContainersOptions, LogOutput, LogsOptions, NetworkingConfig, RemoveContainerOptions,
+    UpdateContainerOptions, UploadToContainerOptions,
+};
+use bollard::errors::Error;
+use bollard::exec::{
+    CreateExecOptions, CreateExecResults, ResizeExecOptions, StartExecOptions, StartExecResults,
+};
+use bollard::image::CreateImageOptions;
+use bollard::models::{
+    ContainerCreateResponse, ContainerInspectResponse, ContainerSummary, CreateImageInfo,
+    EventMessage, ExecInspectResponse, ImageInspect, Network, NetworkCreateResponse,
+};
+use bollard::network::{
+    ConnectNetworkOptions, CreateNetworkOptions, DisconnectNetworkOptions, InspectNetworkOptions,
+    ListNetworksOptions,
+};
+use bollard::system::EventsOptions;
+use bytes::Bytes;
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+pub mod fake;
+
+/// Handle to the docker backend used for all container operations
+pub type Docker = Arc<dyn DockerBackend>;
+
+/// Connect to the local docker daemon
+pub fn connect() -> Result<Docker> {
+    let docker = bollard::Docker::connect_with_local_defaults()
+        .into_diagnostic()
+        .wrap_err("Failed to connect to docker")?;
+    Ok(Arc::new(docker))
+}
+
+/// Convert a container config build from borrowed strings into the owned config taken by [`DockerBackend`]
+pub fn owned_config<T: Into<String> + Eq + Hash>(config: Config<T>) -> Config<String> {
+    fn string<T: Into<String>>(value: Option<T>) -> Option<String> {
+        value.map(Into::into)
+    }
+    fn strings<T: Into<String>>(values: Option<Vec<T>>) -> Option<Vec<String>> {
+        values.map(|values| values.into_iter().map(Into::into).collect())
+    }
+    fn keys<T: Into<String>, V>(map: Option<HashMap<T, V>>) -> Option<HashMap<String, V>> {
+        map.map(|map| {
+            map.into_iter()
+                .map(|(key, value)| (key.into(), value))
+                .collect()
+        })
+    }
+
+    Config {
+        hostname: string(config.hostname),
+        domainname: string(config.domainname),
+        user: string(config.user),
+        attach_stdin: config.attach_stdin,
+        attach_stdout: config.attach_stdout,
+        attach_stderr: config.attach_stderr,
+        exposed_ports: keys(config.exposed_ports),
+        tty: config.tty,
+        open_stdin: config.open_stdin,
+        stdin_once: config.stdin_once,
+        env: strings(config.env),
+        cmd: strings(config.cmd),
+        healthcheck: config.healthcheck,
+        args_escaped: config.args_escaped,
+        image: string(config.image),
+        volumes: keys(config.volumes),
+        working_dir: string(config.working_dir),
+        entrypoint: strings(config.entrypoint),
+        network_disabled: config.network_disabled,
+        mac_address: string(config.mac_address),
+        on_build: strings(config.on_build),
+        labels: config.labels.map(|labels| {
+            labels
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect()
+        }),
+        stop_signal: string(config.stop_signal),
+        stop_timeout: config.stop_timeout,
+        shell: strings(config.shell),
+        host_config: config.host_config,
+        networking_config: config.networking_config.map(|networking| NetworkingConfig {
+            endpoints_config: keys(Some(networking.endpoints_config)).unwrap_or_default(),
+        }),
+    }
+}
+
+/// Ip of a running container in its first network
+pub(crate) async fn container_ip(docker: &Docker, container: &str) -> Option<IpAddr> {
+    docker
+        .inspect_container(container, None)
+        .await
+        .ok()?
+        .network_settings?
+        .networks?
+        .values()
+        .next()?
+        .ip_address
+        .as_deref()?
+        .parse()
+        .ok()
+}
+
+/// The docker api calls used by haze
+///
+/// Implemented for [`bollard::Docker`] to talk to a docker daemon, and by [`fake::FakeDocker`] to run the
+/// instance orchestration against in-memory containers.
+#[async_trait]
+pub trait DockerBackend: Send + Sync {
+    async fn create_container(
+        &self,
+        options: Option<CreateContainerOptions<String>>,
+        config: Config<String>,
+    ) -> Result<ContainerCreateResponse, Error>;
+
+    async fn start_container(&self, name: &str) -> Result<(), Error>;
+
+    async fn inspect_container(
+        &self,
+        name: &str,
+        options: Option<InspectContainerOptions>,
+    ) -> Result<ContainerInspectResponse, Error>;
+
+    async fn update_container(
+        &self,
+        name: &str,
+        options: UpdateContainerOptions<String>,
+    ) -> Result<(), Error>;
+
+    async fn remove_container(
+        &self,
+        name: &str,
+        options: Option<RemoveContainerOptions>,
+    ) -> Result<(), Error>;
+
+    async fn list_containers(
+        &self,
+        options: Option<ListContainersOptions<String>>,
+    ) -> Result<Vec<ContainerSummary>, Error>;
+
+    fn logs(
+        &self,
+        name: &str,
+        options: Option<LogsOptions<String>>,
+    ) -> BoxStream<'static, Result<LogOutput, Error>>;
+
+    async fn upload_to_container(
+        &self,
+        name: &str,
+        options: Option<UploadToContainerOptions<String>>,
+        tar: Bytes,
+    ) -> Result<(), Error>;
+
+    fn download_from_container(
+        &self,
+        name: &str,
+        options: Option<DownloadFromContainerOptions<String>>,
+    ) -> BoxStream<'static, Result<Bytes, Error>>;
+
+    async fn create_exec(
+        &self,
+        container: &str,
+        options: CreateExecOptions<String>,
+    ) -> Result<CreateExecResults, Error>;
+
+    async fn start_exec(
+        &self,
+        id: &str,
+        options: Option<StartExecOptions>,
+    ) -> Result<StartExecResults, Error>;
+
+    async fn inspect_exec(&self, id: &str) -> Result<ExecInspectResponse, Error>;
+
+    async fn resize_exec(&self, id: &str, options: ResizeExecOptions) -> Result<(), Error>;
+
+    async fn create_network(
+        &self,
+        options: CreateNetworkOptions<String>,
+    ) -> Result<NetworkCreateResponse, Error>;
+
+    async fn inspect_network(
+        &self,
+        name: &str,
+        options: Option<InspectNetworkOptions<String>>,
+    ) -> Result<Network, Error>;
+
+    async fn list_networks(
+        &self,
+        options: Option<ListNetworksOptions<String>>,
+    ) -> Result<Vec<Network>, Error>;
+
+    async fn connect_network(
+        &self,
+        name: &str,
+        options: ConnectNetworkOptions<String>,
+    ) -> Result<(), Error>;
+
+    async fn disconnect_network(
+        &self,
+        name: &str,
+        options: DisconnectNetworkOptions<String>,
+    ) -> Result<(), Error>;
+
+    async fn remove_network(&self, name: &str) -> Result<(), Error>;
+
+    async fn inspect_image(&self, name: &str) -> Result<ImageInspect, Error>;
+
+    /// Pull an image
+    fn create_image(
+        &self,
+        options: Option<CreateImageOptions<'static, String>>,
+    ) -> BoxStream<'static, Result<CreateImageInfo, Error>>;
+
+    /// Stream events as they happen
+    fn events(
+        &self,
+        options: Option<EventsOptions<String>>,
+    ) -> BoxStream<'static, Result<EventMessage, Error>>;
+}
+
+#[async_trait]
+impl DockerBackend for bollard::Docker {
+    async fn create_container(
+        &self,
+        options: Option<CreateContainerOptions<String>>,
+        config: Config<String>,
+    ) -> Result<ContainerCreateResponse, Error> {
+        bollard::Docker::create_container(self, options, config).await
+    }
+
+    async fn start_container(&self, name: &str) -> Result<(), Error> {
+        bollard::Docker::start_container::<String>(self, name, None).await
+    }
+
+    async fn inspect_container(
+        &self,
+        name: &str,
+        options: Option<InspectContainerOptions>,
+    ) -> Result<ContainerInspectResponse, Error> {
+        bollard::Docker::inspect_container(self, name, options).await
+    }
+
+    async fn update_container(
+        &self,
+        name: &str,
+        options: UpdateContainerOptions<String>,
+    ) -> Result<(), Error> {
+        bollard::Docker::update_container(self, name, options).await
+    }
+
+    async fn remove_container(
+        &self,
+        name: &str,
+        options: Option<RemoveContainerOptions>,
+    ) -> Result<(), Error> {
+        bollard::Docker::remove_container(self, name, options).await
+    }
+
+    async fn list_containers(
+        &self,
+        options: Option<ListContainersOptions<String>>,
+    ) -> Result<Vec<ContainerSummary>, Error> {
+        bollard::Docker::list_containers(self, options).await
+    }
+
+    fn logs(
+        &self,
+        name: &str,
+        options: Option<LogsOptions<String>>,
+    ) -> BoxStream<'static, Result<LogOutput, Error>> {
+        bollard::Docker::logs(self, name, options).boxed()
+    }
+
+    async fn upload_to_container(
+        &self,
+        name: &str,
+        options: Option<UploadToContainerOptions<String>>,
+        tar: Bytes,
+    ) -> Result<(), Error> {
+        bollard::Docker::upload_to_container(self, name, options, tar).await
+    }
+
+    fn download_from_container(
+        &self,
+        name: &str,
+        options: Option<DownloadFromContainerOptions<String>>,
+    ) -> BoxStream<'static, Result<Bytes, Error>> {
+        bollard::Docker::download_from_container(self, name, options).boxed()
+    }
+
+    async fn create_exec(
+        &self,
+        container: &str,
+        options: CreateExecOptions<String>,
+    ) -> Result<CreateExecResults, Error> {
+        bollard::Docker::create_exec(self, container, options).await
+    }
+
+    async fn start_exec(
+        &self,
+        id: &str,
+        options: Option<StartExecOptions>,
+    ) -> Result<StartExecResults, Error> {
+        bollard::Docker::start_exec(self, id, options).await
+    }
+
+    async fn inspect_exec(&self, id: &str) -> Result<ExecInspectResponse, Error> {
+        bollard::Docker::inspect_exec(self, id).await
+    }
+
+    async fn resize_exec(&self, id: &str, options: ResizeExecOptions) -> Result<(), Error> {
+        bollard::Docker::resize_exec(self, id, options).await
+    }
+
+    async fn create_network(
+        &self,
+        options: CreateNetworkOptions<String>,
+    ) -> Result<NetworkCreateResponse, Error> {
+        bollard::Docker::create_network(self, options).await
+    }
+
+    async fn inspect_network(
+        &self,
+        name: &str,
+        options: Option<InspectNetworkOptions<String>>,
+    ) -> Result<Network, Error> {
+        bollard::Docker::inspect_network(self, name, options).await
+    }
+
+    async fn list_networks(
+        &self,
+        options: Option<ListNetworksOptions<String>>,
+    ) -> Result<Vec<Network>, Error> {
+        bollard::Docker::list_networks(self, options).await
+    }
+
+    async fn connect_network(
+        &self,
+        name: &str,
+        options: ConnectNetworkOptions<String>,
+    ) -> Result<(), Error> {
+        bollard::Docker::connect_network(self, name, options).await
+    }
+
+    async fn disconnect_network(
+        &self,
+        name: &str,
+        options: DisconnectNetworkOptions<String>,
+    ) -> Result<(), Error> {
+        bollard::Docker::disconnect_network(self, name, options).await
+    }
+
+    async fn remove_network(&self, name: &str) -> Result<(), Error> {
+        bollard::Docker::remove_network(self, name).await
+    }
+
+    async fn inspect_image(&self, name: &str) -> Result<ImageInspect, Error> {
+        bollard::Docker::inspect_image(self, name).await
+    }
+
+    fn create_image(
+        &self,
+        options: Option<CreateImageOptions<'static, String>>,
+    ) -> BoxStream<'static, Result<CreateImageInfo, Error>> {
+        bollard::Docker::create_image(self, options, None, None).boxed()
+    }
+
+    fn events(
+        &self,
+        options: Option<EventsOptions<String>>,
+    ) -> BoxStream<'static, Result<EventMessage, Error>> {
+        bollard::Docker::events(self, options).boxed()
+    }
+}
@@ -0,0 +1,740 @@
+//! In-memory docker backend for testing the instance orchestration without a docker daemon
+
+use crate::docker::DockerBackend;
+use async_trait::async_trait;
+use bollard::container::{
+    Config, CreateContainerOptions, DownloadFromContainerOptions, InspectContainerOptions,
+    ListContainersOptions, LogOutput, LogsOptions, RemoveContainerOptions, UpdateContainerOptions,
+    UploadToContainerOptions,
+};
+use bollard::errors::Error;
+use bollard::exec::{
+    CreateExecOptions, CreateExecResults, ResizeExecOptions, StartExecOptions, StartExecResults,
+};
+use bollard::image::CreateImageOptions;
+use bollard::models::{
+    ContainerConfig, ContainerCreateResponse, ContainerInspectResponse, ContainerState,
+    ContainerStateStatusEnum, ContainerSummary, ContainerSummaryNetworkSettings, CreateImageInfo,
+    EndpointSettings, EventActor, EventMessage, EventMessageTypeEnum, ExecInspectResponse,
+    ImageInspect, Ipam, IpamConfig, Network, NetworkContainer, NetworkCreateResponse,
+    NetworkSettings,
+};
+use bollard::network::{
+    ConnectNetworkOptions, CreateNetworkOptions, DisconnectNetworkOptions, InspectNetworkOptions,
+    ListNetworksOptions,
+};
+use bollard::system::EventsOptions;
+use bytes::Bytes;
+use chrono::DateTime;
+use futures_util::stream::{empty, iter, once, unfold, BoxStream};
+use futures_util::StreamExt;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use tokio::io::sink;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+/// A container created in the [`FakeDocker`] backend
+#[derive(Debug, Clone)]
+pub struct FakeContainer {
+    pub id: String,
+    pub name: String,
+    pub config: Config<String>,
+    pub running: bool,
+    /// Networks the container is connected to
+    pub networks: HashMap<String, FakeEndpoint>,
+    pub created: i64,
+}
+
+/// The connection of a [`FakeContainer`] to a network
+#[derive(Debug, Clone)]
+pub struct FakeEndpoint {
+    pub ip: String,
+    pub aliases: Vec<String>,
+}
+
+/// A command executed in the [`FakeDocker`] backend
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FakeExec {
+    pub container: String,
+    pub user: String,
+    pub cmd: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct FakeNetwork {
+    id: String,
+    name: String,
+    subnet: u8,
+    next_ip: u8,
+}
+
+/// Docker api filters, by filter name
+type Filters = HashMap<String, Vec<String>>;
+
+#[derive(Default)]
+struct State {
+    containers: Vec<FakeContainer>,
+    networks: Vec<FakeNetwork>,
+    images: BTreeSet<String>,
+    failing_starts: HashSet<String>,
+    execs: Vec<FakeExec>,
+    /// Open event streams with their filters
+    event_listeners: Vec<(Filters, UnboundedSender<EventMessage>)>,
+    next_id: u64,
+}
+
+impl State {
+    fn next_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}{:012x}", prefix, self.next_id)
+    }
+
+    fn container(&self, name: &str) -> Result<&FakeContainer, Error> {
+        self.containers
+            .iter()
+            .find(|container| container.matches(name))
+            .ok_or_else(|| not_found(format!("No such container: {}", name)))
+    }
+
+    fn container_mut(&mut self, name: &str) -> Result<&mut FakeContainer, Error> {
+        self.containers
+            .iter_mut()
+            .find(|container| container.matches(name))
+            .ok_or_else(|| not_found(format!("No such container: {}", name)))
+    }
+
+    fn network_mut(&mut self, name: &str) -> Result<&mut FakeNetwork, Error> {
+        self.networks
+            .iter_mut()
+            .find(|network| network.name == name || network.id == name)
+            .ok_or_else(|| not_found(format!("network {} not found", name)))
+    }
+
+    /// Send a container event to all event streams with matching filters
+    fn emit(&mut self, action: &str, container: &FakeContainer) {
+        let mut attributes = container.labels();
+        attributes.insert("name".into(), container.name.clone());
+        let event = EventMessage {
+            typ: Some(EventMessageTypeEnum::CONTAINER),
+            action: Some(action.into()),
+            actor: Some(EventActor {
+                id: Some(container.id.clone()),
+                attributes: Some(attributes),
+            }),
+            ..Default::default()
+        };
+        let labels = container.labels();
+        self.event_listeners.retain(|(filters, listener)| {
+            !matches_label_filters(&labels, filters) || listener.send(event.clone()).is_ok()
+        });
+    }
+
+    /// Allocate an ip in a network for a container
+    fn connect(
+        &mut self,
+        network: &str,
+        aliases: Vec<String>,
+    ) -> Result<(String, FakeEndpoint), Error> {
+        let network = self.network_mut(network)?;
+        network.next_ip += 1;
+        let endpoint = FakeEndpoint {
+            ip: format!("172.{}.0.{}", network.subnet, network.next_ip),
+            aliases,
+        };
+        Ok((network.name.clone(), endpoint))
+    }
+}
+
+impl FakeContainer {
+    fn matches(&self, name: &str) -> bool {
+        let name = name.trim_start_matches('/');
+        self.name == name || self.id == name
+    }
+
+    fn labels(&self) -> HashMap<String, String> {
+        self.config.labels.clone().unwrap_or_default()
+    }
+
+    fn endpoints(&self) -> HashMap<String, EndpointSettings> {
+        self.networks
+            .iter()
+            .map(|(name, endpoint)| {
+                (
+                    name.clone(),
+                    EndpointSettings {
+                        aliases: Some(endpoint.aliases.clone()),
+                        // like docker, the ip is only known while the container is running
+                        ip_address: Some(if self.running {
+                            endpoint.ip.clone()
+                        } else {
+                            String::new()
+                        }),
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn inspect(&self) -> ContainerInspectResponse {
+        ContainerInspectResponse {
+            id: Some(self.id.clone()),
+            name: Some(format!("/{}", self.name)),
+            created: DateTime::from_timestamp(self.created, 0).map(|time| time.to_rfc3339()),
+            image: self.config.image.clone(),
+            state: Some(ContainerState {
+                status: Some(if self.running {
+                    ContainerStateStatusEnum::RUNNING
+                } else {
+                    ContainerStateStatusEnum::CREATED
+                }),
+                running: Some(self.running),
+                ..Default::default()
+            }),
+            config: Some(ContainerConfig {
+                image: self.config.image.clone(),
+                user: self.config.user.clone(),
+                env: self.config.env.clone(),
+                cmd: self.config.cmd.clone(),
+                labels: self.config.labels.clone(),
+                exposed_ports: self.config.exposed_ports.clone(),
+                ..Default::default()
+            }),
+            host_config: self.config.host_config.clone(),
+            network_settings: Some(NetworkSettings {
+                networks: Some(self.endpoints()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn summary(&self) -> ContainerSummary {
+        ContainerSummary {
+            id: Some(self.id.clone()),
+            names: Some(vec![format!("/{}", self.name)]),
+            image: self.config.image.clone(),
+            created: Some(self.created),
+            labels: Some(self.labels()),
+            state: Some(if self.running { "running" } else { "created" }.into()),
+            network_settings: Some(ContainerSummaryNetworkSettings {
+                networks: Some(self.endpoints()),
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+/// Docker backend that keeps all containers, networks and images in memory
+///
+/// Containers don't run anything, starting a container only marks it as running and assigns it ips in its networks.
+/// Commands executed in a container are recorded, produce no output and exit successfully. Event streams receive
+/// the `create`, `start` and `destroy` events of containers.
+#[derive(Default)]
+pub struct FakeDocker {
+    state: Mutex<State>,
+}
+
+impl FakeDocker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Make starting the container with the given name fail
+    pub fn fail_start(&self, name: &str) {
+        self.state().failing_starts.insert(name.into());
+    }
+
+    /// Let a container that was marked with [`FakeDocker::fail_start`] start again
+    pub fn allow_start(&self, name: &str) {
+        self.state().failing_starts.remove(name);
+    }
+
+    /// Mark an image as already pulled
+    pub fn add_image(&self, image: &str) {
+        self.state().images.insert(normalize_image(image));
+    }
+
+    /// Mark a port like `3306/tcp` as exposed by a container, as if its image declared it
+    pub fn expose(&self, container: &str, port: &str) {
+        if let Ok(container) = self.state().container_mut(container) {
+            container
+                .config
+                .exposed_ports
+                .get_or_insert_with(HashMap::new)
+                .insert(port.into(), HashMap::new());
+        }
+    }
+
+    /// All existing containers, in order of creation
+    pub fn containers(&self) -> Vec<FakeContainer> {
+        self.state().containers.clone()
+    }
+
+    pub fn container(&self, name: &str) -> Option<FakeContainer> {
+        self.state().container(name).ok().cloned()
+    }
+
+    /// Names of all existing networks
+    pub fn networks(&self) -> Vec<String> {
+        self.state()
+            .networks
+            .iter()
+            .map(|network| network.name.clone())
+            .collect()
+    }
+
+    /// All pulled images
+    pub fn images(&self) -> Vec<String> {
+        self.state().images.iter().cloned().collect()
+    }
+
+    /// All commands executed so far
+    pub fn execs(&self) -> Vec<FakeExec> {
+        self.state().execs.clone()
+    }
+}
+
+fn error(status_code: u16, message: String) -> Error {
+    Error::DockerResponseServerError {
+        status_code,
+        message,
+    }
+}
+
+fn not_found(message: String) -> Error {
+    error(404, message)
+}
+
+/// Strip the implicit registry and add the implicit `latest` tag, so different names for the same image match
+fn normalize_image(image: &str) -> String {
+    let image = image.trim_start_matches("docker.io/");
+    let image = image.strip_prefix("library/").unwrap_or(image);
+    let name = image.rsplit('/').next().unwrap_or(image);
+    if name.contains(':') {
+        image.to_string()
+    } else {
+        format!("{}:latest", image)
+    }
+}
+
+/// Check if the labels match all `label` filters, either `key` or `key=value`
+fn matches_label_filters(labels: &HashMap<String, String>, filters: &Filters) -> bool {
+    filters
+        .get("label")
+        .into_iter()
+        .flatten()
+        .all(|filter| match filter.split_once('=') {
+            Some((key, value)) => labels.get(key).map(String::as_str) == Some(value),
+            None => labels.contains_key(filter),
+        })
+}
+
+#[async_trait]
+impl DockerBackend for FakeDocker {
+    async fn create_container(
+        &self,
+        options: Option<CreateContainerOptions<String>>,
+        config: Config<String>,
+    ) -> Result<ContainerCreateResponse, Error> {
+        let mut state = self.state();
+        let image = config.image.as_deref().unwrap_or_default();
+        if !state.images.contains(&normalize_image(image)) {
+            return Err(not_found(format!("No such image: {}", image)));
+        }
+
+        let id = state.next_id("");
+        let name = options
+            .map(|options| options.name)
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| id.clone());
+        if state.container(&name).is_ok() {
+            return Err(error(
+                409,
+                format!(
+                    "Conflict. The container name \"/{}\" is already in use",
+                    name
+                ),
+            ));
+        }
+
+        let mut networks = HashMap::new();
+        let network_mode = config
+            .host_config
+            .as_ref()
+            .and_then(|host| host.network_mode.as_deref());
+        if let Some(network) = network_mode.filter(|mode| !matches!(*mode, "default" | "bridge")) {
+            let aliases = config
+                .networking_config
+                .as_ref()
+                .and_then(|networking| networking.endpoints_config.get(network))
+                .and_then(|endpoint| endpoint.aliases.clone())
+                .unwrap_or_default();
+            let (network, endpoint) = state.connect(network, aliases)?;
+            networks.insert(network, endpoint);
+        }
+
+        let created = 1_700_000_000 + state.next_id as i64;
+        let container = FakeContainer {
+            id: id.clone(),
+            name,
+            config,
+            running: false,
+            networks,
+            created,
+        };
+        state.emit("create", &container);
+        state.containers.push(container);
+        Ok(ContainerCreateResponse {
+            id,
+            warnings: Vec::new(),
+        })
+    }
+
+    async fn start_container(&self, name: &str) -> Result<(), Error> {
+        let mut state = self.state();
+        let container = state.container(name)?;
+        if state.failing_starts.contains(&container.name) {
+            return Err(error(
+                500,
+                format!("failed to start container {}", container.name),
+            ));
+        }
+        let container = state.container_mut(name)?;
+        container.running = true;
+        let container = container.clone();
+        state.emit("start", &container);
+        Ok(())
+    }
+
+    async fn inspect_container(
+        &self,
+        name: &str,
+        _options: Option<InspectContainerOptions>,
+    ) -> Result<ContainerInspectResponse, Error> {
+        Ok(self.state().container(name)?.inspect())
+    }
+
+    async fn update_container(
+        &self,
+        name: &str,
+        options: UpdateContainerOptions<String>,
+    ) -> Result<(), Error> {
+        let mut state = self.state();
+        let container = state.container_mut(name)?;
+        if let Some(memory) = options.memory {
+            container.config.host_config.get_or_insert_default().memory = Some(memory);
+        }
+        Ok(())
+    }
+
+    async fn remove_container(
+        &self,
+        name: &str,
+        options: Option<RemoveContainerOptions>,
+    ) -> Result<(), Error> {
+        let mut state = self.state();
+        let container = state.container(name)?;
+        if container.running && !options.is_some_and(|options| options.force) {
+            return Err(error(
+                409,
+                format!(
+                    "cannot remove container \"/{}\": container is running: stop the container before removing or force remove",
+                    container.name
+                ),
+            ));
+        }
+        let container = container.clone();
+        state
+            .containers
+            .retain(|container| !container.matches(name));
+        state.emit("destroy", &container);
+        Ok(())
+    }
+
+    async fn list_containers(
+        &self,
+        options: Option<ListContainersOptions<String>>,
+    ) -> Result<Vec<ContainerSummary>, Error> {
+        let options = options.unwrap_or_default();
+        Ok(self
+            .state()
+            .containers
+            .iter()
+            .filter(|container| options.all || container.running)
+            .filter(|container| matches_label_filters(&container.labels(), &options.filters))
+            .map(FakeContainer::summary)
+            .collect())
+    }
+
+    fn logs(
+        &self,
+        name: &str,
+        _options: Option<LogsOptions<String>>,
+    ) -> BoxStream<'static, Result<LogOutput, Error>> {
+        match self.state().container(name) {
+            Ok(_) => empty().boxed(),
+            Err(e) => once(async move { Err(e) }).boxed(),
+        }
+    }
+
+    async fn upload_to_container(
+        &self,
+        name: &str,
+        _options: Option<UploadToContainerOptions<String>>,
+        _tar: Bytes,
+    ) -> Result<(), Error> {
+        self.state().container(name)?;
+        Ok(())
+    }
+
+    fn download_from_container(
+        &self,
+        name: &str,
+        options: Option<DownloadFromContainerOptions<String>>,
+    ) -> BoxStream<'static, Result<Bytes, Error>> {
+        let path = options.map(|options| options.path).unwrap_or_default();
+        let error = match self.state().container(name) {
+            Ok(container) => not_found(format!(
+                "Could not find the file {} in container {}",
+                path, container.name
+            )),
+            Err(e) => e,
+        };
+        once(async move { Err(error) }).boxed()
+    }
+
+    async fn create_exec(
+        &self,
+        container: &str,
+        options: CreateExecOptions<String>,
+    ) -> Result<CreateExecResults, Error> {
+        let mut state = self.state();
+        let found = state.container(container)?;
+        if !found.running {
+            return Err(error(409, format!("container {} is not running", found.id)));
+        }
+        let exec = FakeExec {
+            container: found.name.clone(),
+            user: options.user.unwrap_or_default(),
+            cmd: options.cmd.unwrap_or_default(),
+        };
+        state.execs.push(exec);
+        Ok(CreateExecResults {
+            id: format!("exec-{}", state.execs.len() - 1),
+        })
+    }
+
+    async fn start_exec(
+        &self,
+        id: &str,
+        _options: Option<StartExecOptions>,
+    ) -> Result<StartExecResults, Error> {
+        self.inspect_exec(id).await?;
+        Ok(StartExecResults::Attached {
+            output: empty().boxed(),
+            input: Box::pin(sink()),
+        })
+    }
+
+    async fn inspect_exec(&self, id: &str) -> Result<ExecInspectResponse, Error> {
+        let state = self.state();
+        let exec = id
+            .strip_prefix("exec-")
+            .and_then(|index| index.parse::<usize>().ok())
+            .and_then(|index| state.execs.get(index))
+            .ok_or_else(|| not_found(format!("No such exec instance: {}", id)))?;
+        Ok(ExecInspectResponse {
+            id: Some(id.to_string()),
+            container_id: Some(state.container(&exec.container)?.id.clone()),
+            running: Some(false),
+            exit_code: Some(0),
+            ..Default::default()
+        })
+    }
+
+    async fn resize_exec(&self, id: &str, _options: ResizeExecOptions) -> Result<(), Error> {
+        self.inspect_exec(id).await?;
+        Ok(())
+    }
+
+    async fn create_network(
+        &self,
+        options: CreateNetworkOptions<String>,
+    ) -> Result<NetworkCreateResponse, Error> {
+        let mut state = self.state();
+        if state.network_mut(&options.name).is_ok() {
+            return Err(error(
+                409,
+                format!("network with name {} already exists", options.name),
+            ));
+        }
+        let id = state.next_id("net");
+        let subnet = 18 + state.networks.len() as u8;
+        state.networks.push(FakeNetwork {
+            id: id.clone(),
+            name: options.name,
+            subnet,
+            next_ip: 1,
+        });
+        Ok(NetworkCreateResponse {
+            id,
+            warning: String::new(),
+        })
+    }
+
+    async fn inspect_network(
+        &self,
+        name: &str,
+        _options: Option<InspectNetworkOptions<String>>,
+    ) -> Result<Network, Error> {
+        let mut state = self.state();
+        let network = state.network_mut(name)?.clone();
+        let containers = state
+            .containers
+            .iter()
+            .filter_map(|container| {
+                let endpoint = container.networks.get(&network.name)?;
+                Some((
+                    container.id.clone(),
+                    NetworkContainer {
+                        name: Some(container.name.clone()),
+                        ipv4_address: Some(format!("{}/16", endpoint.ip)),
+                        ..Default::default()
+                    },
+                ))
+            })
+            .collect();
+        Ok(Network {
+            name: Some(network.name),
+            id: Some(network.id),
+            ipam: Some(Ipam {
+                config: Some(vec![IpamConfig {
+                    subnet: Some(format!("172.{}.0.0/16", network.subnet)),
+                    gateway: Some(format!("172.{}.0.1", network.subnet)),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            containers: Some(containers),
+            ..Default::default()
+        })
+    }
+
+    async fn list_networks(
+        &self,
+        _options: Option<ListNetworksOptions<String>>,
+    ) -> Result<Vec<Network>, Error> {
+        let names = self.networks();
+        let mut networks = Vec::with_capacity(names.len());
+        for name in names {
+            networks.push(self.inspect_network(&name, None).await?);
+        }
+        Ok(networks)
+    }
+
+    async fn connect_network(
+        &self,
+        name: &str,
+        options: ConnectNetworkOptions<String>,
+    ) -> Result<(), Error> {
+        let mut state = self.state();
+        state.container(&options.container)?;
+        let aliases = options.endpoint_config.aliases.unwrap_or_default();
+        let (network, endpoint) = state.connect(name, aliases)?;
+        state
+            .container_mut(&options.container)?
+            .networks
+            .insert(network, endpoint);
+        Ok(())
+    }
+
+    async fn disconnect_network(
+        &self,
+        name: &str,
+        options: DisconnectNetworkOptions<String>,
+    ) -> Result<(), Error> {
+        let mut state = self.state();
+        let network = state.network_mut(name)?.name.clone();
+        let container = state.container_mut(&options.container)?;
+        if container.networks.remove(&network).is_none() {
+            return Err(error(
+                403,
+                format!(
+                    "container {} is not connected to network {}",
+                    container.id, network
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn remove_network(&self, name: &str) -> Result<(), Error> {
+        let mut state = self.state();
+        let network = state.network_mut(name)?.clone();
+        if state
+            .containers
+            .iter()
+            .any(|container| container.networks.contains_key(&network.name))
+        {
+            return Err(error(
+                403,
+                format!(
+                    "error while removing network: network {} id {} has active endpoints",
+                    network.name, network.id
+                ),
+            ));
+        }
+        state.networks.retain(|existing| existing.id != network.id);
+        Ok(())
+    }
+
+    async fn inspect_image(&self, name: &str) -> Result<ImageInspect, Error> {
+        let image = normalize_image(name);
+        if self.state().images.contains(&image) {
+            Ok(ImageInspect {
+                id: Some(format!("sha256:{}", image)),
+                repo_tags: Some(vec![image]),
+                ..Default::default()
+            })
+        } else {
+            Err(not_found(format!("No such image: {}", name)))
+        }
+    }
+
+    fn create_image(
+        &self,
+        options: Option<CreateImageOptions<'static, String>>,
+    ) -> BoxStream<'static, Result<CreateImageInfo, Error>> {
+        let options = options.unwrap_or_default();
+        let image = if options.tag.is_empty() {
+            normalize_image(&options.from_image)
+        } else {
+            format!("{}:{}", options.from_image, options.tag)
+        };
+        self.state().images.insert(image.clone());
+        iter([Ok(CreateImageInfo {
+            status: Some(format!("Downloaded newer image for {}", image)),
+            ..Default::default()
+        })])
+        .boxed()
+    }
+
+    fn events(
+        &self,
+        options: Option<EventsOptions<String>>,
+    ) -> BoxStream<'static, Result<EventMessage, Error>> {
+        let (sender, receiver) = unbounded_channel();
+        let filters = options.unwrap_or_default().filters;
+        self.state().event_listeners.push((filters, sender));
+        unfold(receiver, |mut receiver| async move {
+            let event = receiver.recv().await?;
+            Some((Ok(event), receiver))
+        })
+        .boxed()
+    }
+}
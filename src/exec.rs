@@ -1,15 +1,15 @@
-use bollard::container::LogsOptions;
+use crate::docker::Docker;
+use crate::tty::{forward_stdin, RawMode};
+use bollard::container::{LogOutput, LogsOptions};
 use bollard::exec::{CreateExecOptions, ResizeExecOptions, StartExecResults};
-use bollard::Docker;
 use futures_util::StreamExt;
 use miette::{IntoDiagnostic, Report, Result, WrapErr};
-use std::io::{stdout, Read, Stdin, Write};
-use std::time::Duration;
-use termion::raw::IntoRawMode;
-use termion::{async_stdin, is_tty, terminal_size};
+use std::io::{stderr, stdout, Read, Stdin, Write};
+use termion::{is_tty, terminal_size};
 use tokio::io::AsyncWriteExt;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::task::spawn;
-use tokio::time::sleep;
 
 pub async fn exec_tty<S1: AsRef<str>, S2: Into<String>, Env: Into<String>>(
     docker: &Docker,
@@ -18,13 +18,12 @@ pub async fn exec_tty<S1: AsRef<str>, S2: Into<String>, Env: Into<String>>(
     cmd: Vec<S2>,
     env: Vec<Env>,
 ) -> Result<ExitCode> {
-    let stdout = stdout();
+    let mut stdout = stdout();
 
     if !is_tty(&stdout) {
         return exec(docker, container, user, cmd, env, Some(stdout)).await;
     }
 
-    let tty_size = terminal_size().into_diagnostic()?;
     let cmd = cmd.into_iter().map(S2::into).collect();
     let env = env.into_iter().map(Env::into).collect();
     let config = CreateExecOptions {
@@ -42,47 +41,66 @@ pub async fn exec_tty<S1: AsRef<str>, S2: Into<String>, Env: Into<String>>(
         .await
         .into_diagnostic()
         .wrap_err("Failed to setup exec")?;
-    if let StartExecResults::Attached {
-        mut output,
-        mut input,
-    } = docker
+    if let StartExecResults::Attached { mut output, input } = docker
         .start_exec(&message.id, None)
         .await
         .into_diagnostic()
         .wrap_err("Failed to start exec")?
     {
-        docker
-            .resize_exec(
-                &message.id,
-                ResizeExecOptions {
-                    height: tty_size.1,
-                    width: tty_size.0,
-                },
-            )
-            .await
-            .ok();
+        resize_tty(docker, &message.id).await;
 
-        // pipe stdin into the docker exec stream input
-        spawn(async move {
-            let mut stdin = async_stdin().bytes();
-            loop {
-                if let Some(Ok(byte)) = stdin.next() {
-                    input.write_all(&[byte]).await.ok();
-                } else {
-                    sleep(Duration::from_nanos(10)).await;
-                }
+        // forward terminal resizes
+        let mut window_change = signal(SignalKind::window_change()).into_diagnostic()?;
+        let resize_docker = docker.clone();
+        let exec_id = message.id.clone();
+        let resize = spawn(async move {
+            while window_change.recv().await.is_some() {
+                resize_tty(&resize_docker, &exec_id).await;
             }
         });
 
         // set stdout in raw mode so we can do tty stuff
-        let mut stdout = stdout.lock().into_raw_mode().into_diagnostic()?;
+        let raw_mode = RawMode::enable()?;
+        let mut stdin = spawn(forward_stdin(input));
+        let mut stdin_open = true;
+        let mut detached = false;
+        let mut stdin_error = None;
 
         // pipe docker exec output into stdout
-        while let Some(Ok(output)) = output.next().await {
-            stdout
-                .write(output.into_bytes().as_ref())
-                .into_diagnostic()?;
-            stdout.flush().into_diagnostic()?;
+        loop {
+            select! {
+                chunk = output.next() => match chunk {
+                    Some(Ok(chunk)) => {
+                        stdout.write_all(chunk.into_bytes().as_ref()).into_diagnostic()?;
+                        stdout.flush().into_diagnostic()?;
+                    }
+                    _ => break,
+                },
+                result = &mut stdin, if stdin_open => {
+                    stdin_open = false;
+                    match result {
+                        Ok(Ok(true)) => {
+                            detached = true;
+                            break;
+                        }
+                        Ok(Err(e)) => {
+                            stdin_error = Some(e);
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        stdin.abort();
+        resize.abort();
+        drop(raw_mode);
+
+        if let Some(e) = stdin_error {
+            return Err(e.wrap_err("Failed to forward input"));
+        }
+        if detached {
+            eprintln!("Detached from {}", container.as_ref());
         }
     } else {
         unreachable!();
@@ -97,6 +115,16 @@ pub async fn exec_tty<S1: AsRef<str>, S2: Into<String>, Env: Into<String>>(
         .into())
 }
 
+/// Resize the tty of an exec to the size of the terminal
+async fn resize_tty(docker: &Docker, exec_id: &str) {
+    if let Ok((width, height)) = terminal_size() {
+        docker
+            .resize_exec(exec_id, ResizeExecOptions { height, width })
+            .await
+            .ok();
+    }
+}
+
 pub async fn exec<S1: AsRef<str>, S2: Into<String>, Env: Into<String>>(
     docker: &Docker,
     container: S1,
@@ -117,6 +145,9 @@ pub async fn exec<S1: AsRef<str>, S2: Into<String>, Env: Into<String>>(
     .await
 }
 
+/// Run a command in a container, writing its stdout to `std_out`
+///
+/// Anything the command writes to stderr is forwarded to the host stderr, or discarded when no `std_out` is provided.
 pub async fn exec_io<S1: AsRef<str>, S2: Into<String>, Env: Into<String>>(
     docker: &Docker,
     container: S1,
@@ -164,8 +195,14 @@ pub async fn exec_io<S1: AsRef<str>, S2: Into<String>, Env: Into<String>>(
             input.shutdown().await.into_diagnostic()?;
         }
         while let Some(Ok(line)) = output.next().await {
-            if let Some(std_out) = &mut std_out {
-                write!(std_out, "{}", line).into_diagnostic()?;
+            match (&mut std_out, line) {
+                (Some(_), LogOutput::StdErr { message }) => {
+                    stderr().write_all(&message).into_diagnostic()?;
+                }
+                (Some(std_out), line) => {
+                    write!(std_out, "{}", line).into_diagnostic()?;
+                }
+                (None, _) => {}
             }
         }
     } else {
@@ -181,6 +218,55 @@ pub async fn exec_io<S1: AsRef<str>, S2: Into<String>, Env: Into<String>>(
         .into())
 }
 
+/// Run a command in a container, passing each chunk of output to `output` as it arrives
+pub async fn exec_streamed<S1: AsRef<str>, S2: Into<String>, Env: Into<String>>(
+    docker: &Docker,
+    container: S1,
+    user: &str,
+    cmd: Vec<S2>,
+    env: Vec<Env>,
+    mut output: impl FnMut(LogOutput),
+) -> Result<ExitCode> {
+    let cmd = cmd.into_iter().map(S2::into).collect();
+    let env = env.into_iter().map(Env::into).collect();
+    let config = CreateExecOptions {
+        cmd: Some(cmd),
+        user: Some(user.to_string()),
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        env: Some(env),
+        tty: Some(false),
+        ..Default::default()
+    };
+    let message = docker
+        .create_exec(container.as_ref(), config)
+        .await
+        .into_diagnostic()
+        .wrap_err("Failed to setup exec")?;
+    if let StartExecResults::Attached {
+        output: mut stream, ..
+    } = docker
+        .start_exec(&message.id, None)
+        .await
+        .into_diagnostic()
+        .wrap_err("Failed to start exec")?
+    {
+        while let Some(Ok(chunk)) = stream.next().await {
+            output(chunk);
+        }
+    } else {
+        unreachable!();
+    }
+
+    Ok(docker
+        .inspect_exec(&message.id)
+        .await
+        .into_diagnostic()?
+        .exit_code
+        .unwrap_or_default()
+        .into())
+}
+
 pub async fn container_logs(
     docker: &Docker,
     mut std_out: impl Write,
@@ -188,7 +274,7 @@ pub async fn container_logs(
     count: usize,
     follow: bool,
 ) -> Result<()> {
-    let mut stream = docker.logs::<String>(
+    let mut stream = docker.logs(
         container,
         Some(LogsOptions {
             stdout: true,
@@ -208,6 +294,10 @@ pub async fn container_logs(
 pub struct ExitCode(i64);
 
 impl ExitCode {
+    pub fn code(&self) -> i64 {
+        self.0
+    }
+
     pub fn is_ok(&self) -> bool {
         self.0 == 0
     }
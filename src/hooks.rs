@@ -0,0 +1,144 @@
+use crate::cloud::{Cloud, CloudOptions};
+use crate::config::{HazeConfig, HooksConfig};
+use crate::docker::Docker;
+use crate::service::{PresetService, Service, ServiceTrait};
+use camino::Utf8Path;
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Report, Result, WrapErr};
+use parse_display::Display;
+use tokio::process::Command;
+
+/// Lifecycle events of an instance that can have hooks configured
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Display)]
+pub enum Hook {
+    #[display("pre_create")]
+    PreCreate,
+    #[display("post_create")]
+    PostCreate,
+    #[display("post_install")]
+    PostInstall,
+    #[display("pre_destroy")]
+    PreDestroy,
+}
+
+impl HooksConfig {
+    /// Combine the global hooks with the hooks of the presets used by an instance
+    pub fn for_services(config: &HazeConfig, services: &[Service]) -> HooksConfig {
+        let presets = services.iter().filter_map(|service| match service {
+            Service::Preset(PresetService(name)) => {
+                config.preset.iter().find(|preset| &preset.name == name)
+            }
+            _ => None,
+        });
+        let mut hooks = config.hooks.clone();
+        for preset in presets {
+            hooks.pre_create.extend_from_slice(&preset.hooks.pre_create);
+            hooks
+                .post_create
+                .extend_from_slice(&preset.hooks.post_create);
+            hooks
+                .post_install
+                .extend_from_slice(&preset.hooks.post_install);
+            hooks
+                .pre_destroy
+                .extend_from_slice(&preset.hooks.pre_destroy);
+        }
+        hooks
+    }
+
+    pub fn commands(&self, hook: Hook) -> &[String] {
+        match hook {
+            Hook::PreCreate => &self.pre_create,
+            Hook::PostCreate => &self.post_create,
+            Hook::PostInstall => &self.post_install,
+            Hook::PreDestroy => &self.pre_destroy,
+        }
+    }
+}
+
+/// Environment variables describing an instance that isn't created yet
+pub fn options_env(id: &str, workdir: &Utf8Path, options: &CloudOptions) -> Vec<(String, String)> {
+    vec![
+        ("HAZE_ID".into(), id.into()),
+        ("HAZE_WORKDIR".into(), workdir.to_string()),
+        ("HAZE_PHP".into(), options.php.name().into()),
+        ("HAZE_DB".into(), options.db.name().into()),
+        (
+            "HAZE_SERVICES".into(),
+            options
+                .services
+                .iter()
+                .map(|service| service.name())
+                .join(","),
+        ),
+    ]
+}
+
+impl Cloud {
+    /// Environment variables describing the instance, similar to the ones set by `haze env`
+    pub async fn hook_env(&self, docker: &Docker) -> Vec<(String, String)> {
+        let mut env = options_env(&self.id, &self.workdir, &self.options);
+        env.push(("NEXTCLOUD_URL".into(), self.address.clone()));
+        if let Some(ip) = self.ip {
+            env.push(("HAZE_IP".into(), ip.to_string()));
+            env.push(("REDIS_URL".into(), format!("redis://{}", ip)));
+        }
+        if let Some(url) = self
+            .db()
+            .ip(docker, &self.id)
+            .await
+            .and_then(|ip| self.db().url(ip))
+        {
+            env.push(("DATABASE_URL".into(), url));
+        }
+        env
+    }
+
+    /// Run the hooks of the instance for an event
+    pub async fn run_hook(&self, docker: &Docker, hook: Hook) -> Result<()> {
+        let commands = self.hooks.commands(hook);
+        if commands.is_empty() {
+            return Ok(());
+        }
+        run_hooks(hook, commands, &self.hook_env(docker).await).await
+    }
+}
+
+/// Run the commands for a hook on the host, stopping at the first command that fails
+pub async fn run_hooks(hook: Hook, commands: &[String], env: &[(String, String)]) -> Result<()> {
+    for command in commands {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("HAZE_HOOK", hook.to_string())
+            .envs(env.iter().map(|(key, value)| (key, value)))
+            .status()
+            .await
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to run {} hook", hook))?;
+        if !status.success() {
+            return Err(Report::msg(format!(
+                "{} hook `{}` failed with {}",
+                hook, command, status
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_hooks() {
+    let env = vec![("HAZE_ID".to_string(), "haze-foo".to_string())];
+    run_hooks(
+        Hook::PostCreate,
+        &[r#"test "$HAZE_ID" = haze-foo && test "$HAZE_HOOK" = post_create"#.into()],
+        &env,
+    )
+    .await
+    .unwrap();
+    assert!(
+        run_hooks(Hook::PreCreate, &["true".into(), "false".into()], &env)
+            .await
+            .is_err()
+    );
+}
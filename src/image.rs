@@ -1,6 +1,6 @@
+use crate::docker::Docker;
 use bollard::image::CreateImageOptions;
 use bollard::models::CreateImageInfo;
-use bollard::Docker;
 use futures_util::StreamExt;
 use miette::{IntoDiagnostic, Result, WrapErr};
 use std::collections::HashMap;
@@ -16,18 +16,14 @@ pub async fn pull_image(docker: &Docker, image: &str) -> Result<()> {
     if docker.inspect_image(image).await.is_err() {
         println!("Pulling image {}", image);
 
-        let mut info_stream = docker.create_image(
-            Some(CreateImageOptions {
-                from_image: if image.contains(':') {
-                    image.to_string()
-                } else {
-                    format!("{}:latest", image)
-                },
-                ..Default::default()
-            }),
-            None,
-            None,
-        );
+        let mut info_stream = docker.create_image(Some(CreateImageOptions {
+            from_image: if image.contains(':') {
+                image.to_string()
+            } else {
+                format!("{}:latest", image)
+            },
+            ..Default::default()
+        }));
 
         let mut bars: HashMap<String, u16> = HashMap::new();
 
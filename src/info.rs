@@ -0,0 +1,302 @@
+use crate::cloud::Cloud;
+use crate::config::HazeConfig;
+use crate::docker::{container_ip, Docker};
+use crate::service::ServiceTrait;
+use chrono::{DateTime, Local, Utc};
+use itertools::Itertools;
+use miette::{Report, Result};
+use serde::Serialize;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(Report::msg(format!("Unknown output format: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CloudInfo {
+    pub id: String,
+    pub php: &'static str,
+    pub db: DatabaseInfo,
+    pub services: Vec<ServiceInfo>,
+    pub pinned: bool,
+    pub ip: Option<IpAddr>,
+    pub address: String,
+    pub created: DateTime<Utc>,
+    pub containers: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatabaseInfo {
+    #[serde(rename = "type")]
+    pub ty: &'static str,
+    pub container: Option<String>,
+    pub healthy: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<DatabaseCredentials>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatabaseCredentials {
+    pub ip: Option<IpAddr>,
+    pub username: &'static str,
+    pub password: &'static str,
+    pub database: &'static str,
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServiceInfo {
+    pub name: String,
+    pub container: Option<String>,
+    pub healthy: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl CloudInfo {
+    pub fn new(cloud: &Cloud) -> CloudInfo {
+        CloudInfo {
+            id: cloud.id.clone(),
+            php: cloud.php().name(),
+            db: DatabaseInfo {
+                ty: cloud.db().family().name(),
+                container: cloud.db().container_name(&cloud.id),
+                healthy: None,
+                credentials: None,
+            },
+            services: cloud
+                .services()
+                .map(|service| ServiceInfo {
+                    name: service.name().to_string(),
+                    container: service.container_name(&cloud.id),
+                    healthy: None,
+                    ip: None,
+                    endpoint: None,
+                    message: None,
+                })
+                .collect(),
+            pinned: cloud.pinned,
+            ip: cloud.ip,
+            address: cloud.address.clone(),
+            created: cloud.created,
+            containers: cloud
+                .containers
+                .iter()
+                .map(|container| container.trim_start_matches('/').to_string())
+                .collect(),
+        }
+    }
+
+    /// Check the health of the database and services of a running instance
+    pub async fn check_health(&mut self, docker: &Docker, cloud: &Cloud) {
+        if cloud.ip.is_none() {
+            return;
+        }
+        self.db.healthy = cloud.db().is_healthy(docker, &cloud.id, "").await.ok();
+        for (service_info, service) in self.services.iter_mut().zip(cloud.services()) {
+            service_info.healthy = service
+                .is_healthy(docker, &cloud.id, &cloud.options)
+                .await
+                .ok();
+        }
+    }
+
+    /// Add database credentials, service endpoints and service start messages
+    pub async fn add_details(&mut self, docker: &Docker, cloud: &Cloud, config: &HazeConfig) {
+        let db = cloud.db();
+        let (username, password, database) = db.credentials();
+        let db_ip = db.ip(docker, &cloud.id).await;
+        self.db.credentials = Some(DatabaseCredentials {
+            ip: db_ip,
+            username,
+            password,
+            database,
+            url: db_ip.and_then(|ip| db.url(ip)),
+        });
+
+        for (service_info, service) in self.services.iter_mut().zip(cloud.services()) {
+            let Some(container) = &service_info.container else {
+                continue;
+            };
+            service_info.ip = container_ip(docker, container).await;
+            service_info.endpoint = service_info.ip.map(|ip| {
+                config
+                    .proxy
+                    .addr_with_port(container, ip, service.proxy_port())
+            });
+            if service_info.ip.is_some() {
+                service_info.message = service
+                    .start_message(docker, &cloud.id)
+                    .await
+                    .ok()
+                    .flatten();
+            }
+        }
+    }
+
+    pub fn healthy(&self) -> Option<bool> {
+        let mut health = self
+            .services
+            .iter()
+            .map(|service| service.healthy)
+            .chain([self.db.healthy]);
+        health.try_fold(true, |all, healthy| Some(all && healthy?))
+    }
+
+    /// Either "stopped", "healthy", "unhealthy" or "unknown" if the health wasn't checked
+    pub fn status(&self) -> &'static str {
+        match (self.ip, self.healthy()) {
+            (None, _) => "stopped",
+            (_, Some(true)) => "healthy",
+            (_, Some(false)) => "unhealthy",
+            (_, None) => "unknown",
+        }
+    }
+
+    fn service_names(&self) -> String {
+        self.services
+            .iter()
+            .map(|service| service.name.as_str())
+            .chain([self.db.ty])
+            .join(", ")
+    }
+
+    /// A single line summary of the instance
+    pub fn summary(&self) -> String {
+        let pin = if self.pinned { "*" } else { "" };
+        format!(
+            "Cloud {}{}, {}, {}, running on {}",
+            self.id,
+            pin,
+            self.php,
+            self.service_names(),
+            self.address
+        )
+    }
+
+    /// A multi-line description of everything known about the instance
+    pub fn describe(&self) -> String {
+        let mut out = String::new();
+        let pin = if self.pinned { " (pinned)" } else { "" };
+        let _ = writeln!(out, "Cloud {}{}", self.id, pin);
+        let _ = writeln!(out, "  address: {}", self.address);
+        if let Some(ip) = self.ip {
+            let _ = writeln!(out, "  ip: {}", ip);
+        }
+        let _ = writeln!(out, "  php: {}", self.php);
+        let _ = writeln!(out, "  created: {}", format_time(self.created));
+        let _ = writeln!(out, "  status: {}", self.status());
+        let _ = writeln!(out, "  containers: {}", self.containers.join(", "));
+
+        let _ = writeln!(out, "Database: {}", self.db.ty);
+        if let Some(container) = &self.db.container {
+            let _ = writeln!(out, "  container: {}", container);
+        }
+        if let Some(credentials) = &self.db.credentials {
+            if let Some(ip) = credentials.ip {
+                let _ = writeln!(out, "  ip: {}", ip);
+            }
+            let _ = writeln!(out, "  username: {}", credentials.username);
+            let _ = writeln!(out, "  password: {}", credentials.password);
+            let _ = writeln!(out, "  database: {}", credentials.database);
+            if let Some(url) = &credentials.url {
+                let _ = writeln!(out, "  url: {}", url);
+            }
+        }
+
+        if !self.services.is_empty() {
+            let _ = writeln!(out, "Services:");
+        }
+        for service in &self.services {
+            let _ = write!(out, "  {}", service.name);
+            if let Some(container) = &service.container {
+                let _ = write!(out, " ({})", container);
+            }
+            match service.healthy {
+                Some(true) => out.push_str(": healthy"),
+                Some(false) => out.push_str(": unhealthy"),
+                None => {}
+            }
+            out.push('\n');
+            if let Some(endpoint) = &service.endpoint {
+                let _ = writeln!(out, "    endpoint: {}", endpoint);
+            }
+            if let Some(message) = &service.message {
+                let _ = writeln!(out, "    {}", message);
+            }
+        }
+        out
+    }
+}
+
+/// Format a list of instances as an aligned table
+pub fn format_table(infos: &[CloudInfo]) -> String {
+    let header = [
+        "ID", "PHP", "DB", "SERVICES", "PINNED", "ADDRESS", "CREATED", "STATUS",
+    ];
+    let rows: Vec<[String; 8]> = infos
+        .iter()
+        .map(|info| {
+            [
+                info.id.clone(),
+                info.php.to_string(),
+                info.db.ty.to_string(),
+                info.services
+                    .iter()
+                    .map(|service| service.name.as_str())
+                    .join(","),
+                if info.pinned { "yes" } else { "no" }.to_string(),
+                info.address.clone(),
+                format_time(info.created),
+                info.status().to_string(),
+            ]
+        })
+        .collect();
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let format_row = |cells: &mut dyn Iterator<Item = &str>| {
+        let line = cells
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .join("  ");
+        format!("{}\n", line.trim_end())
+    };
+    let mut table = format_row(&mut header.into_iter());
+    for row in &rows {
+        table.push_str(&format_row(&mut row.iter().map(String::as_str)));
+    }
+    table
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
@@ -0,0 +1,74 @@
+//! Easy setup and management of Nextcloud test instances using docker
+//!
+//! Besides the `haze` command line tool, the instances can be managed from rust:
+//!
+//! ```no_run
+//! use haze::{setup, Cloud, CloudOptions, HazeConfig};
+//! # async fn run() -> miette::Result<()> {
+//! let docker = haze::docker::connect()?;
+//! let config = HazeConfig::load()?;
+//! let cloud = setup(&docker, CloudOptions::default(), &config).await?;
+//! cloud
+//!     .occ(&docker, vec!["app:enable", "deck"], None, Vec::<String>::new())
+//!     .await?
+//!     .to_result()?;
+//! cloud.destroy(&docker).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! All docker calls go through the [`docker::DockerBackend`] trait, the in-memory [`docker::fake::FakeDocker`]
+//! allows testing code that manages instances without a docker daemon.
+
+mod capture;
+mod chaos;
+mod cloud;
+pub mod config;
+mod copy;
+mod daemon;
+mod dashboard;
+mod database;
+mod dns;
+pub mod docker;
+mod exec;
+mod git;
+mod hooks;
+mod image;
+mod info;
+mod mapping;
+mod network;
+mod php;
+mod proxy;
+mod service;
+mod setup;
+mod status;
+mod tls;
+mod tty;
+
+pub use cloud::{Cloud, CloudOptions};
+pub use config::HazeConfig;
+pub use copy::Owner;
+pub use database::{Database, DatabaseFamily};
+pub use exec::ExitCode;
+pub use php::PhpVersion;
+pub use service::{Service, ServiceTrait};
+pub use setup::setup;
+pub use status::{ComponentStatus, ContainerStatus};
+
+/// Internals used by the `haze` command line tool, these are not part of the public api
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::capture::{follow_requests, to_har};
+    pub use crate::chaos::{chaos, ChaosAction};
+    pub use crate::copy::{download, upload};
+    pub use crate::daemon::daemon;
+    pub use crate::dns::dns;
+    pub use crate::exec::{container_logs, exec_tty};
+    pub use crate::git::checkout_all;
+    pub use crate::info::{format_table, CloudInfo, OutputFormat};
+    pub use crate::network::clear_networks;
+    pub use crate::proxy::proxy;
+    pub use crate::service::RedisTls;
+}
+
+use miette::Result;
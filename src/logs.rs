@@ -0,0 +1,400 @@
+use bollard::container::{LogOutput, LogsOptions};
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use futures_util::future::join_all;
+use futures_util::StreamExt;
+use haze::docker::Docker;
+use haze::Cloud;
+use miette::{IntoDiagnostic, Result};
+use regex::Regex;
+use std::io::Write;
+use std::mem::{replace, take};
+use std::time::Duration;
+use termion::color::{AnsiValue, Fg, Reset};
+use tokio::select;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::spawn;
+use tokio::time::interval;
+
+/// Colors used for the container prefixes, cycled through in order
+const COLORS: [u8; 12] = [6, 2, 3, 5, 4, 1, 14, 10, 11, 13, 12, 9];
+
+/// How long lines from followed containers are held back to allow sorting lines that arrive out of order
+const REORDER_WINDOW: Duration = Duration::from_millis(250);
+
+/// A container to show the logs of, with the name used in the prefix
+#[derive(Debug, Clone)]
+pub struct LogSource {
+    pub name: String,
+    pub container: String,
+}
+
+impl LogSource {
+    /// All containers belonging to an instance
+    pub fn all(cloud: &Cloud) -> Vec<LogSource> {
+        cloud
+            .containers
+            .iter()
+            .map(|container| {
+                let container = container.trim_start_matches('/');
+                let name = container
+                    .strip_prefix(cloud.id.as_str())
+                    .unwrap_or(container)
+                    .trim_start_matches('-');
+                LogSource {
+                    name: if name.is_empty() { "cloud" } else { name }.to_string(),
+                    container: container.to_string(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct LogLine {
+    time: DateTime<Utc>,
+    source: usize,
+    line: String,
+}
+
+/// The lines of a container that were already shown before following its logs
+#[derive(Debug)]
+struct Shown {
+    /// Time of the last shown line
+    since: DateTime<Utc>,
+    /// Contents of the shown lines with exactly that time
+    lines: Vec<String>,
+}
+
+impl Shown {
+    fn new(lines: &[LogLine], start: DateTime<Utc>) -> Self {
+        let since = lines.last().map(|line| line.time).unwrap_or(start);
+        Shown {
+            since,
+            lines: lines
+                .iter()
+                .filter(|line| line.time == since)
+                .map(|line| line.line.clone())
+                .collect(),
+        }
+    }
+
+    /// Check if a followed line wasn't shown yet
+    ///
+    /// Lines with the same time as the last shown line are only skipped if they were shown, since docker can only
+    /// follow logs from the start of a second and multiple lines can have the same timestamp.
+    fn is_new(&mut self, line: &LogLine) -> bool {
+        if line.time != self.since {
+            return line.time > self.since;
+        }
+        match self.lines.iter().position(|shown| *shown == line.line) {
+            Some(index) => {
+                self.lines.remove(index);
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+/// Show the logs of multiple containers, interleaved by timestamp and prefixed with the container
+pub async fn multiplexed_logs(
+    docker: &Docker,
+    mut out: impl Write,
+    sources: &[LogSource],
+    count: usize,
+    follow: bool,
+    filter: Option<&Regex>,
+    colored: bool,
+) -> Result<()> {
+    let start = Utc::now();
+    let printer = LinePrinter {
+        sources,
+        filter,
+        colored,
+        width: sources
+            .iter()
+            .map(|source| source.name.len())
+            .max()
+            .unwrap_or_default(),
+    };
+
+    let tails = join_all(
+        sources
+            .iter()
+            .enumerate()
+            .map(|(index, source)| read_logs(docker, &source.container, index, count)),
+    )
+    .await;
+    let shown: Vec<Shown> = tails.iter().map(|lines| Shown::new(lines, start)).collect();
+    let mut lines: Vec<LogLine> = tails.into_iter().flatten().collect();
+    lines.sort_by_key(|line| line.time);
+    for line in &lines {
+        printer.print(&mut out, line)?;
+    }
+
+    if !follow {
+        return Ok(());
+    }
+
+    let (tx, mut rx) = unbounded_channel();
+    for ((index, source), shown) in sources.iter().enumerate().zip(shown) {
+        spawn(follow_logs(
+            docker.clone(),
+            source.container.clone(),
+            index,
+            shown,
+            tx.clone(),
+        ));
+    }
+    drop(tx);
+
+    let mut pending: Vec<LogLine> = Vec::new();
+    let mut tick = interval(REORDER_WINDOW);
+    loop {
+        select! {
+            line = rx.recv() => match line {
+                Some(line) => pending.push(line),
+                None => break,
+            },
+            _ = tick.tick() => {
+                let cutoff = Utc::now() - TimeDelta::from_std(REORDER_WINDOW).unwrap_or_default();
+                pending.sort_by_key(|line| line.time);
+                let ready = pending.partition_point(|line| line.time <= cutoff);
+                for line in pending.drain(..ready) {
+                    printer.print(&mut out, &line)?;
+                }
+            }
+        }
+    }
+    pending.sort_by_key(|line| line.time);
+    for line in &pending {
+        printer.print(&mut out, line)?;
+    }
+    Ok(())
+}
+
+struct LinePrinter<'a> {
+    sources: &'a [LogSource],
+    filter: Option<&'a Regex>,
+    colored: bool,
+    width: usize,
+}
+
+impl LinePrinter<'_> {
+    fn print(&self, mut out: impl Write, line: &LogLine) -> Result<()> {
+        if let Some(filter) = self.filter {
+            if !filter.is_match(&line.line) {
+                return Ok(());
+            }
+        }
+        let time = line.time.with_timezone(&Local).format("%H:%M:%S%.3f");
+        let name = &self.sources[line.source].name;
+        let width = self.width;
+        if self.colored {
+            let color = Fg(AnsiValue(COLORS[line.source % COLORS.len()]));
+            writeln!(
+                out,
+                "{time} {color}{name:width$}{} | {}",
+                Fg(Reset),
+                line.line
+            )
+        } else {
+            writeln!(out, "{time} {name:width$} | {}", line.line)
+        }
+        .into_diagnostic()
+    }
+}
+
+/// Read the last `count` lines of a container's logs
+async fn read_logs(docker: &Docker, container: &str, source: usize, count: usize) -> Vec<LogLine> {
+    let mut stream = docker.logs(
+        container,
+        Some(LogsOptions {
+            stdout: true,
+            stderr: true,
+            timestamps: true,
+            tail: format!("{}", count),
+            ..Default::default()
+        }),
+    );
+    let mut lines = Vec::new();
+    let mut parser = LineParser::new(source, DateTime::<Utc>::MIN_UTC);
+    while let Some(Ok(output)) = stream.next().await {
+        lines.extend(parser.parse(output));
+    }
+    lines.extend(parser.finish());
+    lines
+}
+
+/// Follow the logs of a container, skipping the lines that were already shown
+async fn follow_logs(
+    docker: Docker,
+    container: String,
+    source: usize,
+    mut shown: Shown,
+    tx: UnboundedSender<LogLine>,
+) {
+    let mut stream = docker.logs(
+        &container,
+        Some(LogsOptions {
+            stdout: true,
+            stderr: true,
+            follow: true,
+            timestamps: true,
+            since: shown.since.timestamp(),
+            tail: "all".to_string(),
+            ..Default::default()
+        }),
+    );
+    let mut parser = LineParser::new(source, shown.since);
+    while let Some(Ok(output)) = stream.next().await {
+        for line in parser.parse(output) {
+            if shown.is_new(&line) && tx.send(line).is_err() {
+                return;
+            }
+        }
+    }
+    for line in parser.finish() {
+        if shown.is_new(&line) {
+            tx.send(line).ok();
+        }
+    }
+}
+
+/// Splits the log output of a container into lines
+///
+/// Docker splits lines longer than 16KiB over multiple frames, so the incomplete last line of stdout and stderr is
+/// kept until the rest of it arrives.
+struct LineParser {
+    source: usize,
+    /// Timestamp of the previous line, used for lines without timestamp
+    last: DateTime<Utc>,
+    partial: [Vec<u8>; 2],
+}
+
+impl LineParser {
+    fn new(source: usize, last: DateTime<Utc>) -> Self {
+        LineParser {
+            source,
+            last,
+            partial: Default::default(),
+        }
+    }
+
+    /// Get the lines completed by a chunk of log output
+    fn parse(&mut self, output: LogOutput) -> Vec<LogLine> {
+        let stream = matches!(output, LogOutput::StdErr { .. }) as usize;
+        let output = output.into_bytes();
+        let partial = &mut self.partial[stream];
+        if partial.is_empty() {
+            partial.extend_from_slice(&output);
+        } else {
+            // every frame of a split line has its own timestamp
+            partial.extend_from_slice(strip_timestamp(&output).unwrap_or(&output));
+        }
+        let Some(end) = partial.iter().rposition(|byte| *byte == b'\n') else {
+            return Vec::new();
+        };
+        let rest = partial.split_off(end + 1);
+        let complete = replace(partial, rest);
+        self.parse_lines(&complete)
+    }
+
+    /// Get the incomplete lines left at the end of the output
+    fn finish(&mut self) -> Vec<LogLine> {
+        take(&mut self.partial)
+            .iter()
+            .flat_map(|partial| self.parse_lines(partial))
+            .collect()
+    }
+
+    /// Parse complete lines, lines without timestamp get the timestamp of the previous line
+    fn parse_lines(&mut self, output: &[u8]) -> Vec<LogLine> {
+        String::from_utf8_lossy(output)
+            .lines()
+            .map(|line| {
+                let parsed = line.split_once(' ').and_then(|(time, rest)| {
+                    Some((DateTime::parse_from_rfc3339(time).ok()?.to_utc(), rest))
+                });
+                let line = match parsed {
+                    Some((time, rest)) => {
+                        self.last = time;
+                        rest
+                    }
+                    None => line,
+                };
+                LogLine {
+                    time: self.last,
+                    source: self.source,
+                    line: line.to_string(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Strip the timestamp docker adds in front of log output
+fn strip_timestamp(output: &[u8]) -> Option<&[u8]> {
+    let space = output.iter().position(|byte| *byte == b' ')?;
+    let time = std::str::from_utf8(&output[..space]).ok()?;
+    DateTime::parse_from_rfc3339(time).ok()?;
+    Some(&output[space + 1..])
+}
+
+#[test]
+fn test_shown_lines() {
+    let time = |time: &str| DateTime::parse_from_rfc3339(time).unwrap().to_utc();
+    let line = |at: &str, line: &str| LogLine {
+        time: time(at),
+        source: 0,
+        line: line.into(),
+    };
+    let tail = [
+        line("2024-01-01T10:00:00.100Z", "first"),
+        line("2024-01-01T10:00:00.500Z", "same"),
+        line("2024-01-01T10:00:00.500Z", "second"),
+    ];
+    let mut shown = Shown::new(&tail, time("2024-01-01T10:00:01Z"));
+
+    // docker follows from the start of the second of the last shown line
+    assert!(!shown.is_new(&tail[0]));
+    assert!(!shown.is_new(&tail[1]));
+    assert!(!shown.is_new(&tail[2]));
+    assert!(shown.is_new(&line("2024-01-01T10:00:00.500Z", "same")));
+    assert!(shown.is_new(&line("2024-01-01T10:00:00.500Z", "third")));
+    assert!(shown.is_new(&line("2024-01-01T10:00:00.700Z", "fourth")));
+}
+
+#[test]
+fn test_split_lines() {
+    let time = |time: &str| DateTime::parse_from_rfc3339(time).unwrap().to_utc();
+    let stdout = |message: &str| LogOutput::StdOut {
+        message: message.to_string().into(),
+    };
+    let stderr = |message: &str| LogOutput::StdErr {
+        message: message.to_string().into(),
+    };
+    let mut parser = LineParser::new(0, DateTime::<Utc>::MIN_UTC);
+
+    assert!(parser
+        .parse(stdout("2024-01-01T10:00:00.100Z first part "))
+        .is_empty());
+    let error = parser.parse(stderr("2024-01-01T10:00:00.200Z error\n"));
+    assert_eq!(1, error.len());
+    assert_eq!("error", error[0].line);
+    let lines = parser.parse(stdout(
+        "2024-01-01T10:00:00.300Z second part\n2024-01-01T10:00:00.400Z next\n",
+    ));
+    assert_eq!(2, lines.len());
+    assert_eq!("first part second part", lines[0].line);
+    assert_eq!(time("2024-01-01T10:00:00.100Z"), lines[0].time);
+    assert_eq!("next", lines[1].line);
+    assert_eq!(time("2024-01-01T10:00:00.400Z"), lines[1].time);
+
+    assert!(parser
+        .parse(stdout("2024-01-01T10:00:00.500Z unterminated"))
+        .is_empty());
+    let rest = parser.finish();
+    assert_eq!(1, rest.len());
+    assert_eq!("unterminated", rest[0].line);
+}
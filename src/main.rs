@@ -1,36 +1,42 @@
-extern crate core;
-
-use crate::args::{ExecService, HazeArgs};
-use crate::cloud::{Cloud, CloudOptions};
-use crate::config::HazeConfig;
-use crate::database::DatabaseFamily;
-use crate::exec::container_logs;
-use crate::git::checkout_all;
-use crate::network::clear_networks;
-use crate::proxy::proxy;
-use crate::service::ServiceTrait;
-use crate::service::{RedisTls, Service};
-use bollard::Docker;
+use crate::args::{CopyPath, ExecService, HazeArgs, RequestsAction};
+use crate::logs::{multiplexed_logs, LogSource};
+use crate::nextcloud_log::show_log;
+use camino::Utf8Path;
+use haze::cli::chaos;
+use haze::cli::checkout_all;
+use haze::cli::clear_networks;
+use haze::cli::daemon;
+use haze::cli::dns;
+use haze::cli::proxy;
+use haze::cli::RedisTls;
+use haze::cli::{container_logs, exec_tty};
+use haze::cli::{download, upload};
+use haze::cli::{follow_requests, to_har};
+use haze::cli::{format_table, CloudInfo, OutputFormat};
+use haze::docker::{connect, Docker};
+use haze::DatabaseFamily;
+use haze::HazeConfig;
+use haze::{Cloud, CloudOptions};
+use haze::{Service, ServiceTrait};
 use itertools::Itertools;
 use miette::{IntoDiagnostic, Report, Result, WrapErr};
+use regex::Regex;
+use serde::Serialize;
 use std::env::vars;
 use std::fs::{create_dir_all, write};
-use std::io::stdout;
+use std::io::{stderr, stdout};
 use std::os::unix::process::CommandExt;
 use std::process::{Command, ExitCode};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::debug;
 
 mod args;
-mod cloud;
-mod config;
-mod database;
-mod exec;
-mod git;
-mod image;
-mod mapping;
-mod network;
-mod php;
-mod proxy;
-mod service;
+mod logs;
+mod nextcloud_log;
+
+/// Exit code used by `haze wait` when the instance didn't start in time, matching `timeout(1)`
+const WAIT_TIMEOUT_EXIT_CODE: u8 = 124;
 
 static FORWARD_ENV: &[&str] = &[
     "OCC_LOG",
@@ -40,6 +46,52 @@ static FORWARD_ENV: &[&str] = &[
     "XDEBUG_CONFIG",
 ];
 
+/// Get the container and absolute path for a path in an instance
+fn instance_path(
+    cloud: &Cloud,
+    service: Option<&ExecService>,
+    path: &str,
+) -> Result<(String, String)> {
+    match service {
+        None => Ok((cloud.id.clone(), cloud.container_path(path))),
+        Some(service) => {
+            let container = service
+                .container_name(cloud)
+                .ok_or_else(|| Report::msg("Service doesn't have a container"))?;
+            if !path.starts_with('/') {
+                return Err(Report::msg(
+                    "Paths in service containers need to be absolute",
+                ));
+            }
+            Ok((container, path.to_string()))
+        }
+    }
+}
+
+/// Create a new instance and install it if auto setup is enabled, printing the progress along the way
+async fn start(docker: &Docker, options: CloudOptions, config: &HazeConfig) -> Result<Cloud> {
+    let cloud = Cloud::create(docker, options, config).await?;
+    println!("{}", cloud.address);
+    if config.auto_setup.enabled {
+        println!("Waiting for servers to start");
+        cloud.wait_for_start(docker, &config.timeouts).await?;
+        for message in cloud.service_messages(docker).await? {
+            println!("{}", message);
+        }
+        println!(
+            "Installing with username {} and password {}",
+            config.auto_setup.username, config.auto_setup.password
+        );
+        cloud.install(docker, config).await?;
+    }
+    Ok(cloud)
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value).into_diagnostic()?);
+    Ok(())
+}
+
 fn get_forward_env() -> Vec<String> {
     vars()
         .filter(|(var, _)| FORWARD_ENV.contains(&var.as_str()))
@@ -52,9 +104,7 @@ async fn main() -> Result<ExitCode> {
     miette::set_panic_hook();
     tracing_subscriber::fmt::init();
 
-    let docker = Docker::connect_with_local_defaults()
-        .into_diagnostic()
-        .wrap_err("Failed to connect to docker")?;
+    let docker = connect()?;
     let config = HazeConfig::load().wrap_err("Failed to load config")?;
 
     let args = HazeArgs::parse(&config.preset, std::env::args())?;
@@ -69,25 +119,39 @@ async fn main() -> Result<ExitCode> {
             }
             clear_networks(&docker).await?;
         }
-        HazeArgs::List { filter } => {
+        HazeArgs::List { filter, format } => {
             let list = Cloud::list(&docker, filter, &config).await?;
-            for cloud in list {
-                let mut services: Vec<_> = cloud.services().map(Service::name).collect();
-                services.push(cloud.db().name());
-                let services = services.join(", ");
-                let pin = if cloud.pinned { "*" } else { "" };
-                println!(
-                    "Cloud {}{}, {}, {}, running on {}",
-                    cloud.id,
-                    pin,
-                    cloud.php().name(),
-                    services,
-                    cloud.address
-                );
+            if format == OutputFormat::Text {
+                for cloud in list {
+                    println!("{}", CloudInfo::new(&cloud).summary());
+                }
+            } else {
+                let mut infos = Vec::with_capacity(list.len());
+                for cloud in &list {
+                    let mut info = CloudInfo::new(cloud);
+                    info.check_health(&docker, cloud).await;
+                    infos.push(info);
+                }
+                if format == OutputFormat::Json {
+                    print_json(&infos)?;
+                } else {
+                    print!("{}", format_table(&infos));
+                }
+            }
+        }
+        HazeArgs::Info { filter, format } => {
+            let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
+            let mut info = CloudInfo::new(&cloud);
+            info.check_health(&docker, &cloud).await;
+            info.add_details(&docker, &cloud, &config).await;
+            if format == OutputFormat::Json {
+                print_json(&info)?;
+            } else {
+                print!("{}", info.describe());
             }
         }
         HazeArgs::Start { options } => {
-            setup(&docker, options, &config).await?;
+            start(&docker, options, &config).await?;
         }
         HazeArgs::Stop { filter } => {
             let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
@@ -98,28 +162,61 @@ async fn main() -> Result<ExitCode> {
             follow,
             count,
             service,
+            all,
+            grep,
         } => {
             let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
-            let container = if let Some(service) = service {
+            let container = if let Some(service) = &service {
                 service
                     .container_name(&cloud.id)
                     .ok_or_else(|| Report::msg("service has no logs".to_string()))?
             } else {
-                cloud.id
+                cloud.id.clone()
             };
-            container_logs(&docker, stdout(), &container, count.unwrap_or(20), follow).await?;
+            let count = count.unwrap_or(20);
+            if all || grep.is_some() {
+                let mut sources = LogSource::all(&cloud);
+                if !all {
+                    sources.retain(|source| source.container == container);
+                    if sources.is_empty() {
+                        return Err(Report::msg(format!(
+                            "{} is not a container of {}",
+                            container, cloud.id
+                        )));
+                    }
+                }
+                let grep = grep
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()
+                    .into_diagnostic()?;
+                multiplexed_logs(
+                    &docker,
+                    stdout().lock(),
+                    &sources,
+                    count,
+                    follow,
+                    grep.as_ref(),
+                    termion::is_tty(&stdout()),
+                )
+                .await?;
+            } else {
+                container_logs(&docker, stdout(), &container, count, follow).await?;
+            }
         }
         HazeArgs::Exec {
             filter,
             service,
+            user,
             command,
         } => {
             let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
-            match service {
+            let result = match service {
                 None => {
                     cloud
-                        .exec(
+                        .exec_as(
                             &docker,
+                            user.as_deref().unwrap_or("haze"),
                             if command.is_empty() {
                                 vec!["bash".to_string()]
                             } else {
@@ -128,7 +225,7 @@ async fn main() -> Result<ExitCode> {
                             atty::is(atty::Stream::Stdout),
                             get_forward_env(),
                         )
-                        .await?;
+                        .await?
                 }
                 Some(ExecService::Db) => {
                     cloud
@@ -136,6 +233,7 @@ async fn main() -> Result<ExitCode> {
                         .exec_sh(
                             &docker,
                             &cloud.id,
+                            user.as_deref().unwrap_or("root"),
                             if command.is_empty() {
                                 vec!["bash".to_string()]
                             } else {
@@ -143,9 +241,28 @@ async fn main() -> Result<ExitCode> {
                             },
                             atty::is(atty::Stream::Stdout),
                         )
-                        .await?;
+                        .await?
                 }
-            }
+                Some(ExecService::Service(service)) => {
+                    let container = service.container_name(&cloud.id).ok_or_else(|| {
+                        Report::msg(format!("{} doesn't have a container", service.name()))
+                    })?;
+                    // an empty user runs the command as the default user of the image
+                    exec_tty(
+                        &docker,
+                        container,
+                        user.as_deref().unwrap_or(""),
+                        if command.is_empty() {
+                            vec!["sh".to_string()]
+                        } else {
+                            command
+                        },
+                        Vec::<String>::default(),
+                    )
+                    .await?
+                }
+            };
+            return Ok(result.into());
         }
         HazeArgs::Occ {
             filter,
@@ -153,7 +270,7 @@ async fn main() -> Result<ExitCode> {
         } => {
             let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
             command.insert(0, "occ".to_string());
-            cloud
+            let result = cloud
                 .exec(
                     &docker,
                     command,
@@ -161,6 +278,7 @@ async fn main() -> Result<ExitCode> {
                     get_forward_env(),
                 )
                 .await?;
+            return Ok(result.into());
         }
         HazeArgs::Db {
             filter,
@@ -168,7 +286,8 @@ async fn main() -> Result<ExitCode> {
             command,
         } => {
             let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
-            cloud.db().exec(&docker, &cloud.id, root, &command).await?;
+            let result = cloud.db().exec(&docker, &cloud.id, root, &command).await?;
+            return Ok(result.into());
         }
         HazeArgs::Open { filter } => {
             let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
@@ -180,17 +299,9 @@ async fn main() -> Result<ExitCode> {
         HazeArgs::Test { options, mut args } => {
             let cloud = Cloud::create(&docker, options, &config).await?;
             println!("Waiting for servers to start");
-            cloud.wait_for_start(&docker).await?;
+            cloud.wait_for_start(&docker, &config.timeouts).await?;
 
-            if !cloud.preset_config.is_empty() {
-                println!("Writing preset config");
-                let encoded_preset_config =
-                    serde_json::to_string(&cloud.preset_config).into_diagnostic()?;
-                cloud
-                    .write_file(&docker, "config/preset.config.json", encoded_preset_config)
-                    .await?;
-                cloud.write_file(&docker, "config/preset.config.php", "<?php $CONFIG=json_decode(file_get_contents(__DIR__ . '/preset.config.json'), true);").await?;
-            }
+            cloud.write_preset_config(&docker).await?;
 
             println!("Installing");
             if let Err(e) = cloud
@@ -229,7 +340,7 @@ async fn main() -> Result<ExitCode> {
         HazeArgs::Integration { options, mut args } => {
             let cloud = Cloud::create(&docker, options, &config).await?;
             println!("Waiting for servers to start");
-            cloud.wait_for_start(&docker).await?;
+            cloud.wait_for_start(&docker, &config.timeouts).await?;
             println!("Installing");
             if let Err(e) = cloud
                 .exec(
@@ -255,7 +366,7 @@ async fn main() -> Result<ExitCode> {
             let cloud = Cloud::create(&docker, CloudOptions::default(), &config).await?;
             let mut out_buffer = Vec::<u8>::with_capacity(1024);
             println!("Waiting for servers to start");
-            cloud.wait_for_start(&docker).await?;
+            cloud.wait_for_start(&docker, &config.timeouts).await?;
             println!("Installing composer");
             if let Err(e) = cloud
                 .exec_with_output(
@@ -317,7 +428,7 @@ async fn main() -> Result<ExitCode> {
             cloud.destroy(&docker).await?;
         }
         HazeArgs::Shell { command, options } => {
-            let cloud = setup(&docker, options, &config).await?;
+            let cloud = start(&docker, options, &config).await?;
             cloud
                 .exec(
                     &docker,
@@ -343,6 +454,186 @@ async fn main() -> Result<ExitCode> {
         HazeArgs::Proxy => {
             proxy(docker, config).await?;
         }
+        HazeArgs::Dns => {
+            dns(docker, config).await?;
+        }
+        HazeArgs::Daemon { proxy: with_proxy } => {
+            if with_proxy {
+                tokio::try_join!(
+                    proxy(docker.clone(), config.clone()),
+                    daemon(docker, config)
+                )?;
+            } else {
+                daemon(docker, config).await?;
+            }
+        }
+        HazeArgs::Requests { filter, action } => {
+            let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
+            match action {
+                RequestsAction::Start => {
+                    cloud.set_capture(true)?;
+                    println!("Capturing requests to {}", cloud.id);
+                }
+                RequestsAction::Stop => {
+                    cloud.set_capture(false)?;
+                    println!("Stopped capturing requests to {}", cloud.id);
+                }
+                RequestsAction::Clear => {
+                    cloud.clear_captured_requests()?;
+                    println!("Removed the captured requests of {}", cloud.id);
+                }
+                RequestsAction::Har(path) => {
+                    let har = serde_json::to_string_pretty(&to_har(&cloud.captured_requests()?))
+                        .into_diagnostic()?;
+                    match path {
+                        Some(path) => write(&path, har)
+                            .into_diagnostic()
+                            .wrap_err_with(|| format!("Failed to write {}", path))?,
+                        None => println!("{har}"),
+                    }
+                }
+                RequestsAction::Follow => {
+                    if !cloud.capture_enabled() {
+                        eprintln!(
+                            "Request capture isn't enabled for {}, use `haze {} requests start` to enable it",
+                            cloud.id, cloud.id
+                        );
+                    }
+                    follow_requests(&cloud, stdout(), 20).await?;
+                }
+            }
+        }
+        HazeArgs::Chaos {
+            filter,
+            service,
+            action,
+        } => {
+            let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
+            chaos(&docker, &cloud, &service, action).await?;
+        }
+        HazeArgs::Status { filter } => {
+            let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
+            let status = cloud.status(&docker).await;
+            let name_width = status.iter().map(|s| s.name.len()).max().unwrap_or(0);
+            let container_width = status
+                .iter()
+                .filter_map(|s| s.container.as_ref())
+                .map(String::len)
+                .max()
+                .unwrap_or(0);
+            for component in &status {
+                println!(
+                    "{:name_width$}  {:container_width$}  {}",
+                    component.name,
+                    component.container.as_deref().unwrap_or("-"),
+                    component.state
+                );
+            }
+            if !status.iter().all(|component| component.state.is_ok()) {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        HazeArgs::Wait { filter, timeout } => {
+            let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
+            if cloud.ip.is_none() {
+                return Err(Report::msg(format!("{} is not running", cloud.id)));
+            }
+            let wait = async {
+                while let Err(e) = cloud.wait_for_start(&docker, &config.timeouts).await {
+                    debug!(error = ?e, "instance not started yet");
+                    sleep(Duration::from_secs(1)).await;
+                }
+                if config.auto_setup.enabled {
+                    while !cloud.is_installed(&docker).await.unwrap_or(false) {
+                        sleep(Duration::from_millis(500)).await;
+                    }
+                }
+            };
+            if tokio::time::timeout(timeout, wait).await.is_err() {
+                eprintln!(
+                    "Timeout after {} seconds waiting for {}",
+                    timeout.as_secs(),
+                    cloud.id
+                );
+                for component in cloud.status(&docker).await {
+                    if component.state.is_ok() {
+                        continue;
+                    }
+                    eprintln!("{} is {}", component.name, component.state);
+                    if let Some(container) = &component.container {
+                        container_logs(&docker, stderr(), container, 20, false).await?;
+                    }
+                }
+                return Ok(ExitCode::from(WAIT_TIMEOUT_EXIT_CODE));
+            }
+        }
+        HazeArgs::Cp { source, target } => match (source, target) {
+            (
+                CopyPath::Host(source),
+                CopyPath::Instance {
+                    filter,
+                    service,
+                    path,
+                },
+            ) => {
+                let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
+                let (container, path) = instance_path(&cloud, service.as_ref(), &path)?;
+                let owner = cloud.owner(&docker).await?;
+                upload(&docker, &container, Utf8Path::new(&source), &path, owner).await?;
+            }
+            (
+                CopyPath::Instance {
+                    filter,
+                    service,
+                    path,
+                },
+                CopyPath::Host(target),
+            ) => {
+                let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
+                let (container, path) = instance_path(&cloud, service.as_ref(), &path)?;
+                download(&docker, &container, &path, Utf8Path::new(&target)).await?;
+            }
+            _ => unreachable!("validated during argument parsing"),
+        },
+        HazeArgs::Log {
+            filter,
+            follow,
+            log_filter,
+            count,
+        } => {
+            let cloud = Cloud::get_by_filter(&docker, filter, &config).await?;
+            show_log(
+                &cloud.workdir.join("data/nextcloud.log"),
+                stdout().lock(),
+                &log_filter,
+                count.unwrap_or(20),
+                follow,
+                termion::is_tty(&stdout()),
+            )
+            .await?;
+        }
+        HazeArgs::Clone { filter, name } => {
+            let source = Cloud::get_by_filter(&docker, filter, &config).await?;
+            let options = CloudOptions {
+                name,
+                ..source.options.clone()
+            };
+            let cloud = Cloud::create(&docker, options, &config).await?;
+            println!("{}", cloud.address);
+            let result = async {
+                println!("Waiting for servers to start");
+                cloud.wait_for_start(&docker, &config.timeouts).await?;
+                println!("Copying {}", source.id);
+                cloud.copy_from(&docker, &source).await?;
+                cloud.set_address_config(&docker).await
+            }
+            .await;
+            // don't leave a half copied instance around
+            if let Err(e) = result {
+                cloud.destroy(&docker).await?;
+                return Err(e);
+            }
+        }
         HazeArgs::Checkout { branch } => {
             checkout_all(&config.sources_root, &branch)?;
         }
@@ -427,126 +718,3 @@ async fn main() -> Result<ExitCode> {
 
     Ok(ExitCode::SUCCESS)
 }
-
-async fn setup(docker: &Docker, options: CloudOptions, config: &HazeConfig) -> Result<Cloud> {
-    let cloud = Cloud::create(docker, options, config).await?;
-    println!("{}", cloud.address);
-    let host = cloud.address.split_once("://").expect("no address?").1;
-    if config.auto_setup.enabled {
-        println!("Waiting for servers to start");
-        cloud.wait_for_start(docker).await?;
-
-        if !cloud.preset_config.is_empty() {
-            println!("Writing preset config");
-            let encoded_preset_config =
-                serde_json::to_string(&cloud.preset_config).into_diagnostic()?;
-            cloud
-                .write_file(docker, "config/preset.config.json", encoded_preset_config)
-                .await?;
-            cloud.write_file(docker, "config/preset.config.php", "<?php $CONFIG=json_decode(file_get_contents(__DIR__ . '/preset.config.json'), true);").await?;
-        }
-
-        println!(
-            "Installing with username {} and password {}",
-            config.auto_setup.username, config.auto_setup.password
-        );
-        let ip_str = format!("{}", cloud.ip.unwrap());
-        cloud
-            .exec(
-                docker,
-                vec![
-                    "install",
-                    &config.auto_setup.username,
-                    &config.auto_setup.password,
-                ],
-                false,
-                Vec::<String>::default(),
-            )
-            .await?;
-        cloud
-            .occ(
-                docker,
-                vec![
-                    "config:system:set",
-                    "overwrite.cli.url",
-                    "--value",
-                    &cloud.address,
-                ],
-                None,
-                Vec::<String>::default(),
-            )
-            .await?;
-        cloud
-            .occ(
-                docker,
-                vec!["config:system:set", "overwritehost", "--value", host],
-                None,
-                Vec::<String>::default(),
-            )
-            .await?;
-        if cloud.address.contains("https://") {
-            cloud
-                .occ(
-                    docker,
-                    vec!["config:system:set", "overwriteprotocol", "--value", "https"],
-                    None,
-                    Vec::<String>::default(),
-                )
-                .await?;
-        }
-
-        let domains = [ip_str.as_str(), "cloud", &cloud.id, host];
-        for (i, domain) in domains.iter().enumerate() {
-            cloud
-                .occ(
-                    docker,
-                    vec![
-                        "config:system:set",
-                        "trusted_domains",
-                        &format!("{}", i),
-                        "--value",
-                        domain,
-                    ],
-                    None,
-                    Vec::<String>::default(),
-                )
-                .await?;
-        }
-
-        for service in cloud.services() {
-            for app in service.apps() {
-                cloud
-                    .exec(
-                        docker,
-                        vec!["occ", "app:enable", *app, "--force"],
-                        false,
-                        Vec::<String>::default(),
-                    )
-                    .await?;
-            }
-        }
-        for service in cloud.services() {
-            for cmd in service.post_setup(docker, &cloud.id, config).await? {
-                cloud
-                    .exec(
-                        docker,
-                        shell_words::split(&cmd).into_diagnostic()?,
-                        false,
-                        Vec::<String>::default(),
-                    )
-                    .await?;
-            }
-        }
-        for cmd in &config.auto_setup.post_setup {
-            cloud
-                .exec(
-                    docker,
-                    shell_words::split(cmd).into_diagnostic()?,
-                    false,
-                    Vec::<String>::default(),
-                )
-                .await?;
-        }
-    }
-    Ok(cloud)
-}
@@ -1,4 +1,5 @@
 use crate::config::{HazeConfig, HazeVolumeConfig};
+use crate::tls::CA_MOUNT;
 use camino::Utf8Path;
 use miette::{IntoDiagnostic, Result};
 use tokio::fs::{create_dir_all, write};
@@ -161,6 +162,18 @@ pub fn default_mappings<'a>() -> impl IntoIterator<Item = Mapping<'a>> {
     IntoIterator::into_iter(mappings)
 }
 
+/// Mount the CA of the proxy tls termination, so instances can reach each other over https
+pub fn ca_mapping<'a>() -> Mapping<'a> {
+    Mapping::new(
+        MappingSourceType::GlobalWorkDir,
+        "proxy-ca/ca.crt",
+        CA_MOUNT,
+    )
+    .file()
+    .read_only()
+    .dont_create()
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum MappingSourceType {
     Sources,
@@ -1,10 +1,10 @@
-use bollard::Docker;
+use crate::docker::Docker;
 use bollard::network::CreateNetworkOptions;
 use miette::{IntoDiagnostic, Result, WrapErr};
 
 pub async fn clear_networks(docker: &Docker) -> Result<()> {
     let networks = docker
-        .list_networks::<&str>(None)
+        .list_networks(None)
         .await
         .into_diagnostic()
         .wrap_err("Failed to list docker networks")?;
@@ -21,7 +21,7 @@ pub async fn clear_networks(docker: &Docker) -> Result<()> {
 
 async fn get_network_id(docker: &Docker, name: &str) -> Result<Option<String>> {
     let networks = docker
-        .list_networks::<&str>(None)
+        .list_networks(None)
         .await
         .into_diagnostic()
         .wrap_err("Failed to list docker networks")?;
@@ -40,7 +40,7 @@ pub async fn ensure_network_exists(docker: &Docker, name: &str) -> Result<String
     } else {
         Ok(docker
             .create_network(CreateNetworkOptions {
-                name,
+                name: name.to_string(),
                 check_duplicate: true,
                 ..Default::default()
             })
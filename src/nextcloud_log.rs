@@ -0,0 +1,352 @@
+use camino::Utf8Path;
+use chrono::{DateTime, Local};
+use miette::{IntoDiagnostic, Report, Result, WrapErr};
+use parse_display::Display;
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::{ErrorKind, SeekFrom, Write};
+use std::str::FromStr;
+use std::time::Duration;
+use termion::color::{Fg, LightBlack, LightRed, Red, Reset, Yellow};
+use termion::style::{Bold, NoBold};
+use tokio::fs::{metadata, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::time::sleep;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Display)]
+pub enum LogLevel {
+    #[display("DEBUG")]
+    Debug,
+    #[display("INFO")]
+    Info,
+    #[display("WARN")]
+    Warn,
+    #[display("ERROR")]
+    Error,
+    #[display("FATAL")]
+    Fatal,
+}
+
+impl LogLevel {
+    fn from_number(level: u64) -> Self {
+        match level {
+            0 => LogLevel::Debug,
+            1 => LogLevel::Info,
+            2 => LogLevel::Warn,
+            3 => LogLevel::Error,
+            _ => LogLevel::Fatal,
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "0" | "debug" => Ok(LogLevel::Debug),
+            "1" | "info" => Ok(LogLevel::Info),
+            "2" | "warn" | "warning" => Ok(LogLevel::Warn),
+            "3" | "error" => Ok(LogLevel::Error),
+            "4" | "fatal" => Ok(LogLevel::Fatal),
+            _ => Err(Report::msg(format!("Unknown log level: {}", s))),
+        }
+    }
+}
+
+/// Filters for the entries shown from the nextcloud log
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LogFilter {
+    /// Minimum level of entries to show
+    pub level: Option<LogLevel>,
+    pub app: Option<String>,
+    pub req: Option<String>,
+}
+
+impl LogFilter {
+    fn matches(&self, entry: &LogEntry) -> bool {
+        self.level.is_none_or(|level| entry.level() >= level)
+            && self.app.as_ref().is_none_or(|app| &entry.app == app)
+            && self.req.as_ref().is_none_or(|req| &entry.req_id == req)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LogEntry {
+    #[serde(rename = "reqId", default)]
+    req_id: String,
+    #[serde(default)]
+    level: u64,
+    #[serde(default)]
+    time: String,
+    #[serde(default)]
+    app: String,
+    #[serde(default)]
+    method: String,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    message: Value,
+    #[serde(default)]
+    exception: Option<Value>,
+}
+
+impl LogEntry {
+    fn level(&self) -> LogLevel {
+        LogLevel::from_number(self.level)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LoggedException {
+    #[serde(rename = "Exception", default)]
+    class: String,
+    #[serde(rename = "Message", default)]
+    message: String,
+    #[serde(rename = "File", default)]
+    file: String,
+    #[serde(rename = "Line", default)]
+    line: u64,
+    #[serde(rename = "Trace", default)]
+    trace: Vec<TraceFrame>,
+    #[serde(rename = "Previous", default)]
+    previous: Option<Box<LoggedException>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TraceFrame {
+    file: Option<String>,
+    line: Option<u64>,
+    #[serde(default)]
+    function: String,
+    class: Option<String>,
+    #[serde(rename = "type")]
+    ty: Option<String>,
+}
+
+/// Open a log file, `None` if it doesn't exist yet
+async fn open_log(path: &Utf8Path) -> Result<Option<File>> {
+    match File::open(path).await {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to open {}", path)),
+    }
+}
+
+/// Show the last `count` matching entries of a nextcloud log file, optionally following new entries
+///
+/// A log file that doesn't exist yet is treated as empty, nextcloud only creates it once the first entry is logged.
+pub async fn show_log(
+    path: &Utf8Path,
+    mut out: impl Write,
+    filter: &LogFilter,
+    count: usize,
+    follow: bool,
+    colored: bool,
+) -> Result<()> {
+    let mut file = open_log(path).await?;
+    let mut content = Vec::new();
+    if let Some(file) = file.as_mut() {
+        file.read_to_end(&mut content).await.into_diagnostic()?;
+    }
+    let mut offset = content.len() as u64;
+
+    let entries: Vec<LogEntry> = String::from_utf8_lossy(&content)
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .filter(|entry| filter.matches(entry))
+        .collect();
+    for entry in &entries[entries.len().saturating_sub(count)..] {
+        print_entry(&mut out, entry, colored)?;
+    }
+
+    if !follow {
+        return Ok(());
+    }
+
+    let mut partial = Vec::new();
+    loop {
+        out.flush().into_diagnostic()?;
+        sleep(Duration::from_millis(500)).await;
+
+        let len = match metadata(path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).into_diagnostic(),
+        };
+        if file.is_none() || len < offset {
+            // log was created, rotated or truncated
+            file = open_log(path).await?;
+            offset = 0;
+            partial.clear();
+        }
+        let Some(file) = file.as_mut() else {
+            continue;
+        };
+        file.seek(SeekFrom::Start(offset)).await.into_diagnostic()?;
+        let read = file.read_to_end(&mut partial).await.into_diagnostic()?;
+        offset += read as u64;
+
+        while let Some(end) = partial.iter().position(|c| *c == b'\n') {
+            let line: Vec<u8> = partial.drain(..=end).collect();
+            if let Ok(entry) = serde_json::from_slice::<LogEntry>(&line) {
+                if filter.matches(&entry) {
+                    print_entry(&mut out, &entry, colored)?;
+                }
+            }
+        }
+    }
+}
+
+fn print_entry(mut out: impl Write, entry: &LogEntry, colored: bool) -> Result<()> {
+    let time = DateTime::parse_from_rfc3339(&entry.time)
+        .map(|time| {
+            time.with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|_| entry.time.clone());
+    let level = format!("{:5}", entry.level().to_string());
+    let level = if colored {
+        match entry.level() {
+            LogLevel::Debug => format!("{}{level}{}", Fg(LightBlack), Fg(Reset)),
+            LogLevel::Info => level,
+            LogLevel::Warn => format!("{}{level}{}", Fg(Yellow), Fg(Reset)),
+            LogLevel::Error => format!("{}{level}{}", Fg(Red), Fg(Reset)),
+            LogLevel::Fatal => format!("{}{}{level}{}{}", Bold, Fg(LightRed), Fg(Reset), NoBold),
+        }
+    } else {
+        level
+    };
+    writeln!(
+        out,
+        "{time} {level} {} [{}] {} {}",
+        entry.app, entry.req_id, entry.method, entry.url
+    )
+    .into_diagnostic()?;
+
+    let exception = match &entry.message {
+        Value::String(message) => {
+            writeln!(out, "    {}", message).into_diagnostic()?;
+            entry.exception.as_ref()
+        }
+        // older versions log the exception as message
+        Value::Object(_) => Some(&entry.message),
+        Value::Null => entry.exception.as_ref(),
+        message => {
+            writeln!(out, "    {}", message).into_diagnostic()?;
+            entry.exception.as_ref()
+        }
+    };
+
+    if let Some(exception) = exception {
+        match LoggedException::deserialize(exception) {
+            Ok(exception) => print_exception(&mut out, &exception)?,
+            Err(_) => writeln!(out, "    {}", exception).into_diagnostic()?,
+        }
+    }
+    Ok(())
+}
+
+fn print_exception(mut out: impl Write, exception: &LoggedException) -> Result<()> {
+    let mut exception = Some(exception);
+    let mut first = true;
+    while let Some(current) = exception {
+        let prefix = if first { "" } else { "Caused by: " };
+        writeln!(
+            out,
+            "    {prefix}{}: {} at {}:{}",
+            current.class, current.message, current.file, current.line
+        )
+        .into_diagnostic()?;
+        for (i, frame) in current.trace.iter().enumerate() {
+            let location = match (&frame.file, frame.line) {
+                (Some(file), Some(line)) => format!("{file}:{line}"),
+                (Some(file), None) => file.clone(),
+                _ => "[internal function]".to_string(),
+            };
+            writeln!(
+                out,
+                "      #{i} {location} {}{}{}()",
+                frame.class.as_deref().unwrap_or_default(),
+                frame.ty.as_deref().unwrap_or_default(),
+                frame.function
+            )
+            .into_diagnostic()?;
+        }
+        exception = current.previous.as_deref();
+        first = false;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+const SAMPLE_LOG: &str = r#"{"reqId":"req1","level":1,"time":"2024-01-01T10:00:00+00:00","app":"files","method":"GET","url":"/index.php/apps/files","message":"Scanned files"}
+{"reqId":"req2","level":3,"time":"2024-01-01T10:00:01+00:00","app":"dav","method":"PUT","url":"/remote.php/dav/files/admin/test.txt","message":"Upload failed","exception":{"Exception":"OCA\\DAV\\Connector\\Sabre\\Exception\\FileLocked","Message":"Locked","File":"/var/www/html/apps/dav/lib/File.php","Line":12,"Trace":[{"file":"/var/www/html/lib/base.php","line":34,"function":"put","class":"OCA\\DAV\\File","type":"->"},{"function":"{closure}"}],"Previous":{"Exception":"OCP\\Lock\\LockedException","Message":"inner","File":"/var/www/html/lib/Lock.php","Line":5,"Trace":[]}}}
+{"reqId":"req3","level":2,"time":"2024-01-01T10:00:02+00:00","app":"core","method":"GET","url":"/","message":{"Exception":"Exception","Message":"old style","File":"/a.php","Line":1,"Trace":[]}}
+not json
+"#;
+
+#[test]
+fn test_print_entry() {
+    let entries: Vec<LogEntry> = SAMPLE_LOG
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    assert_eq!(entries.len(), 3);
+
+    let mut out = Vec::new();
+    print_entry(&mut out, &entries[1], false).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert!(lines[0].ends_with(" ERROR dav [req2] PUT /remote.php/dav/files/admin/test.txt"));
+    assert_eq!(
+        &lines[1..],
+        [
+            "    Upload failed",
+            "    OCA\\DAV\\Connector\\Sabre\\Exception\\FileLocked: Locked at /var/www/html/apps/dav/lib/File.php:12",
+            "      #0 /var/www/html/lib/base.php:34 OCA\\DAV\\File->put()",
+            "      #1 [internal function] {closure}()",
+            "    Caused by: OCP\\Lock\\LockedException: inner at /var/www/html/lib/Lock.php:5",
+        ]
+    );
+
+    // older versions log the exception as message
+    let mut out = Vec::new();
+    print_entry(&mut out, &entries[2], false).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(
+        out.lines().nth(1),
+        Some("    Exception: old style at /a.php:1")
+    );
+}
+
+#[tokio::test]
+async fn test_show_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = Utf8Path::from_path(dir.path())
+        .unwrap()
+        .join("nextcloud.log");
+
+    // the log doesn't exist until the first entry is logged
+    let mut out = Vec::new();
+    show_log(&path, &mut out, &LogFilter::default(), 10, false, false)
+        .await
+        .unwrap();
+    assert!(out.is_empty());
+
+    std::fs::write(&path, SAMPLE_LOG).unwrap();
+    let filter = LogFilter {
+        level: Some(LogLevel::Warn),
+        ..LogFilter::default()
+    };
+    let mut out = Vec::new();
+    show_log(&path, &mut out, &filter, 1, false, false)
+        .await
+        .unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.lines().next().unwrap().contains("[req3]"));
+    assert!(!out.contains("[req2]"));
+}
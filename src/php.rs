@@ -1,12 +1,14 @@
+use crate::config::TimeoutConfig;
 use crate::database::Database;
+use crate::docker::Docker;
 use crate::image::pull_image;
 use crate::network::ensure_network_exists;
 use crate::service::Service;
 use crate::service::ServiceTrait;
+use crate::status::wait_for_healthy;
 use bollard::container::{Config, CreateContainerOptions, NetworkingConfig};
 use bollard::models::{EndpointSettings, HostConfig};
 use bollard::network::ConnectNetworkOptions;
-use bollard::Docker;
 use itertools::Itertools;
 use maplit::hashmap;
 use miette::{IntoDiagnostic, Report, Result, WrapErr};
@@ -14,8 +16,8 @@ use reqwest::{Client, Url};
 use std::net::IpAddr;
 use std::str::FromStr;
 use std::time::Duration;
-use tokio::time::{sleep, timeout};
 
+/// Php version of the php container of an instance
 #[derive(Clone, Debug, Eq, PartialEq, Default)]
 #[allow(dead_code)]
 pub enum PhpVersion {
@@ -151,11 +153,7 @@ impl PhpVersion {
             .into_diagnostic()?
             .id;
 
-        if let Err(e) = docker
-            .start_container::<String>(&id, None)
-            .await
-            .into_diagnostic()
-        {
+        if let Err(e) = docker.start_container(&id).await.into_diagnostic() {
             docker.remove_container(&id, None).await.ok();
             return Err(e);
         }
@@ -164,7 +162,7 @@ impl PhpVersion {
             .connect_network(
                 "haze",
                 ConnectNetworkOptions {
-                    container: id.as_str(),
+                    container: id.clone(),
                     endpoint_config: EndpointSettings {
                         aliases: Some(vec![id.to_string()]),
                         ..Default::default()
@@ -181,20 +179,43 @@ impl PhpVersion {
         Ok(id)
     }
 
-    pub async fn wait_for_start(&self, ip: Option<IpAddr>) -> Result<()> {
-        let client = Client::new();
+    pub async fn wait_for_start(
+        &self,
+        docker: &Docker,
+        cloud_id: &str,
+        ip: Option<IpAddr>,
+        timeouts: &TimeoutConfig,
+    ) -> Result<()> {
+        if ip.is_none() {
+            return Err(Report::msg("Container not running"));
+        }
+        wait_for_healthy(
+            docker,
+            "the php container to serve status.php",
+            Some(cloud_id),
+            timeouts.php(),
+            Duration::from_millis(100),
+            || self.is_healthy(ip),
+        )
+        .await
+    }
+
+    /// Check once if the webserver in the container is accepting requests
+    ///
+    /// Returns an error if the webserver can't be reached and `false` if it responds with an error status.
+    /// Whether nextcloud is installed isn't checked, since this is also used to wait for the webserver before installing.
+    pub async fn is_healthy(&self, ip: Option<IpAddr>) -> Result<bool> {
         let url = Url::parse(&format!(
             "http://{}/status.php",
             ip.ok_or_else(|| Report::msg("Container not running"))?
         ))
         .into_diagnostic()?;
-        timeout(Duration::from_secs(15), async {
-            while client.get(url.clone()).send().await.is_err() {
-                sleep(Duration::from_millis(100)).await
-            }
-        })
-        .await
-        .into_diagnostic()
-        .wrap_err("Timeout after 15 seconds")
+        let response = Client::new()
+            .get(url)
+            .send()
+            .await
+            .into_diagnostic()
+            .wrap_err("Failed to request status.php")?;
+        Ok(response.status().is_success())
     }
 }
@@ -1,93 +1,294 @@
-use crate::service::ServiceTrait;
+use crate::capture::{Capture, CaptureWriter, CAPTURE_FLAG};
+use crate::config::{route_name, ProxyRouting, PROXY_PATH_PREFIX};
+use crate::dashboard::{dashboard, DASHBOARD_SUBDOMAIN};
+use crate::dns::{serve_dns, Names};
+use crate::docker::{container_ip, Docker};
+use crate::service::{Service, ServiceTrait};
+use crate::tls::{serve_tls, tls_config, LocalCa, CA_DIR};
 use crate::Result;
 use crate::{Cloud, HazeConfig};
+use axum::extract::connect_info::{ConnectInfo, Connected};
 use axum::http::header::HOST;
-use axum::http::HeaderValue;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::serve::IncomingStream;
 use axum::{
     body::Body,
     extract::{Request, State},
-    response::{IntoResponse, Response},
+    response::{IntoResponse, Redirect, Response},
     Router,
 };
-use bollard::Docker;
+use bollard::system::EventsOptions;
+use futures_util::StreamExt;
 use hyper::StatusCode;
 use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
-use miette::{miette, IntoDiagnostic};
-use std::collections::HashMap;
+use maplit::hashmap;
+use miette::{miette, IntoDiagnostic, WrapErr};
+use std::collections::{HashMap, HashSet};
 use std::fs::{create_dir_all, set_permissions};
+use std::mem::take;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::net::UnixListener;
+use std::time::{Duration, Instant};
+use tokio::fs::try_exists;
+use tokio::net::{TcpListener, UnixListener};
 use tokio::signal::ctrl_c;
 use tokio::spawn;
 use tokio::time::sleep;
-use tracing::{debug, error, info};
+use tokio::try_join;
+use tokio_rustls::rustls::ServerConfig;
+use tracing::{debug, error, info, warn};
 
-struct ActiveInstances {
-    known: Mutex<HashMap<String, SocketAddr>>,
-    last: Mutex<Option<SocketAddr>>,
+/// Where requests for a subdomain are sent to
+#[derive(Debug, Clone, PartialEq)]
+struct Route {
+    addr: SocketAddr,
+    /// The instance the route belongs to
+    cloud_id: Option<String>,
+}
+
+impl Route {
+    fn new(addr: SocketAddr, cloud_id: &str) -> Self {
+        Route {
+            addr,
+            cloud_id: Some(cloud_id.into()),
+        }
+    }
+}
+
+/// A name an instance or one of its services is reachable by
+pub(crate) struct RouteName<'a> {
+    pub name: String,
+    pub cloud: &'a Cloud,
+    /// `None` for the instance itself
+    pub service: Option<&'a Service>,
+}
+
+impl RouteName<'_> {
+    /// Address of the running container for the name
+    pub async fn addr(&self, docker: &Docker) -> Option<SocketAddr> {
+        match self.service {
+            None => Some(SocketAddr::new(self.cloud.ip?, 80)),
+            Some(service) => {
+                let container = service.container_name(&self.cloud.id)?;
+                let ip = container_ip(docker, &container).await?;
+                Some(SocketAddr::new(ip, service.proxy_port()))
+            }
+        }
+    }
+}
+
+/// Get the names of the instances and their services, without duplicates
+///
+/// Instances are named by their id and services by their container name, which match the urls generated for them and
+/// are unique since they are container names. Additionally, services can be reached by the instance name followed by
+/// the service name, as long as that doesn't conflict with another name.
+/// Since the names are matched exactly, both instance and service names can contain dashes.
+pub(crate) fn route_names(clouds: &[Cloud]) -> Vec<RouteName<'_>> {
+    let services = || {
+        clouds.iter().flat_map(|cloud| {
+            cloud.services().filter_map(move |service| {
+                Some((cloud, service, service.container_name(&cloud.id)?))
+            })
+        })
+    };
+    let instances = clouds.iter().map(|cloud| RouteName {
+        name: route_name(&cloud.id).into(),
+        cloud,
+        service: None,
+    });
+    let containers = services().map(|(cloud, service, container)| RouteName {
+        name: route_name(&container).into(),
+        cloud,
+        service: Some(service),
+    });
+    let aliases = services().map(|(cloud, service, _)| RouteName {
+        name: format!("{}-{}", route_name(&cloud.id), service.name()),
+        cloud,
+        service: Some(service),
+    });
+
+    let mut seen = HashSet::new();
+    instances
+        .chain(containers)
+        .chain(aliases)
+        .filter(|route| seen.insert(route.name.clone()))
+        .collect()
+}
+
+/// How long the capture flag of an instance is cached before it is checked again
+const CAPTURE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Addresses of the instances and services, by subdomain
+#[derive(Default)]
+struct RoutingTable {
+    routes: HashMap<String, Route>,
+    /// All services by subdomain, to start services that aren't running on the first request
+    services: HashMap<String, (String, Service)>,
+    /// The most recently created running instance
+    last: Option<Route>,
+    /// Whether instances have request capture enabled, by id, with the time the flag was checked
+    capturing: HashMap<String, (bool, Instant)>,
+}
+
+pub(crate) struct ActiveInstances {
+    table: Mutex<RoutingTable>,
     docker: Docker,
     config: HazeConfig,
 }
 
 impl ActiveInstances {
-    pub fn new(docker: Docker, config: HazeConfig) -> Self {
+    pub(crate) fn new(docker: Docker, config: HazeConfig) -> Self {
         ActiveInstances {
-            known: Mutex::default(),
-            last: Mutex::default(),
+            table: Mutex::default(),
             docker,
             config,
         }
     }
 
-    pub async fn get(&self, name: &str) -> Option<SocketAddr> {
-        if let Some(ip) = self.known.lock().unwrap().get(name).cloned() {
-            return Some(ip);
+    async fn get(&self, name: &str) -> Option<Route> {
+        if let Some(forward) = self
+            .config
+            .proxy
+            .forward
+            .iter()
+            .find(|forward| forward.name == name)
+        {
+            return Some(Route {
+                addr: forward.target,
+                cloud_id: None,
+            });
         }
 
-        // service proxy
-        let addr = if name.matches('-').count() == 2 {
-            let (name, service_name) = name.rsplit_once('-').unwrap();
-            let cloud = Cloud::get_by_filter(&self.docker, Some(name.into()), &self.config)
-                .await
-                .ok()?;
-            let service = cloud
-                .services()
-                .find(|service| service.name() == service_name)?;
-            let ip = service.get_ip(&self.docker, &cloud.id).await.ok()??;
-            SocketAddr::new(ip, service.proxy_port())
-        } else {
-            SocketAddr::new(
-                Cloud::get_by_filter(&self.docker, Some(name.into()), &self.config)
-                    .await
-                    .ok()?
-                    .ip?,
-                80,
-            )
+        let service = {
+            let table = self.table.lock().unwrap();
+            if let Some(route) = table.routes.get(name) {
+                return Some(route.clone());
+            }
+            table.services.get(name).cloned()
         };
 
-        println!("{name} => {addr}");
+        // services that aren't running are started on demand, the start event will add the route
+        let (cloud_id, service) = service?;
+        let ip = service
+            .get_ip(&self.docker, &cloud_id, &self.config.timeouts)
+            .await
+            .ok()??;
+        Some(Route::new(
+            SocketAddr::new(ip, service.proxy_port()),
+            &cloud_id,
+        ))
+    }
 
-        self.known.lock().unwrap().insert(name.into(), addr);
-        Some(addr)
+    fn last(&self) -> Option<Route> {
+        self.table.lock().unwrap().last.clone()
     }
 
-    pub fn last(&self) -> Option<SocketAddr> {
-        *self.last.lock().unwrap()
+    /// Ip of a running instance or service by its name, without starting services on demand
+    pub(crate) fn routed_ip(&self, name: &str) -> Option<IpAddr> {
+        let table = self.table.lock().unwrap();
+        table.routes.get(name).map(|route| route.addr.ip())
     }
 
-    async fn update_last(&self) {
-        let last = Cloud::get_by_filter(&self.docker, None, &self.config)
-            .await
-            .ok()
-            .and_then(|cloud| Some(SocketAddr::new(cloud.ip?, 80)));
-        let mut old = self.last.lock().unwrap();
-        if old.as_ref() != last.as_ref() {
-            info!(instance = ?last, "Found new instance");
-            *old = last;
+    /// Whether request capture is enabled for an instance
+    ///
+    /// The flag file is checked when a request is routed to the instance, at most once per [`CAPTURE_CHECK_INTERVAL`].
+    async fn is_capturing(&self, cloud_id: &str) -> bool {
+        let cached = self.table.lock().unwrap().capturing.get(cloud_id).copied();
+        if let Some((capturing, checked)) = cached {
+            if checked.elapsed() < CAPTURE_CHECK_INTERVAL {
+                return capturing;
+            }
+        }
+        let flag = self.config.work_dir.join(cloud_id).join(CAPTURE_FLAG);
+        let capturing = try_exists(flag).await.unwrap_or(false);
+        self.table
+            .lock()
+            .unwrap()
+            .capturing
+            .insert(cloud_id.to_string(), (capturing, Instant::now()));
+        capturing
+    }
+
+    /// Rebuild the routing table from the running containers
+    pub(crate) async fn refresh(&self) -> Result<()> {
+        let clouds = Cloud::list(&self.docker, None, &self.config).await?;
+        let mut table = RoutingTable::default();
+        for name in route_names(&clouds) {
+            let addr = name.addr(&self.docker).await;
+            if let Some(addr) = addr {
+                let route = Route::new(addr, &name.cloud.id);
+                // instances are sorted newest first
+                if name.service.is_none() {
+                    table.last.get_or_insert(route.clone());
+                }
+                table.routes.insert(name.name.clone(), route);
+            }
+            if let Some(service) = name.service {
+                table
+                    .services
+                    .insert(name.name, (name.cloud.id.clone(), service.clone()));
+            }
+        }
+
+        let mut old = self.table.lock().unwrap();
+        for (name, route) in &table.routes {
+            if old.routes.get(name) != Some(route) {
+                info!(name, addr = %route.addr, "Route changed");
+            }
+        }
+        for name in old.routes.keys() {
+            if !table.routes.contains_key(name) {
+                info!(name, "Route removed");
+            }
+        }
+        if old.last != table.last {
+            info!(instance = ?table.last.as_ref().map(|route| route.addr), "Found new instance");
+        }
+        table.capturing = take(&mut old.capturing);
+        let routed: HashSet<&String> = table
+            .routes
+            .values()
+            .filter_map(|route| route.cloud_id.as_ref())
+            .collect();
+        table
+            .capturing
+            .retain(|cloud_id, _| routed.contains(cloud_id));
+        *old = table;
+        Ok(())
+    }
+
+    /// Keep the routing table up to date by following the docker events of haze containers
+    async fn watch(&self) {
+        loop {
+            let mut events = self.docker.events(Some(EventsOptions {
+                filters: hashmap! {
+                    "type".into() => vec!["container".into()],
+                    "label".into() => vec!["haze-cloud-id".into()],
+                },
+                ..Default::default()
+            }));
+            if let Err(error) = self.refresh().await {
+                error!(?error, "failed to load instances");
+            }
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(event) => {
+                        if matches!(event.action.as_deref(), Some("start" | "die" | "destroy")) {
+                            if let Err(error) = self.refresh().await {
+                                error!(?error, "failed to load instances");
+                            }
+                        }
+                    }
+                    Err(error) => {
+                        error!(%error, "error while following docker events");
+                        break;
+                    }
+                }
+            }
+            // reconnect when the event stream ends, for example because docker restarted
+            sleep(Duration::from_secs(1)).await;
         }
     }
 }
@@ -99,8 +300,25 @@ pub async fn proxy(docker: Docker, config: HazeConfig) -> Result<()> {
     let listen = config.proxy.listen.clone();
 
     let base_address = config.proxy.address.clone();
-    let instances = ActiveInstances::new(docker, config);
-    serve(instances, listen, base_address).await
+    let tls = if config.proxy.tls_enabled() {
+        let ca = LocalCa::load_or_create(&config.work_dir.join(CA_DIR), &base_address)?;
+        Some((
+            config.proxy.tls_listen.clone(),
+            tls_config(ca, base_address.clone())?,
+        ))
+    } else {
+        None
+    };
+    let instances = Arc::new(ActiveInstances::new(docker, config.clone()));
+    if config.dns.listen.is_empty() {
+        serve(instances, listen, base_address, tls).await
+    } else {
+        try_join!(
+            serve(instances.clone(), listen, base_address, tls),
+            serve_dns(Names::Proxy(instances), config)
+        )?;
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -108,42 +326,102 @@ struct AppState {
     instances: Arc<ActiveInstances>,
     base_address: Arc<String>,
     proxy_client: Arc<Client>,
+    captures: CaptureWriter,
 }
 
-async fn serve(instances: ActiveInstances, listen: String, base_address: String) -> Result<()> {
-    let instances = Arc::new(instances);
-    let base_address = Arc::new(base_address);
-    let last_instances = instances.clone();
-
+/// The app proxying requests to the instances
+fn router(instances: Arc<ActiveInstances>, base_address: String) -> Router {
     let proxy_client: Client =
         hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
             .build(HttpConnector::new());
+    let (captures, _) = CaptureWriter::spawn();
 
-    spawn(async move {
-        loop {
-            sleep(Duration::from_secs(1)).await;
-            last_instances.update_last().await;
+    Router::new().fallback(handler).with_state(AppState {
+        instances,
+        base_address: Arc::new(base_address),
+        proxy_client: Arc::new(proxy_client),
+        captures,
+    })
+}
+
+async fn serve(
+    instances: Arc<ActiveInstances>,
+    listen: String,
+    base_address: String,
+    tls: Option<(String, Arc<ServerConfig>)>,
+) -> Result<()> {
+    let watched_instances = instances.clone();
+    spawn(async move { watched_instances.watch().await });
+
+    let app = router(instances, base_address);
+
+    match tls {
+        Some((tls_listen, tls_config)) => {
+            try_join!(
+                listen_and_serve(app.clone(), &listen, 0o666),
+                serve_tls(app, &tls_listen, tls_config)
+            )?;
+            Ok(())
         }
-    });
+        None => listen_and_serve(app, &listen, 0o666).await,
+    }
+}
+
+/// The connection a request came in on
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClientInfo {
+    /// Address of the client, unknown for connections over a unix socket
+    pub ip: Option<IpAddr>,
+    /// Whether the connection was made to the builtin tls termination
+    pub https: bool,
+}
 
+impl Connected<IncomingStream<'_, TcpListener>> for ClientInfo {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        ClientInfo {
+            ip: Some(stream.remote_addr().ip()),
+            https: false,
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for ClientInfo {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        ClientInfo {
+            ip: None,
+            https: false,
+        }
+    }
+}
+
+/// Whether a listen address is a `host:port` socket address, anything else is the path of a unix socket
+pub(crate) fn is_socket_address(listen: &str) -> bool {
+    listen
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.contains('/') && port.parse::<u16>().is_ok())
+}
+
+/// Serve an app on either a unix socket path or a socket address, until ctrl-c is pressed
+///
+/// Unix sockets are created with the provided permissions.
+pub(crate) async fn listen_and_serve(app: Router, listen: &str, mode: u32) -> Result<()> {
     let cancel = async {
         ctrl_c().await.ok();
     };
 
-    let app = Router::new().fallback(handler).with_state(AppState {
-        instances: instances.clone(),
-        base_address: base_address.clone(),
-        proxy_client: Arc::new(proxy_client),
-    });
-
-    if !listen.starts_with('/') {
-        let addr: SocketAddr = listen.parse().into_diagnostic()?;
-        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-        println!("listening on {}", listener.local_addr().unwrap());
-        axum::serve(listener, app)
-            .with_graceful_shutdown(cancel)
+    if is_socket_address(listen) {
+        let listener = TcpListener::bind(listen)
             .await
-            .unwrap();
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to listen on {}", listen))?;
+        info!(listen = %listener.local_addr().unwrap(), "Listening");
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<ClientInfo>(),
+        )
+        .with_graceful_shutdown(cancel)
+        .await
+        .into_diagnostic()?;
     } else {
         let listen: PathBuf = listen.into();
         if let Some(parent) = listen.parent() {
@@ -154,13 +432,16 @@ async fn serve(instances: ActiveInstances, listen: String, base_address: String)
         }
         let _ = tokio::fs::remove_file(&listen).await;
 
-        let uds = UnixListener::bind(&listen).unwrap();
-        set_permissions(&listen, PermissionsExt::from_mode(0o666)).into_diagnostic()?;
+        let uds = UnixListener::bind(&listen)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to listen on {}", listen.display()))?;
+        set_permissions(&listen, PermissionsExt::from_mode(mode)).into_diagnostic()?;
+        info!(listen = %listen.display(), "Listening");
 
-        axum::serve(uds, app)
+        axum::serve(uds, app.into_make_service_with_connect_info::<ClientInfo>())
             .with_graceful_shutdown(cancel)
             .await
-            .unwrap();
+            .into_diagnostic()?;
     }
 
     Ok(())
@@ -170,8 +451,8 @@ async fn get_remote(
     host: Option<&HeaderValue>,
     instances: &ActiveInstances,
     base_address: &str,
-) -> Result<SocketAddr, String> {
-    let host = match host.and_then(|host| host.to_str().ok()) {
+) -> Result<Route, String> {
+    let host = match host_name(host) {
         Some(host) => host,
         None => return Err("No or invalid hostname provided".into()),
     };
@@ -181,29 +462,129 @@ async fn get_remote(
             .ok_or_else(|| String::from("No running instance known"))
     } else {
         let requested_instance = host.split('.').next().unwrap();
-        if requested_instance == "host-push" {
-            Ok((IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 7867).into())
-        } else {
-            instances
-                .get(requested_instance)
-                .await
-                .ok_or_else(|| format!("Error {} has no known ip", requested_instance))
-        }
+        instances
+            .get(requested_instance)
+            .await
+            .ok_or_else(|| format!("Error {} has no known ip", requested_instance))
     };
     match ip {
         Ok(ip) => Ok(ip),
         Err(e) => {
-            eprintln!("{}", e);
+            warn!("{}", e);
             Err(e)
         }
     }
 }
 
+/// Split a path of the form `/i/<name>/<rest>` into the instance name and the remaining path
+fn split_path_route(path: &str) -> Option<(&str, &str)> {
+    let path = path.strip_prefix(PROXY_PATH_PREFIX)?;
+    let end = path.find(['/', '?']).unwrap_or(path.len());
+    let (name, rest) = path.split_at(end);
+    (!name.is_empty()).then_some((name, rest))
+}
+
+fn header_str(value: Option<&HeaderValue>) -> Option<&str> {
+    value?.to_str().ok()
+}
+
+/// Get the requested hostname without port
+fn host_name(host: Option<&HeaderValue>) -> Option<&str> {
+    let host = header_str(host)?;
+    // the host includes the port when https isn't served on the default port
+    Some(host.split_once(':').map_or(host, |(host, _port)| host))
+}
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+static X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Set the `X-Forwarded-*` headers describing the original request and get the address of the original client
+///
+/// Headers set by a reverse proxy in front of haze, connected over a unix socket or loopback, are kept.
+/// When no proxy sets the protocol, `https` determines whether the original request used https.
+///
+/// `X-Forwarded-For` is removed, hyper-reverse-proxy sets it to the returned address when forwarding the request.
+/// Behind a reverse proxy, that is the last address it added, so the instance always sees the original client.
+fn set_forwarded_headers(headers: &mut HeaderMap, client: ClientInfo, https: bool) -> IpAddr {
+    let behind_proxy = client.ip.is_none_or(|ip| ip.is_loopback());
+    if !behind_proxy {
+        headers.remove(&X_FORWARDED_FOR);
+        headers.remove(&X_FORWARDED_PROTO);
+        headers.remove(&X_FORWARDED_HOST);
+    }
+
+    let forwarded_for = headers
+        .get_all(&X_FORWARDED_FOR)
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok()?.rsplit(',').next()?.trim().parse().ok());
+    headers.remove(&X_FORWARDED_FOR);
+    if client.https {
+        headers.insert(X_FORWARDED_PROTO.clone(), HeaderValue::from_static("https"));
+    } else if !headers.contains_key(&X_FORWARDED_PROTO) {
+        let proto = if behind_proxy && https {
+            "https"
+        } else {
+            "http"
+        };
+        headers.insert(X_FORWARDED_PROTO.clone(), HeaderValue::from_static(proto));
+    }
+    if !headers.contains_key(&X_FORWARDED_HOST) {
+        if let Some(host) = headers.get(HOST).cloned() {
+            headers.insert(X_FORWARDED_HOST.clone(), host);
+        }
+    }
+
+    // clients connected over the unix socket run on the same machine
+    forwarded_for
+        .or(client.ip)
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
 type Client = hyper_util::client::legacy::Client<HttpConnector, Body>;
 
-async fn handler(State(state): State<AppState>, mut req: Request) -> Result<Response, StatusCode> {
+async fn handler(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+    mut req: Request,
+) -> Result<Response, StatusCode> {
     let host = req.headers().get(HOST).cloned();
-    let remote = match get_remote(host.as_ref(), &state.instances, &state.base_address).await {
+    let original_uri = req.uri().clone();
+    let remote = match state.instances.config.proxy.routing {
+        ProxyRouting::Subdomain => {
+            if !state.base_address.is_empty()
+                && host_name(host.as_ref())
+                    == Some(format!("{}.{}", DASHBOARD_SUBDOMAIN, state.base_address).as_str())
+            {
+                return Ok(dashboard(&state.instances.docker, &state.instances.config, req).await);
+            }
+            get_remote(host.as_ref(), &state.instances, &state.base_address).await
+        }
+        ProxyRouting::Path => {
+            let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+            // everything outside the instance paths is handled by the dashboard
+            let Some((name, rest)) = split_path_route(path) else {
+                return Ok(dashboard(&state.instances.docker, &state.instances.config, req).await);
+            };
+            if !rest.starts_with('/') {
+                return Ok(
+                    Redirect::permanent(&format!("{PROXY_PATH_PREFIX}{name}/{rest}"))
+                        .into_response(),
+                );
+            }
+            let name = name.to_string();
+            // the instance is configured with the prefix as webroot, but expects requests without it
+            *req.uri_mut() = rest.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+            state
+                .instances
+                .get(&name)
+                .await
+                .ok_or_else(|| format!("Error {} has no known ip", name))
+        }
+    };
+
+    let remote = match remote {
         Ok(remote) => remote,
         Err(e) => {
             return Ok(hyper::Response::builder()
@@ -213,7 +594,7 @@ async fn handler(State(state): State<AppState>, mut req: Request) -> Result<Resp
         }
     };
 
-    let uri = format!("http://{remote}");
+    let uri = format!("http://{}", remote.addr);
     debug!(target = uri, "proxying request");
 
     // fix weird duplicate host header
@@ -221,19 +602,373 @@ async fn handler(State(state): State<AppState>, mut req: Request) -> Result<Resp
     if let Some(host) = host {
         req.headers_mut().insert(HOST, host.clone());
     }
+    let client_ip = set_forwarded_headers(
+        req.headers_mut(),
+        client,
+        state.instances.config.proxy.https,
+    );
 
-    match hyper_reverse_proxy::call(
-        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-        &uri,
-        req,
-        &state.proxy_client,
-    )
-    .await
-    {
-        Ok(response) => Ok(response.map(Body::new)),
+    let capturing = match &remote.cloud_id {
+        Some(cloud_id) => state.instances.is_capturing(cloud_id).await,
+        None => false,
+    };
+    let capture = remote
+        .cloud_id
+        .as_ref()
+        .filter(|_| capturing)
+        .map(|cloud_id| {
+            let url = format!(
+                "{}://{}{}",
+                header_str(req.headers().get(&X_FORWARDED_PROTO)).unwrap_or("http"),
+                header_str(req.headers().get(&X_FORWARDED_HOST)).unwrap_or_default(),
+                original_uri
+                    .path_and_query()
+                    .map_or("/", |path| path.as_str()),
+            );
+            Capture::start(
+                &state.captures,
+                &state.instances.config.work_dir.join(cloud_id),
+                url,
+                &mut req,
+            )
+        });
+
+    match hyper_reverse_proxy::call(client_ip, &uri, req, &state.proxy_client).await {
+        Ok(response) => {
+            let response = response.map(Body::new);
+            Ok(match capture {
+                Some(capture) => capture.finish(response),
+                None => response,
+            })
+        }
         Err(error) => {
             error!(%error, "error while proxying request");
+            if let Some(capture) = capture {
+                capture.failed();
+            }
             Ok(StatusCode::BAD_REQUEST.into_response())
         }
     }
 }
+
+#[tokio::test]
+async fn test_routes_follow_events() {
+    use crate::cloud::{test_env, test_options};
+    use crate::Database;
+    use tokio::time::timeout;
+
+    /// Wait until the watcher processed the events and the route points to the expected address
+    async fn wait_for_route(instances: &ActiveInstances, name: &str, expected: Option<SocketAddr>) {
+        let route = || {
+            let table = instances.table.lock().unwrap();
+            table.routes.get(name).map(|route| route.addr)
+        };
+        let routed = async {
+            while route() != expected {
+                sleep(Duration::from_millis(5)).await;
+            }
+        };
+        if timeout(Duration::from_secs(5), routed).await.is_err() {
+            panic!("route for {name} is {:?} instead of {expected:?}", route());
+        }
+    }
+
+    let (_dir, config, _, docker) = test_env();
+    let instances = Arc::new(ActiveInstances::new(docker.clone(), config.clone()));
+    let watched_instances = instances.clone();
+    spawn(async move { watched_instances.watch().await });
+
+    let cloud = Cloud::create(&docker, test_options("test", Database::MariaDB), &config)
+        .await
+        .unwrap();
+    let first = SocketAddr::new(cloud.ip.unwrap(), 80);
+    wait_for_route(&instances, "test", Some(first)).await;
+    assert_eq!(instances.last().map(|route| route.addr), Some(first));
+
+    cloud.set_capture(true).unwrap();
+    assert!(instances.is_capturing(&cloud.id).await);
+
+    cloud.destroy(&docker).await.unwrap();
+    wait_for_route(&instances, "test", None).await;
+    assert_eq!(instances.last(), None);
+
+    // recreating an instance with the same name routes to the new container
+    let cloud = Cloud::create(&docker, test_options("test", Database::MariaDB), &config)
+        .await
+        .unwrap();
+    let second = SocketAddr::new(cloud.ip.unwrap(), 80);
+    assert_ne!(first, second);
+    wait_for_route(&instances, "test", Some(second)).await;
+}
+
+#[test]
+fn test_split_path_route() {
+    assert_eq!(
+        Some(("foo", "/index.php/apps/files?dir=/")),
+        split_path_route("/i/foo/index.php/apps/files?dir=/")
+    );
+    assert_eq!(
+        Some(("foo-office", "/")),
+        split_path_route("/i/foo-office/")
+    );
+    assert_eq!(Some(("foo", "")), split_path_route("/i/foo"));
+    assert_eq!(Some(("foo", "?x=1")), split_path_route("/i/foo?x=1"));
+    assert_eq!(None, split_path_route("/i/"));
+    assert_eq!(None, split_path_route("/instances/haze-foo/stop"));
+}
+
+#[test]
+fn test_forwarded_headers() {
+    let forwarded = |client: ClientInfo, https: bool, headers: &[(&str, &str)]| {
+        let mut map = HeaderMap::new();
+        map.insert(HOST, HeaderValue::from_static("foo.haze.example.com"));
+        for (name, value) in headers {
+            map.insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        let client_ip = set_forwarded_headers(&mut map, client, https);
+        let [proto, host] = [&X_FORWARDED_PROTO, &X_FORWARDED_HOST].map(|name| {
+            map.get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+        });
+        assert!(!map.contains_key(&X_FORWARDED_FOR));
+        (client_ip.to_string(), proto, host)
+    };
+    let remote = ClientInfo {
+        ip: Some(IpAddr::from([192, 168, 1, 10])),
+        https: false,
+    };
+    let unix = ClientInfo {
+        ip: None,
+        https: false,
+    };
+
+    // headers sent by a remote client are replaced
+    assert_eq!(
+        forwarded(remote, true, &[("x-forwarded-for", "1.2.3.4")]),
+        (
+            "192.168.1.10".into(),
+            Some("http".into()),
+            Some("foo.haze.example.com".into())
+        )
+    );
+    assert_eq!(
+        forwarded(
+            ClientInfo {
+                https: true,
+                ..remote
+            },
+            false,
+            &[]
+        )
+        .1
+        .as_deref(),
+        Some("https")
+    );
+    // headers from a reverse proxy in front are kept
+    assert_eq!(
+        forwarded(
+            unix,
+            true,
+            &[
+                ("x-forwarded-for", "10.0.0.1, 1.2.3.4"),
+                ("x-forwarded-host", "cloud.example.com")
+            ]
+        ),
+        (
+            "1.2.3.4".into(),
+            Some("https".into()),
+            Some("cloud.example.com".into())
+        )
+    );
+    assert_eq!(forwarded(unix, false, &[]).0, "127.0.0.1");
+}
+
+#[tokio::test]
+async fn test_forwarded_upstream_headers() {
+    use crate::cloud::test_env;
+    use crate::config::ProxyForward;
+    use axum::body::to_bytes;
+    use tower::ServiceExt;
+
+    // an upstream that responds with the forwarded headers it received
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = upstream.local_addr().unwrap();
+    let echo = Router::new().fallback(|headers: HeaderMap| async move {
+        [&X_FORWARDED_FOR, &X_FORWARDED_PROTO, &X_FORWARDED_HOST]
+            .map(|name| {
+                header_str(headers.get(name))
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .join(" ")
+    });
+    spawn(async move { axum::serve(upstream, echo).await });
+
+    let (_dir, mut config, _, docker) = test_env();
+    config.proxy.address = "haze.example.com".into();
+    config.proxy.forward.push(ProxyForward {
+        name: "echo".into(),
+        target,
+    });
+    let app = router(
+        Arc::new(ActiveInstances::new(docker, config)),
+        "haze.example.com".into(),
+    );
+    let forwarded = |client: ClientInfo, headers: &[(&str, &str)]| {
+        let mut request = Request::builder()
+            .uri("/")
+            .header(HOST, "echo.haze.example.com");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        request.extensions_mut().insert(ConnectInfo(client));
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+    };
+
+    let remote = ClientInfo {
+        ip: Some(IpAddr::from([192, 168, 1, 10])),
+        https: false,
+    };
+    assert_eq!(
+        forwarded(remote, &[("x-forwarded-for", "1.2.3.4")]).await,
+        "192.168.1.10 http echo.haze.example.com"
+    );
+    let front_proxy = ClientInfo {
+        ip: Some(IpAddr::from([127, 0, 0, 1])),
+        https: false,
+    };
+    assert_eq!(
+        forwarded(
+            front_proxy,
+            &[
+                ("x-forwarded-for", "1.2.3.4"),
+                ("x-forwarded-proto", "https"),
+                ("x-forwarded-host", "cloud.example.com")
+            ]
+        )
+        .await,
+        "1.2.3.4 https cloud.example.com"
+    );
+    let unix = ClientInfo {
+        ip: None,
+        https: false,
+    };
+    assert_eq!(
+        forwarded(unix, &[("x-forwarded-for", "1.2.3.4")]).await,
+        "1.2.3.4 http echo.haze.example.com"
+    );
+}
+
+#[tokio::test]
+async fn test_route_names() {
+    use crate::cloud::{test_env, test_options};
+    use crate::Database;
+
+    let (_dir, config, _, docker) = test_env();
+    let create = |name: &str, services: &[&str]| {
+        let mut options = test_options(name, Database::Sqlite);
+        options.services = services
+            .iter()
+            .flat_map(|service| Service::from_type(&[], service).unwrap())
+            .collect();
+        Cloud::create(&docker, options, &config)
+    };
+    let cloud = create("dashed-name", &["ldap", "s3"]).await.unwrap();
+    create("dashed", &[]).await.unwrap();
+    let conflicting = create("dashed-name-s3", &[]).await.unwrap();
+
+    let clouds = Cloud::list(&docker, None, &config).await.unwrap();
+    let names = route_names(&clouds);
+    let find = |name: &str| names.iter().find(|route| route.name == name);
+
+    assert_eq!(find("dashed").unwrap().cloud.id, "haze-dashed");
+    let instance = find("dashed-name").unwrap();
+    assert_eq!(instance.cloud.id, cloud.id);
+    assert_eq!(
+        instance.addr(&docker).await,
+        Some(SocketAddr::new(cloud.ip.unwrap(), 80))
+    );
+
+    // services are reachable by the container name used in their urls
+    let ldap_admin = find("dashed-name-ldap-admin").unwrap();
+    assert_eq!(ldap_admin.service.unwrap().name(), "ldap-admin");
+    let ip = container_ip(&docker, "haze-dashed-name-ldap-admin")
+        .await
+        .unwrap();
+    assert_eq!(
+        ldap_admin.addr(&docker).await,
+        Some(SocketAddr::new(
+            ip,
+            ldap_admin.service.unwrap().proxy_port()
+        ))
+    );
+    assert_eq!(
+        find("dashed-name-ldap").unwrap().service.unwrap().name(),
+        "ldap"
+    );
+    assert_eq!(
+        find("dashed-name-object").unwrap().service.unwrap().name(),
+        "s3"
+    );
+
+    // the service name alias doesn't take precedence over an instance
+    let s3 = find("dashed-name-s3").unwrap();
+    assert!(s3.service.is_none());
+    assert_eq!(s3.cloud.id, conflicting.id);
+    assert_eq!(
+        names
+            .iter()
+            .filter(|route| route.name == "dashed-name-s3")
+            .count(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_forward_routes() {
+    use crate::cloud::test_env;
+    use crate::config::ProxyConfig;
+
+    let (_dir, mut config, _, docker) = test_env();
+    config.proxy = toml::from_str::<ProxyConfig>(
+        r#"
+        listen = "127.0.0.1:8080"
+        address = "haze.example.com"
+
+        [[forward]]
+        name = "host-push"
+        target = "127.0.0.1:7867"
+        "#,
+    )
+    .unwrap();
+    let instances = ActiveInstances::new(docker, config);
+
+    assert_eq!(
+        instances.get("host-push").await,
+        Some(Route {
+            addr: SocketAddr::from(([127, 0, 0, 1], 7867)),
+            cloud_id: None,
+        })
+    );
+    assert_eq!(instances.get("office").await, None);
+}
+
+#[test]
+fn test_is_socket_address() {
+    assert!(is_socket_address("127.0.0.1:8080"));
+    assert!(is_socket_address("[::1]:8080"));
+    assert!(is_socket_address("localhost:8080"));
+    assert!(!is_socket_address("/run/haze/proxy.sock"));
+    assert!(!is_socket_address("haze.sock"));
+    assert!(!is_socket_address("sockets/haze.sock"));
+    assert!(!is_socket_address("./127.0.0.1:8080"));
+}
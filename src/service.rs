@@ -15,7 +15,8 @@ mod sharded;
 mod smb;
 
 use crate::cloud::CloudOptions;
-use crate::config::{HazeConfig, Preset};
+use crate::config::{HazeConfig, Preset, TimeoutConfig};
+use crate::docker::Docker;
 pub use crate::service::clam::{ClamIcap, ClamIcapTls};
 use crate::service::dav::Dav;
 use crate::service::imaginary::Imaginary;
@@ -30,16 +31,17 @@ pub use crate::service::push::NotifyPush;
 use crate::service::sftp::Sftp;
 use crate::service::sharded::{Sharding, ShardingMigrate, ShardingMigrateUnset, SingleShard};
 use crate::service::smb::Smb;
+use crate::status::wait_for_healthy;
 use bollard::models::ContainerState;
-use bollard::Docker;
 use enum_dispatch::enum_dispatch;
 use miette::{IntoDiagnostic, Report, Result, WrapErr};
 use serde_json::Value;
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::time::Duration;
-use tokio::time::{sleep, timeout};
+use tokio::time::sleep;
 
+/// An additional service that can be started alongside an instance
 #[async_trait::async_trait]
 #[enum_dispatch(Service)]
 pub trait ServiceTrait {
@@ -81,6 +83,11 @@ pub trait ServiceTrait {
         &[]
     }
 
+    /// Postfixes of the extra database containers started by the service
+    fn databases(&self) -> &'static [&'static str] {
+        &[]
+    }
+
     fn config(
         &self,
         _docker: &Docker,
@@ -116,27 +123,34 @@ pub trait ServiceTrait {
         ))
     }
 
-    async fn wait_for_running(&self, docker: &Docker, cloud_id: &str) -> Result<()> {
-        timeout(Duration::from_secs(30), async {
-            while !self.is_running(docker, cloud_id).await? {
-                sleep(Duration::from_millis(100)).await
-            }
-            Ok(())
-        })
+    async fn wait_for_running(
+        &self,
+        docker: &Docker,
+        cloud_id: &str,
+        timeouts: &TimeoutConfig,
+    ) -> Result<()> {
+        wait_for_healthy(
+            docker,
+            &format!("the {} container to start", self.name()),
+            self.container_name(cloud_id).as_deref(),
+            timeouts.service(self.name()),
+            Duration::from_millis(100),
+            || self.is_running(docker, cloud_id),
+        )
         .await
-        .into_diagnostic()
-        .wrap_err("Timeout after 30 seconds")?
     }
 
-    async fn get_ip(&self, docker: &Docker, cloud_id: &str) -> Result<Option<IpAddr>> {
+    async fn get_ip(
+        &self,
+        docker: &Docker,
+        cloud_id: &str,
+        timeouts: &TimeoutConfig,
+    ) -> Result<Option<IpAddr>> {
         let Some(container) = self.container_name(cloud_id) else {
             return Ok(None);
         };
-        docker
-            .start_container::<String>(&container, None)
-            .await
-            .into_diagnostic()?;
-        self.wait_for_running(docker, cloud_id).await?;
+        docker.start_container(&container).await.into_diagnostic()?;
+        self.wait_for_running(docker, cloud_id, timeouts).await?;
 
         sleep(Duration::from_millis(100)).await;
 
@@ -188,6 +202,7 @@ impl ServiceTrait for RedisTls {
     }
 }
 
+/// All supported services, see [`ServiceTrait`] for the methods available on each service
 #[enum_dispatch]
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub enum Service {
@@ -264,16 +279,17 @@ impl Service {
         docker: &Docker,
         cloud_id: &str,
         options: &CloudOptions,
+        timeouts: &TimeoutConfig,
     ) -> Result<()> {
-        timeout(Duration::from_secs(30), async {
-            while !self.is_healthy(docker, cloud_id, options).await? {
-                sleep(Duration::from_millis(100)).await
-            }
-            Ok(())
-        })
+        wait_for_healthy(
+            docker,
+            &format!("the {} service to become healthy", self.name()),
+            self.container_name(cloud_id).as_deref(),
+            timeouts.service(self.name()),
+            Duration::from_millis(100),
+            || self.is_healthy(docker, cloud_id, options),
+        )
         .await
-        .into_diagnostic()
-        .wrap_err("Timeout after 30 seconds")?
     }
 }
 
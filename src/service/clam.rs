@@ -1,12 +1,12 @@
 use crate::cloud::CloudOptions;
 use crate::config::HazeConfig;
+use crate::docker::{owned_config, Docker};
 use crate::exec::exec;
 use crate::image::pull_image;
 use crate::service::ServiceTrait;
 use crate::Result;
 use bollard::container::{Config, CreateContainerOptions, NetworkingConfig};
 use bollard::models::{EndpointSettings, HostConfig};
-use bollard::Docker;
 use maplit::hashmap;
 use miette::{IntoDiagnostic, WrapErr};
 use tokio::fs::write;
@@ -64,14 +64,11 @@ impl ServiceTrait for ClamIcap {
             ..Default::default()
         };
         let id = docker
-            .create_container(options, config)
+            .create_container(options, owned_config(config))
             .await
             .into_diagnostic()?
             .id;
-        docker
-            .start_container::<String>(&id, None)
-            .await
-            .into_diagnostic()?;
+        docker.start_container(&id).await.into_diagnostic()?;
         Ok(vec![id])
     }
 
@@ -153,14 +150,11 @@ impl ServiceTrait for ClamIcapTls {
             ..Default::default()
         };
         let id = docker
-            .create_container(options, config)
+            .create_container(options, owned_config(config))
             .await
             .into_diagnostic()?
             .id;
-        docker
-            .start_container::<String>(&id, None)
-            .await
-            .into_diagnostic()?;
+        docker.start_container(&id).await.into_diagnostic()?;
         Ok(vec![id])
     }
 
@@ -1,11 +1,11 @@
 use crate::cloud::CloudOptions;
 use crate::config::HazeConfig;
+use crate::docker::{owned_config, Docker};
 use crate::image::pull_image;
 use crate::service::ServiceTrait;
 use crate::Result;
 use bollard::container::{Config, CreateContainerOptions, NetworkingConfig};
 use bollard::models::{ContainerState, EndpointSettings, HostConfig};
-use bollard::Docker;
 use maplit::hashmap;
 use miette::{IntoDiagnostic, Report};
 
@@ -59,14 +59,11 @@ impl ServiceTrait for Ldap {
             ..Default::default()
         };
         let id = docker
-            .create_container(options, config)
+            .create_container(options, owned_config(config))
             .await
             .into_diagnostic()?
             .id;
-        docker
-            .start_container::<String>(&id, None)
-            .await
-            .into_diagnostic()?;
+        docker.start_container(&id).await.into_diagnostic()?;
         Ok(vec![id])
     }
 
@@ -138,14 +135,11 @@ impl ServiceTrait for LdapAdmin {
             ..Default::default()
         };
         let id = docker
-            .create_container(options, config)
+            .create_container(options, owned_config(config))
             .await
             .into_diagnostic()?
             .id;
-        docker
-            .start_container::<String>(&id, None)
-            .await
-            .into_diagnostic()?;
+        docker.start_container(&id).await.into_diagnostic()?;
         Ok(vec![id])
     }
 
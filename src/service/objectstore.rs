@@ -1,14 +1,15 @@
 use crate::cloud::CloudOptions;
 use crate::config::HazeConfig;
+use crate::docker::{owned_config, Docker};
 use crate::exec::exec;
 use crate::image::pull_image;
 use crate::service::ServiceTrait;
 use crate::Result;
 use bollard::container::{Config, CreateContainerOptions, NetworkingConfig};
 use bollard::models::{ContainerState, EndpointSettings, HostConfig};
-use bollard::Docker;
 use maplit::hashmap;
 use miette::IntoDiagnostic;
+use std::io::Stdout;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ObjectStore {
@@ -108,14 +109,11 @@ impl ServiceTrait for ObjectStore {
             ..Default::default()
         };
         let id = docker
-            .create_container(options, config)
+            .create_container(options, owned_config(config))
             .await
             .into_diagnostic()?
             .id;
-        docker
-            .start_container::<String>(&id, None)
-            .await
-            .into_diagnostic()?;
+        docker.start_container(&id).await.into_diagnostic()?;
         Ok(vec![id])
     }
 
@@ -130,14 +128,13 @@ impl ServiceTrait for ObjectStore {
         }
         match self {
             ObjectStore::S3 | ObjectStore::S3mb => {
-                let mut output = Vec::new();
                 let exit = exec(
                     docker,
                     format!("{}-object", cloud_id),
                     "root",
                     vec!["curl", "localhost:9000/minio/health/ready"],
                     Vec::<String>::default(),
-                    Some(&mut output),
+                    Option::<Stdout>::None,
                 )
                 .await?;
                 Ok(exit.is_ok())
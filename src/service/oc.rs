@@ -1,17 +1,18 @@
 use crate::cloud::CloudOptions;
 use crate::config::HazeConfig;
+use crate::docker::{owned_config, Docker};
 use crate::exec::exec;
 use crate::image::pull_image;
 use crate::service::ServiceTrait;
 use crate::Result;
 use bollard::container::{Config, CreateContainerOptions, NetworkingConfig};
 use bollard::models::{EndpointSettings, HostConfig};
-use bollard::Docker;
 use maplit::hashmap;
 use miette::IntoDiagnostic;
 use std::io::Stdout;
 use std::net::Ipv4Addr;
 use tokio::spawn;
+use tracing::info;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Oc;
@@ -65,14 +66,11 @@ impl ServiceTrait for Oc {
             ..Default::default()
         };
         let id = docker
-            .create_container(options, config)
+            .create_container(options, owned_config(config))
             .await
             .into_diagnostic()?
             .id;
-        docker
-            .start_container::<String>(&id, None)
-            .await
-            .into_diagnostic()?;
+        docker.start_container(&id).await.into_diagnostic()?;
         Ok(vec![id])
     }
 
@@ -86,10 +84,10 @@ impl ServiceTrait for Oc {
         cloud_id: &str,
         config: &HazeConfig,
     ) -> Result<Vec<String>> {
-        if let Some(ip) = self.get_ip(docker, cloud_id).await? {
+        if let Some(ip) = self.get_ip(docker, cloud_id, &config.timeouts).await? {
             let container = self.container_name(cloud_id).unwrap();
             let addr = config.proxy.addr(&container, ip);
-            println!("OC running on {addr}");
+            info!(%addr, "OC running");
             let docker = docker.clone();
             spawn(async move {
                 let (protocol, domain) = addr.split_once("://").unwrap();
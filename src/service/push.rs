@@ -1,10 +1,10 @@
 use crate::cloud::CloudOptions;
 use crate::config::HazeConfig;
+use crate::docker::{owned_config, Docker};
 use crate::image::pull_image;
 use crate::service::ServiceTrait;
 use bollard::container::{Config, CreateContainerOptions, NetworkingConfig};
 use bollard::models::{EndpointSettings, HostConfig};
-use bollard::Docker;
 use maplit::hashmap;
 use miette::{IntoDiagnostic, Result};
 
@@ -66,7 +66,7 @@ impl ServiceTrait for NotifyPush {
             ..Default::default()
         };
         let id = docker
-            .create_container(options, config)
+            .create_container(options, owned_config(config))
             .await
             .into_diagnostic()?
             .id;
@@ -87,7 +87,10 @@ impl ServiceTrait for NotifyPush {
         cloud_id: &str,
         config: &HazeConfig,
     ) -> Result<Vec<String>> {
-        let ip = self.get_ip(docker, cloud_id).await?.unwrap();
+        let ip = self
+            .get_ip(docker, cloud_id, &config.timeouts)
+            .await?
+            .unwrap();
         let addr = config
             .proxy
             .addr_with_port(&self.container_name(cloud_id).unwrap(), ip, 7867);
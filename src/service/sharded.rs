@@ -1,9 +1,9 @@
 use crate::cloud::CloudOptions;
 use crate::config::HazeConfig;
 use crate::database::DatabaseFamily;
+use crate::docker::Docker;
 use crate::service::ServiceTrait;
 use crate::Result;
-use bollard::Docker;
 use futures_util::future::try_join_all;
 use maplit::hashmap;
 use miette::Report;
@@ -62,6 +62,10 @@ impl ServiceTrait for Sharding {
         Ok(running.iter().copied().all(identity))
     }
 
+    fn databases(&self) -> &'static [&'static str] {
+        SHARDS
+    }
+
     fn config(
         &self,
         _docker: &Docker,
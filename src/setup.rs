@@ -0,0 +1,200 @@
+use crate::cloud::{network_gateway, Cloud, CloudOptions};
+use crate::config::HazeConfig;
+use crate::docker::Docker;
+use crate::hooks::Hook;
+use crate::service::ServiceTrait;
+use crate::tls::CA_MOUNT;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use tracing::{debug, warn};
+
+/// Create a new instance and, if auto setup is enabled, install nextcloud in it
+///
+/// See [`Cloud::install`] for the steps performed during installation.
+pub async fn setup(docker: &Docker, options: CloudOptions, config: &HazeConfig) -> Result<Cloud> {
+    let cloud = Cloud::create(docker, options, config).await?;
+    if config.auto_setup.enabled {
+        cloud.wait_for_start(docker, &config.timeouts).await?;
+        cloud.install(docker, config).await?;
+    }
+    Ok(cloud)
+}
+
+impl Cloud {
+    /// Install nextcloud in a started instance
+    ///
+    /// This writes the preset config, runs the installer with the admin credentials from the auto setup config,
+    /// configures the address of the instance, trusts the proxy and the CA of its tls termination, enables the apps needed by the services and runs the post-setup
+    /// commands of the services and the auto setup config, followed by the `post_install` hooks.
+    pub async fn install(&self, docker: &Docker, config: &HazeConfig) -> Result<()> {
+        self.write_preset_config(docker).await?;
+
+        debug!("Installing with username {}", config.auto_setup.username);
+        self.exec(
+            docker,
+            vec![
+                "install",
+                &config.auto_setup.username,
+                &config.auto_setup.password,
+            ],
+            false,
+            Vec::<String>::default(),
+        )
+        .await?;
+        self.set_address_config(docker).await?;
+        if !config.proxy.listen.is_empty() {
+            self.set_trusted_proxy(docker).await?;
+        }
+        if config.proxy.tls_enabled() {
+            self.occ(
+                docker,
+                vec!["security:certificates:import", CA_MOUNT],
+                None,
+                Vec::<String>::default(),
+            )
+            .await?
+            .to_result()
+            .wrap_err("Failed to import the CA of the proxy")?;
+        }
+
+        for service in self.services() {
+            for app in service.apps() {
+                self.exec(
+                    docker,
+                    vec!["occ", "app:enable", *app, "--force"],
+                    false,
+                    Vec::<String>::default(),
+                )
+                .await?;
+            }
+        }
+        for service in self.services() {
+            for cmd in service.post_setup(docker, &self.id, config).await? {
+                self.exec(
+                    docker,
+                    shell_words::split(&cmd).into_diagnostic()?,
+                    false,
+                    Vec::<String>::default(),
+                )
+                .await?;
+            }
+        }
+        for cmd in &config.auto_setup.post_setup {
+            self.exec(
+                docker,
+                shell_words::split(cmd).into_diagnostic()?,
+                false,
+                Vec::<String>::default(),
+            )
+            .await?;
+        }
+        if let Err(e) = self.run_hook(docker, Hook::PostInstall).await {
+            warn!("{}", e);
+        }
+        Ok(())
+    }
+
+    /// Write the config provided by the presets of the instance, if any
+    pub async fn write_preset_config(&self, docker: &Docker) -> Result<()> {
+        if self.preset_config.is_empty() {
+            return Ok(());
+        }
+        debug!("Writing preset config");
+        let encoded_preset_config = serde_json::to_string(&self.preset_config).into_diagnostic()?;
+        self.write_file(docker, "config/preset.config.json", encoded_preset_config)
+            .await?;
+        self.write_file(
+            docker,
+            "config/preset.config.php",
+            "<?php $CONFIG=json_decode(file_get_contents(__DIR__ . '/preset.config.json'), true);",
+        )
+        .await
+    }
+
+    /// Trust the forwarded headers set by `haze proxy`
+    ///
+    /// The proxy runs on the host and connects to the ip of the instance in the shared `haze` network,
+    /// so its requests come from the gateway of that network.
+    pub async fn set_trusted_proxy(&self, docker: &Docker) -> Result<()> {
+        let gateway = network_gateway(docker, "haze").await?;
+        self.occ(
+            docker,
+            vec![
+                "config:system:set",
+                "trusted_proxies",
+                "0",
+                "--value",
+                &gateway,
+            ],
+            None,
+            Vec::<String>::default(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Point the instance config to the address the instance is reachable on
+    pub async fn set_address_config(&self, docker: &Docker) -> Result<()> {
+        let host = self.address.split_once("://").expect("no address?").1;
+        // instances behind the proxy with path routing are served from a sub path
+        let (host, webroot) = match host.split_once('/') {
+            Some((host, path)) => (host, Some(format!("/{path}"))),
+            None => (host, None),
+        };
+        let ip_str = format!("{}", self.ip.unwrap());
+        self.occ(
+            docker,
+            vec![
+                "config:system:set",
+                "overwrite.cli.url",
+                "--value",
+                &self.address,
+            ],
+            None,
+            Vec::<String>::default(),
+        )
+        .await?;
+        self.occ(
+            docker,
+            vec!["config:system:set", "overwritehost", "--value", host],
+            None,
+            Vec::<String>::default(),
+        )
+        .await?;
+        if self.address.contains("https://") {
+            self.occ(
+                docker,
+                vec!["config:system:set", "overwriteprotocol", "--value", "https"],
+                None,
+                Vec::<String>::default(),
+            )
+            .await?;
+        }
+        if let Some(webroot) = &webroot {
+            self.occ(
+                docker,
+                vec!["config:system:set", "overwritewebroot", "--value", webroot],
+                None,
+                Vec::<String>::default(),
+            )
+            .await?;
+        }
+
+        let domains = [ip_str.as_str(), "cloud", &self.id, host];
+        for (i, domain) in domains.iter().enumerate() {
+            self.occ(
+                docker,
+                vec![
+                    "config:system:set",
+                    "trusted_domains",
+                    &format!("{}", i),
+                    "--value",
+                    domain,
+                ],
+                None,
+                Vec::<String>::default(),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
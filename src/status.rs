@@ -0,0 +1,125 @@
+use crate::docker::Docker;
+use crate::exec::container_logs;
+use bollard::models::ContainerStateStatusEnum;
+use itertools::Itertools;
+use miette::{Report, Result};
+use parse_display::Display;
+use serde::{Serialize, Serializer};
+use std::fmt::Write;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::time::{self, sleep};
+
+#[derive(Debug, Clone, Eq, PartialEq, Display)]
+pub enum ContainerStatus {
+    #[display("healthy")]
+    Healthy,
+    #[display("unhealthy")]
+    Unhealthy,
+    #[display("running")]
+    Running,
+    #[display("created but not started")]
+    Created,
+    #[display("exited with code {0}")]
+    Exited(i64),
+    #[display("{0}")]
+    Other(String),
+    #[display("missing")]
+    Missing,
+}
+
+impl ContainerStatus {
+    pub async fn get(docker: &Docker, container: &str) -> ContainerStatus {
+        let Ok(info) = docker.inspect_container(container, None).await else {
+            return ContainerStatus::Missing;
+        };
+        let state = info.state.unwrap_or_default();
+        match state.status {
+            Some(ContainerStateStatusEnum::RUNNING) => ContainerStatus::Running,
+            Some(ContainerStateStatusEnum::CREATED) => ContainerStatus::Created,
+            Some(ContainerStateStatusEnum::EXITED) => {
+                ContainerStatus::Exited(state.exit_code.unwrap_or_default())
+            }
+            Some(status) => ContainerStatus::Other(status.to_string()),
+            None => ContainerStatus::Missing,
+        }
+    }
+
+    /// Refine the state of a running container with the result of its health check
+    pub fn with_health(self, healthy: bool) -> ContainerStatus {
+        match (self, healthy) {
+            (ContainerStatus::Running, true) => ContainerStatus::Healthy,
+            (ContainerStatus::Running, false) => ContainerStatus::Unhealthy,
+            (state, _) => state,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self, ContainerStatus::Healthy | ContainerStatus::Running)
+    }
+}
+
+impl Serialize for ContainerStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// State of one part of an instance
+#[derive(Debug, Serialize)]
+pub struct ComponentStatus {
+    pub name: String,
+    pub container: Option<String>,
+    pub state: ContainerStatus,
+}
+
+/// Poll a health check until it passes or the timeout is reached
+///
+/// Errors from the check are treated as "not healthy yet", when the timeout is reached the error
+/// contains the last output of the check and the tail of the container logs.
+pub async fn wait_for_healthy<F, Fut>(
+    docker: &Docker,
+    check: &str,
+    container: Option<&str>,
+    timeout: Duration,
+    interval: Duration,
+    mut is_healthy: F,
+) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let start = Instant::now();
+    let last_output = loop {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        let output = match time::timeout(remaining, is_healthy()).await {
+            Ok(Ok(true)) => return Ok(()),
+            Ok(Ok(false)) => "not healthy".to_string(),
+            Ok(Err(e)) => e.chain().join(": "),
+            Err(_) => "health check didn't finish in time".to_string(),
+        };
+        if start.elapsed() >= timeout {
+            break output;
+        }
+        sleep(interval).await;
+    };
+
+    let mut message = format!(
+        "Timeout after {} seconds waiting for {check}\nLast health check: {last_output}",
+        timeout.as_secs()
+    );
+    if let Some(container) = container {
+        let mut logs = Vec::new();
+        if container_logs(docker, &mut logs, container, 20, false)
+            .await
+            .is_ok()
+        {
+            let _ = write!(
+                message,
+                "\nLast logs of {container}:\n{}",
+                String::from_utf8_lossy(&logs).trim_end()
+            );
+        }
+    }
+    Err(Report::msg(message))
+}
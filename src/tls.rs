@@ -0,0 +1,334 @@
+use crate::proxy::ClientInfo;
+use crate::Result;
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use axum::Router;
+use camino::Utf8Path;
+use chrono::{Datelike, Days, Utc};
+use miette::{miette, IntoDiagnostic, WrapErr};
+use rcgen::{
+    date_time_ymd, BasicConstraints, Certificate, CertificateParams, DnType, GeneralSubtree, IsCa,
+    KeyPair, KeyUsagePurpose, NameConstraints,
+};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::fs::{create_dir_all, read_to_string, write, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::ctrl_c;
+use tokio::spawn;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
+
+/// Directory inside the work directory containing the local CA
+pub const CA_DIR: &str = "proxy-ca";
+
+/// Path the CA certificate is mounted at inside the instances
+pub const CA_MOUNT: &str = "/haze/ca.crt";
+
+/// Root certificate authority used to issue certificates for the proxied domains
+pub struct LocalCa {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Debug for LocalCa {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalCa").finish_non_exhaustive()
+    }
+}
+
+impl LocalCa {
+    /// Load the CA stored in `dir`, generating a new one if it doesn't exist yet
+    ///
+    /// The CA can only issue certificates for `address` and its subdomains, a stored CA for a different address is replaced.
+    pub fn load_or_create(dir: &Utf8Path, address: &str) -> Result<LocalCa> {
+        if address.is_empty() {
+            return Err(miette!(
+                "Proxy address needs to be configured to serve https"
+            ));
+        }
+        let cert_path = dir.join("ca.crt");
+        let key_path = dir.join("ca.key");
+        let is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        let name_constraints = NameConstraints {
+            permitted_subtrees: vec![GeneralSubtree::DnsName(address.into())],
+            excluded_subtrees: Vec::new(),
+        };
+
+        if cert_path.exists() && key_path.exists() {
+            let key = KeyPair::from_pem(&read_to_string(&key_path).into_diagnostic()?)
+                .into_diagnostic()
+                .wrap_err("Failed to load CA key")?;
+            let params =
+                CertificateParams::from_ca_cert_pem(&read_to_string(&cert_path).into_diagnostic()?)
+                    .into_diagnostic()
+                    .wrap_err("Failed to load CA certificate")?;
+            if params.is_ca == is_ca && params.name_constraints.as_ref() == Some(&name_constraints)
+            {
+                let cert = params.self_signed(&key).into_diagnostic()?;
+                return Ok(LocalCa { cert, key });
+            }
+            info!("The stored local CA doesn't match the proxy address, replacing it");
+        }
+
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "haze local CA");
+        params.is_ca = is_ca;
+        params.name_constraints = Some(name_constraints);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let key = KeyPair::generate().into_diagnostic()?;
+        let cert = params.self_signed(&key).into_diagnostic()?;
+
+        create_dir_all(dir)
+            .into_diagnostic()
+            .wrap_err("Failed to create CA directory")?;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&key_path)
+            .and_then(|mut file| file.write_all(key.serialize_pem().as_bytes()))
+            .into_diagnostic()
+            .wrap_err("Failed to write CA key")?;
+        write(&cert_path, cert.pem())
+            .into_diagnostic()
+            .wrap_err("Failed to write CA certificate")?;
+
+        log_trust_instructions(&cert_path);
+
+        Ok(LocalCa { cert, key })
+    }
+
+    /// Issue a certificate for a domain, valid for a year
+    fn issue(&self, name: &str) -> Result<CertifiedKey> {
+        let mut params = CertificateParams::new(vec![name.to_string()]).into_diagnostic()?;
+        params.distinguished_name.push(DnType::CommonName, name);
+        let today = Utc::now().date_naive();
+        let expires = today + Days::new(365);
+        params.not_before = date_time_ymd(today.year(), today.month() as u8, today.day() as u8);
+        params.not_after =
+            date_time_ymd(expires.year(), expires.month() as u8, expires.day() as u8);
+
+        let key = KeyPair::generate().into_diagnostic()?;
+        let cert = params
+            .signed_by(&key, &self.cert, &self.key)
+            .into_diagnostic()?;
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        let signing_key = any_supported_type(&key_der).into_diagnostic()?;
+        Ok(CertifiedKey::new(vec![cert.der().clone()], signing_key))
+    }
+}
+
+fn log_trust_instructions(cert_path: &Utf8Path) {
+    info!("Generated a new local CA at {cert_path}");
+    info!("To trust it system wide:");
+    info!("  Debian/Ubuntu: sudo cp {cert_path} /usr/local/share/ca-certificates/haze.crt && sudo update-ca-certificates");
+    info!("  Fedora/Arch:   sudo trust anchor --store {cert_path}");
+    info!("Firefox uses its own certificate store, import it there under Settings > Certificates > Authorities");
+}
+
+/// Issues certificates for the base address and its subdomains as they are requested
+#[derive(Debug)]
+struct CertResolver {
+    ca: LocalCa,
+    base_address: String,
+    issued: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name().unwrap_or(&self.base_address);
+        let is_proxied = name == self.base_address
+            || name
+                .strip_suffix(&self.base_address)
+                .is_some_and(|sub| sub.ends_with('.'));
+        if !is_proxied {
+            debug!(name, "not issuing certificate for unknown domain");
+            return None;
+        }
+
+        let mut issued = self.issued.lock().unwrap();
+        if let Some(key) = issued.get(name) {
+            return Some(key.clone());
+        }
+        match self.ca.issue(name) {
+            Ok(key) => {
+                let key = Arc::new(key);
+                issued.insert(name.to_string(), key.clone());
+                Some(key)
+            }
+            Err(error) => {
+                error!(name, ?error, "failed to issue certificate");
+                None
+            }
+        }
+    }
+}
+
+/// Tls config serving certificates issued by the local CA for `base_address` and its subdomains
+pub fn tls_config(ca: LocalCa, base_address: String) -> Result<Arc<ServerConfig>> {
+    let resolver = CertResolver {
+        ca,
+        base_address,
+        issued: Mutex::default(),
+    };
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .into_diagnostic()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Listener that does the tls handshakes of incoming connections in the background
+struct TlsListener {
+    connections: Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    async fn bind(addr: SocketAddr, config: Arc<ServerConfig>) -> Result<TlsListener> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to listen on {}", addr))?;
+        let local_addr = listener.local_addr().into_diagnostic()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (sender, connections) = channel(16);
+
+        spawn(async move {
+            while !sender.is_closed() {
+                let (stream, remote) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(error) => {
+                        error!(%error, "failed to accept connection");
+                        sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                spawn(async move {
+                    match timeout(Duration::from_secs(10), acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            sender.send((stream, remote)).await.ok();
+                        }
+                        Ok(Err(error)) => debug!(%error, %remote, "tls handshake failed"),
+                        Err(_) => debug!(%remote, "tls handshake timed out"),
+                    }
+                });
+            }
+        });
+
+        Ok(TlsListener {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        // the accept loop only stops once the listener is dropped
+        self.connections
+            .recv()
+            .await
+            .expect("tls accept loop stopped")
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientInfo {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        ClientInfo {
+            ip: Some(stream.remote_addr().ip()),
+            https: true,
+        }
+    }
+}
+
+/// Serve an app over tls on a socket address, until ctrl-c is pressed
+pub(crate) async fn serve_tls(app: Router, listen: &str, config: Arc<ServerConfig>) -> Result<()> {
+    let addr: SocketAddr = listen
+        .parse()
+        .map_err(|_| miette!("Invalid tls listen address {}", listen))?;
+    let listener = TlsListener::bind(addr, config).await?;
+    info!(listen = %listener.local_addr, "Listening with tls");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<ClientInfo>(),
+    )
+    .with_graceful_shutdown(async {
+        ctrl_c().await.ok();
+    })
+    .await
+    .into_diagnostic()
+}
+
+#[test]
+fn test_local_ca() {
+    use tokio_rustls::rustls::client::danger::ServerCertVerifier;
+    use tokio_rustls::rustls::client::WebPkiServerVerifier;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use tokio_rustls::rustls::RootCertStore;
+
+    let dir = tempfile::tempdir().unwrap();
+    let dir = Utf8Path::from_path(dir.path()).unwrap();
+    let ca = LocalCa::load_or_create(dir, "haze.example.com").unwrap();
+    let pem = read_to_string(dir.join("ca.crt")).unwrap();
+    ca.issue("foo.haze.example.com").unwrap();
+
+    // the stored CA is reused
+    let ca = LocalCa::load_or_create(dir, "haze.example.com").unwrap();
+    assert_eq!(pem, read_to_string(dir.join("ca.crt")).unwrap());
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_slice(pem.as_bytes()).unwrap())
+        .unwrap();
+    let verifier =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(roots), Arc::new(default_provider()))
+            .build()
+            .unwrap();
+    let verify = |issued: &str, name: &str| {
+        let key = ca.issue(issued).unwrap();
+        verifier.verify_server_cert(
+            &key.cert[0],
+            &[],
+            &ServerName::try_from(name.to_string()).unwrap(),
+            &[],
+            UnixTime::now(),
+        )
+    };
+    verify("bar.haze.example.com", "bar.haze.example.com").unwrap();
+    // the name constraints prevent the CA from being used for other domains
+    assert!(verify("example.org", "example.org").is_err());
+
+    // changing the proxy address replaces the CA
+    LocalCa::load_or_create(dir, "haze.example.org").unwrap();
+    assert_ne!(pem, read_to_string(dir.join("ca.crt")).unwrap());
+}
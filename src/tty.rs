@@ -0,0 +1,178 @@
+use miette::{IntoDiagnostic, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{stdin, stdout, Read, Stdout};
+use std::os::unix::fs::OpenOptionsExt;
+use std::panic::{set_hook, take_hook};
+use std::sync::{Mutex, Once};
+use std::thread;
+use termion::raw::{IntoRawMode, RawTerminal};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::channel;
+
+/// Key sequence to detach from an interactive exec without stopping the command, ctrl-p ctrl-q like docker
+pub const DETACH_KEYS: &[u8] = &[0x10, 0x11];
+
+static RAW_TERMINAL: Mutex<Option<RawTerminal<Stdout>>> = Mutex::new(None);
+static PANIC_HOOK: Once = Once::new();
+
+/// Keeps the terminal in raw mode while alive
+///
+/// The terminal is also restored when panicking, before the panic message is printed.
+pub struct RawMode(());
+
+impl RawMode {
+    pub fn enable() -> Result<Self> {
+        PANIC_HOOK.call_once(|| {
+            let default_hook = take_hook();
+            set_hook(Box::new(move |info| {
+                restore_terminal();
+                default_hook(info);
+            }));
+        });
+        let raw = stdout().into_raw_mode().into_diagnostic()?;
+        *RAW_TERMINAL.lock().unwrap_or_else(|e| e.into_inner()) = Some(raw);
+        Ok(RawMode(()))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+fn restore_terminal() {
+    // dropping the raw terminal restores the original terminal mode
+    RAW_TERMINAL
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take();
+}
+
+/// Tracks partial matches of the detach keys in the input
+pub struct DetachKeys {
+    keys: &'static [u8],
+    matched: usize,
+}
+
+impl DetachKeys {
+    pub fn new(keys: &'static [u8]) -> Self {
+        DetachKeys { keys, matched: 0 }
+    }
+
+    /// Append the input that should be forwarded to `out`, returns true if the detach keys were pressed
+    ///
+    /// Input matching the start of the detach keys is held back until it's clear if the full sequence is pressed.
+    pub fn feed(&mut self, input: &[u8], out: &mut Vec<u8>) -> bool {
+        for &byte in input {
+            if byte == self.keys[self.matched] {
+                self.matched += 1;
+                if self.matched == self.keys.len() {
+                    self.matched = 0;
+                    return true;
+                }
+            } else {
+                out.extend_from_slice(&self.keys[..self.matched]);
+                self.matched = 0;
+                if byte == self.keys[0] {
+                    self.matched = 1;
+                } else {
+                    out.push(byte);
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Forward stdin to `input` until stdin is closed or the detach keys are pressed
+///
+/// Returns true when detached
+pub async fn forward_stdin(input: impl AsyncWrite + Unpin) -> Result<bool> {
+    match open_stdin() {
+        Some(stdin) => forward_polled(stdin, input).await,
+        // regular files can't be polled
+        None => forward_blocking(input).await,
+    }
+}
+
+/// Open stdin again in non-blocking mode, `None` if it can't be polled
+///
+/// Reopening creates a separate file description, so the non-blocking flag doesn't affect stdout when both are the
+/// same terminal and nothing has to be restored afterward.
+fn open_stdin() -> Option<AsyncFd<File>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/stdin")
+        .ok()?;
+    AsyncFd::new(file).ok()
+}
+
+async fn forward_polled(stdin: AsyncFd<File>, mut input: impl AsyncWrite + Unpin) -> Result<bool> {
+    let mut buff = [0; 8 * 1024];
+    let mut detach = DetachKeys::new(DETACH_KEYS);
+    loop {
+        let mut guard = stdin.readable().await.into_diagnostic()?;
+        // `try_io` clears the readiness when the read would block
+        let read = match guard.try_io(|file| file.get_ref().read(&mut buff)) {
+            Ok(read) => read.into_diagnostic()?,
+            Err(_would_block) => continue,
+        };
+        if read == 0 {
+            return Ok(false);
+        }
+        if forward(&mut detach, &buff[..read], &mut input).await? {
+            return Ok(true);
+        }
+    }
+}
+
+async fn forward_blocking(mut input: impl AsyncWrite + Unpin) -> Result<bool> {
+    let (sender, mut chunks) = channel(4);
+    thread::spawn(move || {
+        let mut stdin = stdin().lock();
+        let mut buff = [0; 8 * 1024];
+        while let Ok(read @ 1..) = stdin.read(&mut buff) {
+            if sender.blocking_send(buff[..read].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    let mut detach = DetachKeys::new(DETACH_KEYS);
+    while let Some(chunk) = chunks.recv().await {
+        if forward(&mut detach, &chunk, &mut input).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Write the input that isn't (part of) the detach keys to `input`, returns true if the detach keys were pressed
+async fn forward(
+    detach: &mut DetachKeys,
+    data: &[u8],
+    mut input: impl AsyncWrite + Unpin,
+) -> Result<bool> {
+    let mut forward = Vec::with_capacity(data.len());
+    let detached = detach.feed(data, &mut forward);
+    input.write_all(&forward).await.into_diagnostic()?;
+    Ok(detached)
+}
+
+#[test]
+fn test_detach_keys() {
+    let mut detach = DetachKeys::new(DETACH_KEYS);
+    let mut out = Vec::new();
+    assert!(!detach.feed(b"ls\x10", &mut out));
+    assert_eq!(out, b"ls");
+    assert!(!detach.feed(b"a", &mut out));
+    assert_eq!(out, b"ls\x10a");
+
+    out.clear();
+    assert!(!detach.feed(b"\x10\x10", &mut out));
+    assert_eq!(out, b"\x10");
+    assert!(detach.feed(b"\x11", &mut out));
+    assert_eq!(out, b"\x10");
+}